@@ -0,0 +1,25 @@
+use chat_service::peer::auth::hash_secret;
+use std::{
+    env::args,
+    fs::OpenOptions,
+    io::{stdin, Write},
+};
+
+// Adds a user to a credentials file: `credentials <file> <name>`, then type the password or token.
+fn main() {
+    let cli_args: Vec<String> = args().collect();
+    let (path, name) = match (cli_args.get(1), cli_args.get(2)) {
+        (Some(path), Some(name)) => (path.clone(), name.clone()),
+        _ => {
+            println!("usage: credentials <file> <name>");
+            return;
+        }
+    };
+    println!("Password for {}:", name);
+    let mut secret = String::new();
+    stdin().read_line(&mut secret).expect("could not read password");
+    let hash = hash_secret(secret.trim_end_matches(['\r', '\n'])).expect("could not hash password");
+    let mut file = OpenOptions::new().create(true).append(true).open(&path).expect("could not open credentials file");
+    file.write_all(format!("{} {}\n", name, hash).as_bytes()).expect("could not write credentials file");
+    println!("Added {} to {}", name, path);
+}
@@ -0,0 +1,113 @@
+use chat_service::{
+    peer::chatlog::{
+        InMemoryChatBuffer,
+        create_listening_threads_from_inmemory_buffer
+    },
+    request::request::{ChatRequest, ChatRequestStatus, ChatRequestVerb},
+};
+use std::{
+    env::args,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
+
+/**
+ * Benchmark: CPU usage of the feed with idle readers.
+ *
+ * Starts a chatlog feed in-process, connects N readers that never receive anything,
+ * and reports how much CPU the process burned while they sat idle.
+ *
+ * `cargo run --release --example idle_readers -- <reader count> <seconds> <feed socket>`
+ */
+const DEFAULT_READER_COUNT: usize = 100;
+const DEFAULT_SECONDS: u64 = 5;
+// Linux reports utime/stime in clock ticks, which are 100/s on practically every system
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+// User + system CPU time of this process, in seconds (Linux only)
+fn cpu_seconds() -> Option<f64> {
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // Fields after the command name, which is wrapped in parentheses and may contain spaces
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 2..].split(' ').collect();
+    let utime = fields.get(11)?.parse::<f64>().ok()?;
+    let stime = fields.get(12)?.parse::<f64>().ok()?;
+    Some((utime + stime) / CLOCK_TICKS_PER_SECOND)
+}
+
+fn request(subject: &str, verb: ChatRequestVerb, object: &str) -> ChatRequest {
+    ChatRequest {
+        subject: Some(subject.to_string()),
+        verb,
+        object: Some(object.to_string()),
+        status: ChatRequestStatus::Valid,
+    }
+}
+
+fn main() {
+    let cli_args: Vec<String> = args().collect();
+    let reader_count = match cli_args.get(1) {
+        Some(x) => x.parse::<usize>().unwrap_or(DEFAULT_READER_COUNT),
+        _ => DEFAULT_READER_COUNT,
+    };
+    let seconds = match cli_args.get(2) {
+        Some(x) => x.parse::<u64>().unwrap_or(DEFAULT_SECONDS),
+        _ => DEFAULT_SECONDS,
+    };
+    let socket_feed = match cli_args.get(3) {
+        Some(x) => x.clone(),
+        _ => String::from("127.0.0.1:8100"),
+    };
+
+    let chat_buffer = InMemoryChatBuffer::new();
+    let (_, _, tx) = create_listening_threads_from_inmemory_buffer(chat_buffer, socket_feed.clone());
+    thread::sleep(Duration::from_millis(200));
+
+    // Connect the readers and log them in, then drain the "is connected!" lines
+    let mut readers: Vec<BufReader<TcpStream>> = vec![];
+    for idx in 0..reader_count {
+        let subject = format!("reader{}", idx);
+        let mut stream = TcpStream::connect(socket_feed.as_str()).expect("could not connect to feed");
+        stream.write_all(format!("{}\n", subject).as_bytes()).expect("feed handshake failed");
+        tx.send(request(&subject, ChatRequestVerb::INIT, "").into()).expect("chatlog is gone");
+        readers.push(BufReader::new(stream));
+    }
+    tx.send(request("bench", ChatRequestVerb::INIT, "").into()).expect("chatlog is gone");
+    tx.send(request("bench", ChatRequestVerb::TX, "warmup").into()).expect("chatlog is gone");
+    for reader in readers.iter_mut() {
+        let mut line = String::new();
+        while !line.contains("warmup") {
+            line.clear();
+            reader.read_line(&mut line).expect("reader failed");
+        }
+    }
+
+    println!("{} idle readers connected, measuring for {}s...", reader_count, seconds);
+    let cpu_before = cpu_seconds();
+    let started = Instant::now();
+    thread::sleep(Duration::from_secs(seconds));
+    let wall = started.elapsed().as_secs_f64();
+    let cpu_after = cpu_seconds();
+
+    match (cpu_before, cpu_after) {
+        (Some(before), Some(after)) => {
+            println!("cpu time: {:.2}s over {:.2}s wall ({:.1}% of one core)", after - before, wall, (after - before) / wall * 100.0);
+        },
+        _ => { println!("cpu time unavailable (needs /proc/self/stat)"); }
+    }
+
+    // Make sure the readers still get woken up
+    let started = Instant::now();
+    tx.send(request("bench", ChatRequestVerb::TX, "wakeup").into()).expect("chatlog is gone");
+    for reader in readers.iter_mut() {
+        let mut line = String::new();
+        while !line.contains("wakeup") {
+            line.clear();
+            reader.read_line(&mut line).expect("reader failed");
+        }
+    }
+    println!("all {} readers received a new line in {:?}", reader_count, started.elapsed());
+    std::process::exit(0);
+}
@@ -1,7 +1,11 @@
+use chat_service::{
+    peer::transport::{connect, client_config},
+    request::entry::LogEntry,
+};
 use std::{
     env::args,
-    net::{TcpStream},
-    io::{BufReader, BufRead}
+    path::Path,
+    io::{BufReader, BufRead, Write}
 };
 
 
@@ -11,9 +15,21 @@ fn main() {
         Some(string) => string.clone(),
         _ => String::from("0.0.0.0:8000")
     };
-    let connect = TcpStream::connect(socket.as_str());
-    match connect {
+    // The feed only shows rooms joined by this subject
+    let subject: String = match cli_args.get(2) {
+        Some(string) => string.clone(),
+        _ => String::from("listener")
+    };
+    // `all`, `live`, `last:<N>` or `since:<message id>`
+    let backfill: String = match cli_args.get(3) {
+        Some(string) => string.clone(),
+        _ => String::from("all")
+    };
+    // A PEM file with the certificate to trust, to connect over TLS
+    let tls = cli_args.get(4).map(|path| client_config(Path::new(path)).expect("could not load certificate"));
+    match connect(socket.as_str(), &tls) {
         Ok(mut stream) => {
+            stream.write_all(format!("{} backfill={}\n", subject, backfill).as_bytes()).expect("Feed handshake failed.");
             let bufreader = BufReader::new(&mut stream);
                 let mut buf_array = bufreader
                     .lines()
@@ -21,7 +37,10 @@ fn main() {
                 // This example just prints, but we could have it do something with ChatWindow
                 loop {
                     match buf_array.next() {
-                        Some(arr) => { println!("{}", arr); },
+                        Some(arr) => match LogEntry::from_json(&arr) {
+                            Some(entry) => { println!("[{}] {}", entry.time_of_day(), entry.render()); },
+                            None => { println!("{}", arr); },
+                        },
                         _ => { () },
                     }
                 }
@@ -0,0 +1,135 @@
+use chat_service::{
+    peer::{
+        async_server::AsyncServer,
+        chatlog::{InMemoryChatBuffer, create_listening_threads_from_inmemory_buffer},
+        server::Server,
+    },
+    request::response::ChatResponse,
+};
+use std::{
+    env::args,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+/**
+ * Load test: the threaded server (`peer::server`) against the async one (`peer::async_server`).
+ *
+ * Opens N idle sessions to each, in this process, and reports how long that took, how many
+ * threads and how much memory the server needed for them, and how long one message took to
+ * reach all of them.
+ *
+ * `cargo run --release --features async --example load_test -- <session count> [threaded|async|both]`
+ *
+ * Every session takes two file descriptors here (client and server side), so raise the limit
+ * first for big runs, e.g. `ulimit -n 20000`. Every session also gets a join notice for each one
+ * opened after it, and nothing reads those until the broadcast, so past a couple of thousand
+ * sessions the kernel's socket buffers fill up and the run stalls.
+ */
+const DEFAULT_SESSION_COUNT: usize = 1000;
+const ASYNC_WORKER_COUNT: usize = 4;
+
+// A field of /proc/self/status, e.g. `Threads` or `VmRSS` (in kB) (Linux only)
+fn status(field: &str) -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with(&format!("{}:", field)))?;
+    line.split_whitespace().nth(1)?.parse::<u64>().ok()
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").expect("no free port").local_addr().expect("no free port").port()
+}
+
+// Open a session and wait for its welcome
+fn open_session(port: u16, subject: &str) -> BufReader<TcpStream> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("could not connect (is the fd limit high enough?)");
+    stream.write_all(format!("[1:{}][2:init][3:version=2 backfill=live]\r\n", subject).as_bytes()).expect("init failed");
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).expect("no welcome");
+    match ChatResponse::from_json(&line) {
+        Some(ChatResponse::Welcome { .. }) => reader,
+        _ => panic!("session for {} was not welcomed: {}", subject, line),
+    }
+}
+
+// Returns the connections, so they outlive the run (dropping them makes the feeds report write errors)
+fn run(name: &str, session_count: usize, start: impl FnOnce(u16)) -> Vec<BufReader<TcpStream>> {
+    let port = free_port();
+    // Whatever the server needs up front counts too
+    let (threads_before, rss_before) = (status("Threads"), status("VmRSS"));
+    start(port);
+    thread::sleep(Duration::from_millis(200));
+
+    let started = Instant::now();
+    let mut sessions: Vec<BufReader<TcpStream>> = (0..session_count)
+        .map(|idx| open_session(port, &format!("user{}", idx)))
+        .collect();
+    let connect_time = started.elapsed();
+    // Let the servers settle before measuring
+    thread::sleep(Duration::from_millis(500));
+    let (threads_after, rss_after) = (status("Threads"), status("VmRSS"));
+
+    let mut bench = open_session(port, "bench").into_inner();
+    let started = Instant::now();
+    bench.write_all(b"[1:bench][2:tx][3:wakeup]\r\n").expect("bench failed");
+    for session in sessions.iter_mut() {
+        let mut line = String::new();
+        while !line.contains("wakeup") {
+            line.clear();
+            session.read_line(&mut line).expect("session failed");
+        }
+    }
+    let broadcast_time = started.elapsed();
+
+    println!("{}:", name);
+    println!("  {} sessions connected in {:?}", session_count, connect_time);
+    match (threads_before, threads_after, rss_before, rss_after) {
+        (Some(threads_before), Some(threads_after), Some(rss_before), Some(rss_after)) => {
+            println!(
+                "  {} more threads, {} kB more memory (client sockets included)",
+                threads_after as i64 - threads_before as i64,
+                rss_after as i64 - rss_before as i64
+            );
+        },
+        _ => { println!("  threads and memory unavailable (needs /proc/self/status)"); }
+    }
+    println!("  one message reached all of them in {:?}", broadcast_time);
+    sessions.push(BufReader::new(bench));
+    sessions
+}
+
+fn main() {
+    let cli_args: Vec<String> = args().collect();
+    let session_count = match cli_args.get(1) {
+        Some(x) => x.parse::<usize>().unwrap_or(DEFAULT_SESSION_COUNT),
+        _ => DEFAULT_SESSION_COUNT,
+    };
+    let which = cli_args.get(2).cloned().unwrap_or(String::from("both"));
+    let mut connections = vec![];
+
+    if which == "threaded" || which == "both" {
+        connections.extend(run("threaded server", session_count, |port| {
+            let chat_buffer = InMemoryChatBuffer::new();
+            let log = chat_buffer.create_handle();
+            let (_, _, tx) = create_listening_threads_from_inmemory_buffer(chat_buffer, String::from("127.0.0.1:0"));
+            // A session holds its worker for as long as it lives, so it takes one worker per session
+            // (and one for the bench session)
+            let server = Server::new(&format!("127.0.0.1:{}", port));
+            thread::spawn(move || server.start_session(session_count + 1, tx, log));
+        }));
+    }
+    if which == "async" || which == "both" {
+        connections.extend(run("async server", session_count, |port| {
+            let chat_buffer = InMemoryChatBuffer::new();
+            let log = chat_buffer.create_handle();
+            let (_, _, tx) = create_listening_threads_from_inmemory_buffer(chat_buffer, String::from("127.0.0.1:0"));
+            let server = AsyncServer::new(&format!("127.0.0.1:{}", port));
+            thread::spawn(move || server.start_session(ASYNC_WORKER_COUNT, tx, log));
+        }));
+    }
+    std::process::exit(0);
+}
@@ -0,0 +1,185 @@
+use chat_service::{
+    config::{
+        flags::{Flags, usage},
+        server::{SERVER_FLAGS, ServerSettings, Storage},
+    },
+    peer::{
+        server::Server,
+        chatlog::{
+            InMemoryChatBuffer,
+            Submission,
+            create_listening_threads_with_tls
+        },
+        shutdown::ShutdownSignal,
+        logstore::{ChatStore, RingBufferStore},
+        auth::Credentials,
+        transport::server_config,
+        ratelimit::RateLimiter,
+    },
+};
+use rustls::ServerConfig;
+use std::{
+    any::Any,
+    collections::HashMap,
+    env::args,
+    path::Path,
+    process,
+    sync::Arc,
+    thread::{JoinHandle, self}, io::Error,
+};
+
+const ABOUT: &str = "chat-server: the client-mode, feed and session servers on one chatlog";
+
+// Settings from `--config` if given, then the rest of the flags on top
+fn read_settings() -> Result<ServerSettings, String> {
+    let flags = Flags::parse(SERVER_FLAGS, args().skip(1))?;
+    if flags.help {
+        print!("{}", usage("chat-server", ABOUT, SERVER_FLAGS));
+        process::exit(0);
+    }
+    let mut settings = match flags.get("config") {
+        Some(path) => ServerSettings::load(Path::new(path)).map_err(|e| e.to_string())?,
+        None => ServerSettings::default(),
+    };
+    settings.apply_flags(&flags)?;
+    Ok(settings)
+}
+
+// A server on `socket`, held to the settings' limits and locked down with the credentials and TLS
+// config if there are any
+fn new_server(socket: &str, settings: &ServerSettings, tls: &Option<Arc<ServerConfig>>, credentials: &Option<Arc<Credentials>>, shutdown: &ShutdownSignal, rate_limiter: &Option<RateLimiter>) -> Server {
+    let mut server = Server::new(socket)
+        .with_shutdown(shutdown.clone())
+        .with_max_frame(settings.max_frame);
+    if let Some(idle_timeout) = settings.idle_timeout {
+        server = server.with_idle_timeout(idle_timeout);
+    }
+    if let Some(rate_limiter) = rate_limiter {
+        server = server.with_rate_limit(rate_limiter.clone());
+    }
+    if let Some(credentials) = credentials {
+        server = server.with_credentials(credentials.clone());
+    }
+    if let Some(tls) = tls {
+        server = server.with_tls(tls.clone());
+    }
+    server
+}
+
+fn flatten_joins(joins: Vec<JoinHandle<Result<(), Error>>>) -> Result<(), Box<dyn Any + Send + 'static>> {
+    for join_handle in joins {
+        join_handle.join()?.unwrap_or(());
+    }
+    Ok(())
+}
+
+// Serve `chat_buffer` until the server is stopped. Errs if the settings point at files that can't
+// be used.
+fn run<S: ChatStore>(settings: ServerSettings, mut chat_buffer: InMemoryChatBuffer<S>) -> Result<(), String> {
+    let tls = match settings.tls() {
+        Some((cert, key)) => Some(
+            server_config(cert, key).map_err(|e| format!("could not load the certificate or key: {}", e))?
+        ),
+        None => None,
+    };
+    // Read once, for the chatlog and every server
+    let credentials = match settings.credentials.as_ref() {
+        Some(path) => Some(Arc::new(
+            Credentials::load(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?
+        )),
+        None => None,
+    };
+    chat_buffer = chat_buffer.with_feed_limits(settings.feed_limits);
+    if let Some(motd) = settings.motd.clone() {
+        chat_buffer = chat_buffer.with_motd(motd);
+    }
+    if let Some(credentials) = credentials.as_ref() {
+        chat_buffer = chat_buffer.with_credentials(credentials.clone());
+    }
+    let log = chat_buffer.create_handle();
+    let shutdown = chat_buffer.shutdown.clone();
+    // Ctrl+C or a SIGTERM stops the server (see `peer::shutdown`)
+    let signal = shutdown.clone();
+    ctrlc::set_handler(move || signal.trigger()).map_err(|e| format!("could not install the signal handler: {}", e))?;
+    // The servers besides the chatlog, joined once it has stopped
+    let mut servers = vec![];
+    let (chatlog, tx) = match settings.legacy_listeners() {
+        true => {
+            let (chatlog, feeds, tx) = create_listening_threads_with_tls(chat_buffer, settings.feed_socket.clone(), tls.clone(), settings.feed_executors);
+            servers.push(feeds);
+            (chatlog, tx)
+        },
+        // Old clients can't be held to the credentials as well as sessions can
+        false => {
+            println!("Not listening on {} or {}: there are credentials (set `legacy` to listen anyway)", settings.client_socket, settings.feed_socket);
+            let tx = chat_buffer.create_tx();
+            (thread::spawn(move || chat_buffer.listen_for_updates()), tx)
+        },
+    };
+    // One limiter for both servers, so nobody gets twice the rate by using both
+    let rate_limiter = settings.rate_limit.map(RateLimiter::new);
+    let tx_session = tx.clone();
+    let tx_shutdown = tx.clone();
+    if settings.legacy_listeners() {
+        let server = new_server(&settings.client_socket, &settings, &tls, &credentials, &shutdown, &rate_limiter);
+        let client_executors = settings.client_executors;
+        let client_log = log.clone();
+        servers.push(thread::spawn(move || {
+            server.start(client_executors, tx, client_log)
+        }));
+    }
+    // Session-mode server: one connection per client for both requests and the feed
+    let session_server = new_server(&settings.session_socket, &settings, &tls, &credentials, &shutdown, &rate_limiter);
+    let session_executors = settings.session_executors;
+    let subscribers = log.subscribers.clone();
+    servers.push(thread::spawn(move || {
+        session_server.start_session(session_executors, tx_session, log)
+    }));
+    // Prints the feeds' stats now and then, until the server shuts down
+    let stats_shutdown = shutdown.clone();
+    let feed_stats = settings.feed_stats;
+    servers.push(thread::spawn(move || {
+        if let Some(interval) = feed_stats {
+            while !stats_shutdown.wait_timeout(interval) {
+                println!("{}", subscribers.summary());
+            }
+        }
+        Ok(())
+    }));
+
+    shutdown.wait();
+    println!("Shutting down...");
+    // Tell every feed, flush the log, and wait for the chatlog to stop before hanging up on clients
+    tx_shutdown.send(Submission::Shutdown { notice: String::from("The server is shutting down.") }).unwrap_or(());
+    let chatlog = flatten_joins(vec![chatlog]);
+    shutdown.close_connections();
+    match chatlog.and(flatten_joins(servers)) {
+        Ok(()) => { println!("Ok!"); },
+        _ => { println!("Not ok!"); }
+    };
+    Ok(())
+}
+
+fn main() {
+    let settings = match read_settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(2);
+        }
+    };
+    let result = match settings.storage.clone() {
+        Storage::Memory => run(settings, InMemoryChatBuffer::new()),
+        Storage::Ring(capacity) => {
+            run(settings, InMemoryChatBuffer::with_store(HashMap::new(), move |_| Ok(RingBufferStore::new(capacity))))
+        },
+        Storage::Directory(log_dir) => match InMemoryChatBuffer::open(&log_dir, settings.sync) {
+            Ok(chat_buffer) => run(settings, chat_buffer),
+            Err(e) => Err(format!("could not open the log directory {}: {}", log_dir.display(), e)),
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(2);
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    env,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+use toml::Table;
+
+use crate::config::{Section, flags::{Flag, Flags}, parse_flag, parse_table, read_table};
+use crate::window::theme::Theme;
+
+/**
+ * A CLI client profile: which server to join, under which name, and how it looks. Read from
+ * `$XDG_CONFIG_HOME/chat-service/client.toml` (`~/.config/...` without XDG_CONFIG_HOME) if it
+ * exists, or the file given with `--config`.
+ */
+#[derive(Debug, Clone)]
+pub struct ClientProfile {
+    // The session socket to connect to
+    pub server: String,
+    // Tried first; the name panel only comes up if the server turns it down
+    pub nickname: Option<String>,
+    // Take the name the server picks (`Dan2`, `Dan3`, ...) instead of asking for another if the
+    // name is taken. Servers with credentials never rename.
+    pub rename: bool,
+    // Window size, or the largest that fits
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    // Ask for a password, for servers with a credentials file
+    pub password: bool,
+    // Connect over TLS, trusting the certificates in this PEM file
+    pub ca: Option<PathBuf>,
+    // End-to-end encrypt DMs with the key in this file (created if missing)
+    pub e2e: Option<PathBuf>,
+    pub theme: Theme,
+}
+
+impl Default for ClientProfile {
+    fn default() -> Self {
+        ClientProfile {
+            server: String::from("0.0.0.0:7000"),
+            nickname: None,
+            rename: false,
+            width: None,
+            height: None,
+            password: false,
+            ca: None,
+            e2e: None,
+            theme: Theme::default(),
+        }
+    }
+}
+
+pub const CLIENT_FLAGS: &[Flag] = &[
+    Flag::option("config", "<file>", "Read the profile from a TOML file (see config/client.toml)"),
+    Flag::option("server", "<addr>", "Join the session server at <addr> [0.0.0.0:7000]"),
+    Flag::option("nickname", "<name>", "Try this name before asking for one"),
+    Flag::switch("rename", "Take the name the server picks if yours is taken (e.g. Dan2)"),
+    Flag::option("width", "<cols>", "Window width"),
+    Flag::option("height", "<rows>", "Window height"),
+    Flag::switch("password", "Ask for a password, for servers with a credentials file"),
+    Flag::option("ca", "<pem>", "Connect over TLS, trusting the certificates in <pem>"),
+    Flag::option("e2e", "<key file>", "End-to-end encrypt DMs with this key (created if missing)"),
+    Flag::option("theme", "<name>", "Colors: `default` or `mono`"),
+];
+
+impl ClientProfile {
+    // Where the profile is read from without `--config`
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_home.join("chat-service").join("client.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<ClientProfile, Error> {
+        let table = read_table(path)?;
+        ClientProfile::from_table(&table)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(contents: &str) -> Result<ClientProfile, String> {
+        ClientProfile::from_table(&parse_table(contents)?)
+    }
+
+    fn from_table(table: &Table) -> Result<ClientProfile, String> {
+        let mut profile = ClientProfile::default();
+        let root = Section::root(table);
+        root.only(&["server", "nickname", "rename", "password", "ca", "e2e", "window", "theme"])?;
+        profile.server = root.string("server")?.unwrap_or(profile.server);
+        profile.nickname = root.string("nickname")?;
+        profile.rename = root.boolean("rename")?.unwrap_or(profile.rename);
+        profile.password = root.boolean("password")?.unwrap_or(profile.password);
+        profile.ca = root.path_buf("ca")?;
+        profile.e2e = root.path_buf("e2e")?;
+        if let Some(window) = root.section("window")? {
+            window.only(&["width", "height"])?;
+            profile.width = window.number("width")?;
+            profile.height = window.number("height")?;
+        }
+        if let Some(theme) = root.section("theme")? {
+            theme.only(&["base", "error", "direct", "text"])?;
+            let color = "a color like `red` or `dark_cyan`";
+            let mut chosen = theme.parsed("base", "`default` or `mono`", Theme::named)?.unwrap_or_default();
+            chosen.error = theme.parsed("error", color, Theme::color)?.unwrap_or(chosen.error);
+            chosen.direct = theme.parsed("direct", color, Theme::color)?.unwrap_or(chosen.direct);
+            chosen.text = theme.parsed("text", color, Theme::color)?.unwrap_or(chosen.text);
+            profile.theme = chosen;
+        }
+        Ok(profile)
+    }
+
+    // Override the profile with whatever was given on the command line
+    pub fn apply_flags(&mut self, flags: &Flags) -> Result<(), String> {
+        if let Some(server) = flags.get("server") {
+            self.server = server.to_string();
+        }
+        if let Some(nickname) = flags.get("nickname") {
+            self.nickname = Some(nickname.to_string());
+        }
+        if flags.has("rename") {
+            self.rename = true;
+        }
+        if let Some(width) = flags.get("width") {
+            self.width = Some(parse_flag("width", width)?);
+        }
+        if let Some(height) = flags.get("height") {
+            self.height = Some(parse_flag("height", height)?);
+        }
+        if flags.has("password") {
+            self.password = true;
+        }
+        if let Some(ca) = flags.get("ca") {
+            self.ca = Some(PathBuf::from(ca));
+        }
+        if let Some(e2e) = flags.get("e2e") {
+            self.e2e = Some(PathBuf::from(e2e));
+        }
+        if let Some(theme) = flags.get("theme") {
+            self.theme = Theme::named(theme).ok_or(format!("--theme: no theme called {:?}", theme))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+/**
+ * Command-line flags
+ * ------------------
+ * Options are given as `--name value` or `--name=value`, switches as `--name`. `--help` (or `-h`)
+ * asks for the usage, which `usage` puts together from the same list of Flags the parser checks
+ * against. There are no positional arguments.
+ */
+pub struct Flag {
+    pub name: &'static str,
+    // How the value is shown in the usage, e.g. `<addr>`. None for switches.
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+impl Flag {
+    pub const fn option(name: &'static str, value: &'static str, help: &'static str) -> Flag {
+        Flag { name, value: Some(value), help }
+    }
+
+    pub const fn switch(name: &'static str, help: &'static str) -> Flag {
+        Flag { name, value: None, help }
+    }
+}
+
+// The flags given on a command line
+#[derive(Debug, Default)]
+pub struct Flags {
+    // Whether `--help` was asked for
+    pub help: bool,
+    // Switches map to an empty string
+    values: HashMap<String, String>,
+}
+
+impl Flags {
+    // Parse `args` (without the program name) against `spec`
+    pub fn parse<I: IntoIterator<Item = String>>(spec: &[Flag], args: I) -> Result<Flags, String> {
+        let mut flags = Flags::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--help" || arg == "-h" {
+                flags.help = true;
+                continue;
+            }
+            let (name, inline_value) = match arg.strip_prefix("--") {
+                Some(flag) => match flag.split_once('=') {
+                    Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                    None => (flag.to_string(), None),
+                },
+                None => { return Err(format!("unexpected argument {} (see --help)", arg)); },
+            };
+            let flag = match spec.iter().find(|flag| flag.name == name) {
+                Some(flag) => flag,
+                None => { return Err(format!("unknown flag --{} (see --help)", name)); },
+            };
+            let value = match (flag.value, inline_value) {
+                (Some(_), Some(value)) => value,
+                (Some(_), None) => match args.next() {
+                    Some(value) => value,
+                    None => { return Err(format!("--{} needs a value", name)); },
+                },
+                (None, Some(_)) => { return Err(format!("--{} doesn't take a value", name)); },
+                (None, None) => String::new(),
+            };
+            flags.values.insert(name, value);
+        }
+        Ok(flags)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|value| value.as_str())
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+}
+
+// What `--help` prints
+pub fn usage(program: &str, about: &str, spec: &[Flag]) -> String {
+    let shown: Vec<String> = spec
+        .iter()
+        .map(|flag| match flag.value {
+            Some(value) => format!("--{} {}", flag.name, value),
+            None => format!("--{}", flag.name),
+        })
+        .collect();
+    let width = shown.iter().map(|flag| flag.len()).max().unwrap_or(0);
+    let mut usage = format!("{}\n\nUsage: {} [flags]\n\nFlags:\n", about, program);
+    for (flag, shown) in spec.iter().zip(shown) {
+        usage.push_str(&format!("  {:width$}  {}\n", shown, flag.help, width = width));
+    }
+    usage.push_str(&format!("  {:width$}  {}\n", "-h, --help", "Show this and exit", width = width));
+    usage
+}
@@ -0,0 +1,139 @@
+pub mod flags;
+pub mod server;
+pub mod client;
+
+use std::{
+    fmt::Display,
+    fs,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use toml::{Table, Value};
+
+// Read a TOML file, naming the file in any error
+pub(crate) fn read_table(path: &Path) -> Result<Table, Error> {
+    let contents = fs::read_to_string(path)?;
+    parse_table(&contents).map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+}
+
+pub(crate) fn parse_table(contents: &str) -> Result<Table, String> {
+    contents.parse::<Table>().map_err(|e| e.to_string().trim_end().to_string())
+}
+
+/**
+ * Configuration
+ * -------------
+ * The server (`src/bin/chat-server.rs`) reads its settings from a TOML file (`server::ServerSettings`),
+ * and the CLI client from a profile (`client::ClientProfile`). `--flags` on the command line
+ * override either (see `flags`). Every key is optional; see `config/server.toml` and
+ * `config/client.toml` for all of them. Keys nobody reads are errors, so typos don't go unnoticed.
+ *
+ * A Section is a table of a config file, and where it is in the file for error messages.
+ */
+pub(crate) struct Section<'a> {
+    name: String,
+    table: &'a Table,
+}
+
+impl<'a> Section<'a> {
+    pub fn root(table: &'a Table) -> Section<'a> {
+        Section { name: String::new(), table }
+    }
+
+    // Fails on any key not in `keys`
+    pub fn only(&self, keys: &[&str]) -> Result<(), String> {
+        match self.table.keys().find(|key| !keys.contains(&key.as_str())) {
+            Some(key) => Err(format!("unknown key {}", self.path(key))),
+            None => Ok(()),
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        match self.name.is_empty() {
+            true => key.to_string(),
+            false => format!("{}.{}", self.name, key),
+        }
+    }
+
+    fn wrong_type(&self, key: &str, expected: &str) -> String {
+        format!("{} must be {}", self.path(key), expected)
+    }
+
+    pub fn section(&self, key: &str) -> Result<Option<Section<'a>>, String> {
+        match self.table.get(key) {
+            Some(Value::Table(table)) => Ok(Some(Section { name: self.path(key), table })),
+            Some(_) => Err(self.wrong_type(key, "a table")),
+            None => Ok(None),
+        }
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<String>, String> {
+        match self.table.get(key) {
+            Some(Value::String(string)) => Ok(Some(string.clone())),
+            Some(_) => Err(self.wrong_type(key, "a string")),
+            None => Ok(None),
+        }
+    }
+
+    pub fn path_buf(&self, key: &str) -> Result<Option<PathBuf>, String> {
+        Ok(self.string(key)?.map(PathBuf::from))
+    }
+
+    pub fn boolean(&self, key: &str) -> Result<Option<bool>, String> {
+        match self.table.get(key) {
+            Some(Value::Boolean(boolean)) => Ok(Some(*boolean)),
+            Some(_) => Err(self.wrong_type(key, "true or false")),
+            None => Ok(None),
+        }
+    }
+
+    // A whole number that fits in T
+    pub fn number<T: TryFrom<i64>>(&self, key: &str) -> Result<Option<T>, String> {
+        match self.table.get(key) {
+            Some(Value::Integer(integer)) => match T::try_from(*integer) {
+                Ok(number) => Ok(Some(number)),
+                Err(_) => Err(format!("{} is out of range", self.path(key))),
+            },
+            Some(_) => Err(self.wrong_type(key, "a whole number")),
+            None => Ok(None),
+        }
+    }
+
+    pub fn float(&self, key: &str) -> Result<Option<f64>, String> {
+        match self.table.get(key) {
+            Some(Value::Float(float)) => Ok(Some(*float)),
+            Some(Value::Integer(integer)) => Ok(Some(*integer as f64)),
+            Some(_) => Err(self.wrong_type(key, "a number")),
+            None => Ok(None),
+        }
+    }
+
+    // A number of seconds, where 0 means never
+    pub fn seconds(&self, key: &str) -> Result<Option<Option<Duration>>, String> {
+        Ok(self.number::<u64>(key)?.map(|secs| match secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }))
+    }
+
+    // A string parsed by `parse`, which returns None for strings it doesn't take
+    pub fn parsed<T>(&self, key: &str, expected: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, String> {
+        match self.string(key)? {
+            Some(string) => match parse(&string) {
+                Some(value) => Ok(Some(value)),
+                None => Err(self.wrong_type(key, expected)),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+// Parse a flag's value, naming the flag if it won't
+pub(crate) fn parse_flag<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display
+{
+    value.parse::<T>().map_err(|e| format!("--{}: {}", name, e))
+}
@@ -0,0 +1,276 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use toml::Table;
+
+use crate::config::{Section, flags::{Flag, Flags}, parse_flag, parse_table, read_table};
+use crate::peer::{
+    logstore::SyncPolicy,
+    ratelimit::RateLimit,
+    chatlog::FEED_EXECUTORS,
+    subscribers::{FeedLimits, Laggards},
+};
+use crate::request::request::MAX_REQUEST_LENGTH;
+
+/**
+ * Where the server keeps the chat log (see `peer::logstore`)
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Storage {
+    // Everything in memory
+    Memory,
+    // The last N lines of each room
+    Ring(usize),
+    // A FileLog per room in the directory
+    Directory(PathBuf),
+}
+
+impl Storage {
+    // `memory`, `ring:<N>`, or anything else for a directory
+    pub fn parse(string: &str) -> Option<Storage> {
+        match string.split_once(':') {
+            _ if string == "memory" => Some(Storage::Memory),
+            Some(("ring", capacity)) => capacity.parse::<usize>().ok().map(Storage::Ring),
+            _ if string.is_empty() => None,
+            _ => Some(Storage::Directory(PathBuf::from(string))),
+        }
+    }
+}
+
+/**
+ * Everything `chat-server` can be told, from a TOML file and `--flags`. The defaults are what the
+ * server used before it had a config file.
+ */
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    // Where the client-mode, feed and session servers listen (see the README)
+    pub client_socket: String,
+    pub feed_socket: String,
+    pub session_socket: String,
+    // Whether the client-mode and feed servers listen. They're left off by default when there are
+    // credentials: sessions are what the CLI client speaks, and the others are only for old clients.
+    pub legacy: Option<bool>,
+    // How many connections each server handles at once
+    pub client_executors: usize,
+    pub session_executors: usize,
+    // Feeds start workers as they come in, up to this many
+    pub feed_executors: usize,
+    pub storage: Storage,
+    // How often a FileLog is fsynced
+    pub sync: SyncPolicy,
+    // A credentials file (see `peer::auth`). Without one, anyone can connect as anyone.
+    pub credentials: Option<PathBuf>,
+    // A PEM certificate chain and private key. Without them, everything is plain TCP.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // Sent to every new feed (see `InMemoryChatBuffer::with_motd`)
+    pub motd: Option<String>,
+    pub idle_timeout: Option<Duration>,
+    pub max_frame: usize,
+    // None lets subjects send as fast as they like
+    pub rate_limit: Option<RateLimit>,
+    pub feed_limits: FeedLimits,
+    // How often to print the feeds' stats (see `Subscribers::summary`). None: never.
+    pub feed_stats: Option<Duration>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            client_socket: String::from("0.0.0.0:9000"),
+            feed_socket: String::from("0.0.0.0:8000"),
+            session_socket: String::from("0.0.0.0:7000"),
+            legacy: None,
+            client_executors: 20,
+            session_executors: 20,
+            feed_executors: FEED_EXECUTORS,
+            storage: Storage::Memory,
+            sync: SyncPolicy::Always,
+            credentials: None,
+            tls_cert: None,
+            tls_key: None,
+            motd: None,
+            // The CLI client PINGs every 20 seconds
+            idle_timeout: Some(Duration::from_secs(90)),
+            max_frame: MAX_REQUEST_LENGTH,
+            // A screenful of lines at once, then two a second; a paste that keeps going gets its sender muted
+            rate_limit: Some(RateLimit::new(10, 2.0).with_mute(20, Duration::from_secs(30))),
+            feed_limits: FeedLimits::default(),
+            feed_stats: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+pub const SERVER_FLAGS: &[Flag] = &[
+    Flag::option("config", "<file>", "Read settings from a TOML file (see config/server.toml)"),
+    Flag::option("client", "<addr>", "Listen for client-mode connections on <addr> [0.0.0.0:9000]"),
+    Flag::option("feed", "<addr>", "Listen for feed subscribers on <addr> [0.0.0.0:8000]"),
+    Flag::option("session", "<addr>", "Listen for sessions on <addr> [0.0.0.0:7000]"),
+    Flag::switch("legacy", "Listen for client-mode and feed connections even with credentials"),
+    Flag::option("executors", "<n>", "Handle <n> connections at once on the client-mode and session servers [20]"),
+    Flag::option("store", "<store>", "Keep the log in `memory`, the last N lines in `ring:<N>`, or a directory [memory]"),
+    Flag::option("sync", "<policy>", "Fsync the log `always`, `never` or every N lines [always]"),
+    Flag::option("credentials", "<file>", "Only let in the subjects in a credentials file"),
+    Flag::option("cert", "<pem>", "Serve TLS with this certificate chain (needs --key)"),
+    Flag::option("key", "<pem>", "The private key for --cert"),
+    Flag::option("motd", "<text>", "Greet every feed with <text>"),
+];
+
+impl ServerSettings {
+    // Settings from a TOML file, on top of the defaults
+    pub fn load(path: &Path) -> Result<ServerSettings, Error> {
+        let table = read_table(path)?;
+        ServerSettings::from_table(&table)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(contents: &str) -> Result<ServerSettings, String> {
+        ServerSettings::from_table(&parse_table(contents)?)
+    }
+
+    fn from_table(table: &Table) -> Result<ServerSettings, String> {
+        let mut settings = ServerSettings::default();
+        let root = Section::root(table);
+        root.only(&["motd", "credentials", "feed_stats_secs", "listen", "executors", "storage", "tls", "limits"])?;
+        settings.motd = root.string("motd")?.or(settings.motd);
+        settings.feed_stats = root.seconds("feed_stats_secs")?.unwrap_or(settings.feed_stats);
+        settings.credentials = root.path_buf("credentials")?.or(settings.credentials);
+        if let Some(listen) = root.section("listen")? {
+            listen.only(&["client", "feed", "session", "legacy"])?;
+            settings.legacy = listen.boolean("legacy")?.or(settings.legacy);
+            settings.client_socket = listen.string("client")?.unwrap_or(settings.client_socket);
+            settings.feed_socket = listen.string("feed")?.unwrap_or(settings.feed_socket);
+            settings.session_socket = listen.string("session")?.unwrap_or(settings.session_socket);
+        }
+        if let Some(executors) = root.section("executors")? {
+            executors.only(&["client", "feed", "session"])?;
+            settings.client_executors = executors.number("client")?.unwrap_or(settings.client_executors);
+            settings.feed_executors = executors.number("feed")?.unwrap_or(settings.feed_executors);
+            settings.session_executors = executors.number("session")?.unwrap_or(settings.session_executors);
+        }
+        if let Some(storage) = root.section("storage")? {
+            storage.only(&["store", "sync"])?;
+            settings.storage = storage.parsed("store", "`memory`, `ring:<N>` or a directory", Storage::parse)?.unwrap_or(settings.storage);
+            settings.sync = storage.parsed("sync", "`always`, `never` or a number", SyncPolicy::parse)?.unwrap_or(settings.sync);
+        }
+        if let Some(tls) = root.section("tls")? {
+            tls.only(&["cert", "key"])?;
+            settings.tls_cert = tls.path_buf("cert")?;
+            settings.tls_key = tls.path_buf("key")?;
+        }
+        if let Some(limits) = root.section("limits")? {
+            settings.read_limits(&limits)?;
+        }
+        settings.check()?;
+        Ok(settings)
+    }
+
+    fn read_limits(&mut self, limits: &Section) -> Result<(), String> {
+        limits.only(&[
+            "idle_timeout_secs", "max_frame",
+            "rate_burst", "rate_per_second", "mute_after", "mute_secs",
+            "feed_high_water", "feed_write_timeout_secs", "laggards",
+        ])?;
+        self.idle_timeout = limits.seconds("idle_timeout_secs")?.unwrap_or(self.idle_timeout);
+        self.max_frame = limits.number("max_frame")?.unwrap_or(self.max_frame);
+        // Rate limits start from the defaults; a burst of 0 turns them off, as does 0 mutes
+        let mut rate_limit = self.rate_limit.unwrap_or(RateLimit::new(10, 2.0));
+        rate_limit.burst = limits.number("rate_burst")?.unwrap_or(rate_limit.burst);
+        rate_limit.per_second = limits.float("rate_per_second")?.unwrap_or(rate_limit.per_second);
+        rate_limit.mute_after = match limits.number::<u32>("mute_after")? {
+            Some(0) => None,
+            Some(strikes) => Some(strikes),
+            None => rate_limit.mute_after,
+        };
+        rate_limit.mute_for = limits.number("mute_secs")?.map(Duration::from_secs).unwrap_or(rate_limit.mute_for);
+        self.rate_limit = match rate_limit.burst {
+            0 => None,
+            _ => Some(rate_limit),
+        };
+        self.feed_limits.high_water = limits.number("feed_high_water")?.unwrap_or(self.feed_limits.high_water);
+        self.feed_limits.write_timeout = limits.seconds("feed_write_timeout_secs")?.unwrap_or(self.feed_limits.write_timeout);
+        self.feed_limits.laggards = limits.parsed("laggards", "`disconnect` or `drop`", |laggards| match laggards {
+            "disconnect" => Some(Laggards::Disconnect),
+            "drop" => Some(Laggards::Drop),
+            _ => None,
+        })?.unwrap_or(self.feed_limits.laggards);
+        Ok(())
+    }
+
+    // Override the settings with whatever was given on the command line
+    pub fn apply_flags(&mut self, flags: &Flags) -> Result<(), String> {
+        if let Some(socket) = flags.get("client") {
+            self.client_socket = socket.to_string();
+        }
+        if let Some(socket) = flags.get("feed") {
+            self.feed_socket = socket.to_string();
+        }
+        if let Some(socket) = flags.get("session") {
+            self.session_socket = socket.to_string();
+        }
+        if flags.has("legacy") {
+            self.legacy = Some(true);
+        }
+        if let Some(executors) = flags.get("executors") {
+            self.client_executors = parse_flag("executors", executors)?;
+            self.session_executors = self.client_executors;
+        }
+        if let Some(store) = flags.get("store") {
+            self.storage = Storage::parse(store).ok_or(format!("--store: can't keep the log in {:?}", store))?;
+        }
+        if let Some(sync) = flags.get("sync") {
+            self.sync = SyncPolicy::parse(sync).ok_or(format!("--sync: unknown policy {:?}", sync))?;
+        }
+        if let Some(credentials) = flags.get("credentials") {
+            self.credentials = Some(PathBuf::from(credentials));
+        }
+        if let Some(cert) = flags.get("cert") {
+            self.tls_cert = Some(PathBuf::from(cert));
+        }
+        if let Some(key) = flags.get("key") {
+            self.tls_key = Some(PathBuf::from(key));
+        }
+        if let Some(motd) = flags.get("motd") {
+            self.motd = Some(motd.to_string());
+        }
+        self.check()
+    }
+
+    // Whether the client-mode and feed servers listen: if `legacy` says so, or else if there are no
+    // credentials
+    pub fn legacy_listeners(&self) -> bool {
+        self.legacy.unwrap_or(self.credentials.is_none())
+    }
+
+    // The certificate and key for TLS, if it's on
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        match (self.tls_cert.as_ref(), self.tls_key.as_ref()) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            _ => None,
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(String::from("TLS needs both a certificate and a key"));
+        }
+        if self.client_executors == 0 || self.feed_executors == 0 || self.session_executors == 0 {
+            return Err(String::from("every server needs at least one executor"));
+        }
+        if self.feed_limits.high_water == 0 {
+            return Err(String::from("the feed high-water mark must be at least 1"));
+        }
+        if self.max_frame == 0 {
+            return Err(String::from("the max frame must be at least 1 byte"));
+        }
+        // A limiter that never refills would mute everyone for good, and NaN never compares
+        if let Some(rate_limit) = self.rate_limit.as_ref() {
+            if !(rate_limit.per_second > 0.0 && rate_limit.per_second.is_finite()) {
+                return Err(String::from("the rate per second must be a positive number"));
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,28 +1,47 @@
 use std::{
     // sync::mpsc,
-    net::{TcpStream},
-    io::{self, BufReader, BufRead},
+    sync::Arc,
+    io::{self, BufReader, BufRead, Write},
 };
+use rustls::ClientConfig;
 use regex::Regex;
+use crate::request::{
+    entry::LogEntry,
+    request::{ChatRequest, ChatRequestStatus, ChatRequestVerb},
+};
+use crate::peer::{
+    auth::encode_secret,
+    transport::{Stream, connect},
+};
 
 // TODO: refactor BOT into a trait
 pub struct Bot<F> 
-    where F: Fn(String, &mut TcpStream) -> Option<()>
+    where F: Fn(String, &mut Stream) -> Option<()>
 {
+    name: String,
     wake_pattern: String,
-    listens_on: TcpStream,
-    writes_to: TcpStream,
+    listens_on: Stream,
+    writes_to: Stream,
     on_wake: F
 }
 
 impl<F> Bot<F> 
-    where F: Fn(String, &mut TcpStream) -> Option<()> {
-    pub fn new(wake_pattern: String, listens_port: String, writes_port: String, on_wake: F) -> Result<Bot<F>, io::Error> {
-        let listens_on = TcpStream::connect(listens_port)?;
-        let writes_to = TcpStream::connect(writes_port)?;
+    where F: Fn(String, &mut Stream) -> Option<()> {
+    // `name` is the subject the bot subscribes to the feed as.
+    pub fn new(name: String, wake_pattern: String, listens_port: String, writes_port: String, on_wake: F) -> Result<Bot<F>, io::Error> {
+        Bot::new_tls(name, wake_pattern, listens_port, writes_port, None, on_wake)
+    }
+
+    // Like `new`, connecting over TLS if there's a client config (see `peer::transport`)
+    pub fn new_tls(name: String, wake_pattern: String, listens_port: String, writes_port: String, tls: Option<Arc<ClientConfig>>, on_wake: F) -> Result<Bot<F>, io::Error> {
+        let mut listens_on = connect(&listens_port, &tls)?;
+        // Only wake on new messages, not on history
+        listens_on.write_all(format!("{} backfill=live\n", name).as_bytes())?;
+        let writes_to = connect(&writes_port, &tls)?;
         // let (thread_spawner, thread_spawn_responder) = mpsc::channel::<u8>();
         return Result::Ok(
             Bot {
+                name,
                 wake_pattern,
                 listens_on,
                 writes_to,
@@ -32,20 +51,28 @@ impl<F> Bot<F>
     }
 
 
+    // Log in on the write connection, for servers with a credentials file (see `peer::auth`).
+    // Everything the bot writes afterwards is sent as the bot's name.
+    pub fn authenticate(&mut self, password: &str) -> Result<(), io::Error> {
+        let request = ChatRequest {
+            subject: Some(self.name.clone()),
+            verb: ChatRequestVerb::INIT,
+            object: Some(format!("password={}", encode_secret(password))),
+            status: ChatRequestStatus::Valid
+        };
+        self.writes_to.write_all(request.to_string_opt().unwrap().as_bytes())
+    }
+
     pub fn listen_on(&mut self) {
-        let reader = BufReader::new(&self.listens_on);
+        let reader = BufReader::new(&mut self.listens_on);
         let mut lines = reader
             .lines()
             .map(|item| {match item {
-                Ok(string) => { 
-                    let splitted = string.split(": ")
-                        .map(|i| i.to_string())
-                        .collect::<Vec<String>>();
-                    if splitted.len() > 1 {
-                        return splitted[1].clone();
-                    }
-                    String::new()
-                    },
+                // Wake on message bodies only
+                Ok(string) => match LogEntry::from_json(&string) {
+                    Some(entry) => entry.to_request().body().unwrap_or_default(),
+                    None => String::new(),
+                },
                 _ => {String::new()}}
             });
         while let Some(line) = lines.next() {
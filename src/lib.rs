@@ -3,3 +3,4 @@ pub mod threadpool;
 pub mod window;
 pub mod request;
 pub mod experiment;
+pub mod config;
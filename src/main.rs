@@ -1,18 +1,38 @@
 use std::{
     env::args,
-    sync::{Arc, Mutex, mpsc},
-    net::{TcpStream},
-    io::{BufReader, BufRead, Write, stdout},
-    thread
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex, mpsc, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    io::{self, BufReader, BufRead, Write, stdout},
+    thread,
+    time::Duration,
 };
 use crossterm::{
     terminal::{enable_raw_mode, disable_raw_mode}
 };
+use rustls::ClientConfig;
 use chat_service::{
-    request::request::{ChatRequest, ChatRequestStatus},
+    config::{
+        client::{CLIENT_FLAGS, ClientProfile},
+        flags::{Flags, usage},
+    },
+    peer::{
+        transport::{Stream, client_config},
+        reconnect::{Granted, Handshake, Reconnect, Reconnected, Resume, Rooms, SharedConnection, handshake},
+        shutdown::ShutdownSignal,
+        e2e::{Identity, Keyring, KeyChange, Sealed, SharedKeyring, fingerprint, lock_keyring, parse_public_key},
+    },
+    request::{
+        request::{ChatRequest, ChatRequestStatus, ChatRequestVerb, Backfill},
+        entry::LogEntry,
+        response::ChatResponse,
+    },
     window::{
         helpers::*,
         NameInput::BasicInputPanel,
+        handlers::{allow_rename, init_request, key_request, ping_request},
+        constants::MAX_WINDOW_HEIGHT,
+        theme::set_theme,
         ChatWindow::{
             ChatWindow,
         },
@@ -22,41 +42,198 @@ use chat_service::{
     }
 };
 
-fn main() {
-    let cli_args: Vec<String> = args().collect();
-    let client_socket = match cli_args.get(1) {
-        Some(string) => string.clone(),
-        _ => String::from("0.0.0.0:9000")
+// Wait before reconnecting: doubles after every failed attempt, up to the max
+const RECONNECT_FIRST_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+// How often to PING servers that answer them (see `reconnect::FEED_SILENCE_TIMEOUT`)
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+// A session the server accepted
+struct Session {
+    stream: Stream,
+    // The name the server knows us by
+    name: String,
+    // If the server skipped the handshake and sent a feed line already
+    first_line: Option<String>,
+    // To reconnect with
+    password: Option<String>,
+    granted: Granted,
+}
+
+// Open a session, asking for another name (and password) until the server accepts one. The
+// profile's nickname is tried first.
+fn open_session(socket: &str, tls: &Option<Arc<ClientConfig>>, height: usize, profile: &ClientProfile, panel: &mut BasicInputPanel) -> io::Result<Session> {
+    let mut nickname = profile.nickname.clone();
+    loop {
+        let name = match nickname.take() {
+            Some(nickname) => nickname,
+            None => panel.capture_input(),
+        };
+        if name.is_empty() {
+            panel.show_error(String::from("Pick a name first"));
+            continue;
+        }
+        let password = match profile.password {
+            true => {
+                let mut password_panel = BasicInputPanel::password();
+                password_panel.print();
+                Some(password_panel.capture_input())
+            },
+            false => None,
+        };
+        let mut init = init_request(name.clone(), password.clone(), Backfill::Last(height), None);
+        if profile.rename {
+            init = allow_rename(init);
+        }
+        match handshake(socket, tls, &init)? {
+            Handshake::Welcome(stream, subject, granted) => {
+                return Ok(Session { stream, name: subject, first_line: None, password, granted });
+            },
+            Handshake::Rejected(reason) | Handshake::NameTaken(reason) => { panel.show_error(reason); },
+            Handshake::Legacy(stream, first_line) => {
+                return Ok(Session { stream, name, first_line, password, granted: Granted::legacy() });
+            },
+        }
+    }
+}
+
+// Show an entry from the feed. With E2E on, this is where keys get pinned and encrypted DMs decrypted,
+// and where DMs that were waiting for a key are sent.
+fn show_entry(cw: &mut ChatWindow, entry: &LogEntry, name: &str, keyring: &Option<SharedKeyring>, stream: &mut SharedConnection) {
+    let keyring = match keyring {
+        Some(keyring) => keyring,
+        None => {
+            cw.add_entry(entry);
+            return;
+        }
     };
-    let socket: String = match cli_args.get(2) {
-        Some(string) => string.clone(),
-        _ => String::from("0.0.0.0:8000")
+    let mut keyring = lock_keyring(keyring);
+    // Every EDM names the key it was sealed with, so keys can be learned from those too
+    let learned = match entry.verb {
+        ChatRequestVerb::KEY => parse_public_key(&entry.object),
+        ChatRequestVerb::EDM if entry.subject != name => entry.to_request().body()
+            .and_then(|body| Sealed::parse(&body))
+            .map(|sealed| sealed.sender_key),
+        _ => None,
     };
-    let width: Option<usize> = match cli_args.get(3) {
-        Some(string) => match string.parse::<usize>() {
-            Ok(value) => Some(value),
-            _ => None
+    if let Some(key) = learned {
+        let shown = fingerprint(&key);
+        match (keyring.learn(&entry.subject, key), entry.verb) {
+            // Answers to GETKEY (`/key <name>`) always show the fingerprint
+            (KeyChange::New, _) | (KeyChange::Known, ChatRequestVerb::KEY) => {
+                cw.add_key_line(format!("{}'s key fingerprint: {}", entry.subject, shown));
+            },
+            (KeyChange::Known, _) => {},
+            (KeyChange::Changed(pinned), _) => {
+                cw.add_key_line(format!(
+                    "WARNING: {}'s key changed to {} (pinned: {}). Messages still go to the pinned key, and messages sealed with the new one are not shown.",
+                    entry.subject, shown, pinned
+                ));
+            },
+        }
+    }
+    match entry.verb {
+        ChatRequestVerb::KEY => {
+            for message in keyring.take_waiting(&entry.subject) {
+                let request = ChatRequest {
+                    subject: Some(name.to_string()),
+                    verb: ChatRequestVerb::EDM,
+                    object: keyring.seal_for(&entry.subject, &message),
+                    status: ChatRequestStatus::Valid,
+                };
+                match request.object.is_some() && stream.write_all(request.to_string_opt().unwrap().as_bytes()).is_ok() {
+                    true => cw.request_sent(),
+                    false => cw.add_key_line(format!("Could not send to {}: {}", entry.subject, message)),
+                }
+            }
         },
-        _ => None
-    };
-    let height: Option<usize> = match cli_args.get(4) {
-        Some(string) => match string.parse::<usize>() {
-            Ok(value) => Some(value),
-            _ => None
+        ChatRequestVerb::EDM => match keyring.open_entry(entry, name) {
+            Some(text) => cw.add_decrypted(entry, text),
+            // Not sealed with the sender's pinned key, so it may not be from them at all
+            None if entry.subject != name => cw.add_key_line(format!(
+                "[{}] {} sent an encrypted message that their pinned key doesn't verify. It is not shown.",
+                entry.time_of_day(), entry.subject
+            )),
+            None => cw.add_entry(entry),
         },
-        _ => None
-    };
+        _ => cw.add_entry(entry),
+    }
+}
 
-    let mut name = String::new();
-    // Fancy UI for adding your name
-    {
-        enable_raw_mode().expect("fail");
-        let mut basic_panel = BasicInputPanel::new();
-        basic_panel.print();
+// Publish our key for encrypted DMs on a (new) session
+fn publish_key(cw: &mut ChatWindow, name: &str, keyring: &SharedKeyring, connection: &mut SharedConnection) {
+    if connection.write_all(key_request(name.to_string(), keyring).to_string_opt().unwrap().as_bytes()).is_ok() {
+        cw.request_sent();
+    }
+}
 
-        let value = basic_panel.capture_input();
-        name = value.clone();
+const ABOUT: &str = "chat-service: the CLI chat client";
+
+// The profile from `--config`, or the default profile if there is one, then the rest of the flags on top
+fn read_profile() -> Result<ClientProfile, String> {
+    let flags = Flags::parse(CLIENT_FLAGS, args().skip(1))?;
+    if flags.help {
+        print!("{}", usage("chat-service", ABOUT, CLIENT_FLAGS));
+        process::exit(0);
     }
+    let path = match flags.get("config") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => ClientProfile::default_path().filter(|path| path.exists()),
+    };
+    let mut profile = match path {
+        Some(path) => ClientProfile::load(&path).map_err(|e| e.to_string())?,
+        None => ClientProfile::default(),
+    };
+    profile.apply_flags(&flags)?;
+    Ok(profile)
+}
+
+fn main() {
+    let profile = match read_profile() {
+        Ok(profile) => profile,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(2);
+        }
+    };
+    set_theme(profile.theme);
+    // Session socket: one connection carries our requests and the feed
+    let socket = profile.server.clone();
+    let (width, height) = (profile.width, profile.height);
+    let tls = match profile.ca.as_ref() {
+        Some(path) => match client_config(path) {
+            Ok(config) => Some(config),
+            Err(v) => {
+                println!("Error: could not load {}: {}", path.display(), v);
+                return;
+            }
+        },
+        None => None,
+    };
+    let keyring: Option<SharedKeyring> = match profile.e2e.as_ref() {
+        Some(path) => match Identity::load_or_create(path) {
+            Ok(identity) => Some(Arc::new(Mutex::new(Keyring::new(identity)))),
+            Err(v) => {
+                println!("Error: could not load {}: {}", path.display(), v);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    // Fancy UI for adding your name. The server may turn it down if it's taken.
+    enable_raw_mode().expect("fail");
+    let mut basic_panel = BasicInputPanel::new();
+    basic_panel.print();
+    let history = height.unwrap_or(MAX_WINDOW_HEIGHT as usize);
+    let Session { stream, name, first_line, password, granted } = match open_session(socket.as_str(), &tls, history, &profile, &mut basic_panel) {
+        Ok(session) => session,
+        Err(v) => {
+            disable_raw_mode().expect("error with disable raw mode");
+            println!("Error: {}", v);
+            return;
+        }
+    };
 
     // Instantiate ChatWindow with Shared State. Clone all instances we need
     let cw: SharedChatWindow = Arc::new(Mutex::new(ChatWindow::new(name.clone(), width, height)));
@@ -64,29 +241,111 @@ fn main() {
     let mut cw_clone1: SharedChatWindow = cw.clone();
     let cw_clone2: SharedChatWindow = cw.clone();
 
+    // Every thread writes to the server through this, so it can be swapped out when we reconnect
+    let feed_stream = stream.try_clone().expect("could not clone session stream");
+    let connection = SharedConnection::new(stream);
+
     // Prints the initial window. Blocking.
     {
-        let locked_cw = lock_chat_window(&mut cw_clone0);
+        let mut locked_cw = lock_chat_window(&mut cw_clone0);
         locked_cw.print();
+        if let Some(keyring) = keyring.as_ref() {
+            let key = lock_keyring(keyring).identity.public_key();
+            locked_cw.add_key_line(format!("Your key fingerprint: {}", fingerprint(&key)));
+            publish_key(&mut locked_cw, &name, keyring, &mut connection.clone());
+        }
     }
 
-    // Thread 1: Connects the ChatWindow to traffic from ChatLog feed and adds lines to the ChatWindow
+    // Triggered on Ctrl+C (see `handlers::handle_modified_keys`): every thread winds down
+    let quit = ShutdownSignal::new();
+    let feed_quit = quit.clone();
+    // Whether the server we're connected to answers PINGs, and the longest line it takes. Both may
+    // change when we reconnect.
+    let heartbeat = Arc::new(AtomicBool::new(granted.pings));
+    let feed_heartbeat = heartbeat.clone();
+    let max_frame = Arc::new(AtomicUsize::new(granted.max_frame));
+    let feed_max_frame = max_frame.clone();
+    let mut feed_connection = connection.clone();
+    let feed_keyring = keyring.clone();
+    let feed_name = name.clone();
+    let reconnect = Reconnect::new(socket, tls).with_backoff(RECONNECT_FIRST_DELAY, RECONNECT_MAX_DELAY);
+    // Joined with /join, and joined again whenever we reconnect
+    let rooms = Rooms::default();
+    let feed_rooms = rooms.clone();
+
+    // Thread 1: Reads the feed the server pushes back on the session and adds lines to the ChatWindow.
+    // When the connection drops, it reconnects and picks the feed up where it left off, unless the user
+    // quit or the server turns us down.
     let h1 = thread::spawn(move || {
-        let connect = TcpStream::connect(socket.as_str());
-        match connect {
-            Ok(mut stream) => {
-                let bufreader = BufReader::new(&mut stream);
-                    let mut buf_array = bufreader
-                        .lines()
-                        .map(|i| i.unwrap());
-                    while let Some(string) = buf_array.next() {
-                        let mut locked_cw = lock_chat_window(&mut cw_clone1);
-                        locked_cw.add_chat_line(string);
-                    }
-            },
-            Err(v) => {
-                println!("Error: {}", v);
-                disable_raw_mode();
+        let mut feed_stream = feed_stream;
+        let mut first_line = first_line;
+        let mut resume = Resume::default();
+        resume.welcomed(&granted);
+        loop {
+            let bufreader = BufReader::new(feed_stream);
+            let mut buf_array = first_line
+                .take()
+                .into_iter()
+                .chain(bufreader.lines().map_while(|i| i.ok()));
+            while let Some(string) = buf_array.next() {
+                let mut locked_cw = lock_chat_window(&mut cw_clone1);
+                match LogEntry::from_json(&string) {
+                    Some(entry) if !resume.saw(&entry) => {},
+                    Some(entry) => show_entry(&mut locked_cw, &entry, &feed_name, &feed_keyring, &mut feed_connection),
+                    None => match ChatResponse::from_json(&string) {
+                        Some(ChatResponse::Rejected { reason, .. }) => {
+                            locked_cw.add_chat_line(format!("Server rejected the connection: {}", reason));
+                        },
+                        Some(ChatResponse::Ack { .. }) => locked_cw.request_acked(),
+                        Some(ChatResponse::Error { message, .. }) => locked_cw.request_failed(message),
+                        Some(ChatResponse::Welcome { .. }) | Some(ChatResponse::Pong) => {},
+                        None => locked_cw.add_chat_line(string),
+                    },
+                }
+            }
+            if feed_quit.is_triggered() {
+                break;
+            }
+            // The server hung up (e.g. it restarted, or the network dropped)
+            feed_connection.disconnect();
+            lock_chat_window(&cw_clone1).disconnected();
+            let init = init_request(feed_name.clone(), password.clone(), resume.backfill(history), resume.epoch());
+            let (stream, line) = loop {
+                let waiting = |attempt, delay| lock_chat_window(&cw_clone1).reconnecting(attempt, delay);
+                let (stream, line, granted) = match reconnect.run(&init, &feed_quit, waiting) {
+                    Reconnected::Resumed(stream, line, granted) => (stream, line, granted),
+                    Reconnected::Rejected(reason) => {
+                        lock_chat_window(&cw_clone1).rejected(reason);
+                        return;
+                    },
+                    Reconnected::Quit => { return; },
+                };
+                if let Ok(clone) = stream.try_clone() {
+                    feed_stream = clone;
+                    feed_heartbeat.store(granted.pings, Ordering::SeqCst);
+                    feed_max_frame.store(granted.max_frame, Ordering::SeqCst);
+                    // A restarted server may number its entries afresh
+                    resume.welcomed(&granted);
+                    break (stream, line);
+                }
+            };
+            first_line = line;
+            feed_connection.replace(stream);
+            // The user quit while we were reconnecting, after Ctrl+C closed the connection
+            if feed_quit.is_triggered() {
+                feed_connection.disconnect();
+                break;
+            }
+            let mut locked_cw = lock_chat_window(&cw_clone1);
+            locked_cw.reconnected();
+            // The server dropped us from every room but the default one with the old connection
+            for join in feed_rooms.rejoin(&feed_name) {
+                if feed_connection.write_all(join.to_string_opt().unwrap().as_bytes()).is_ok() {
+                    locked_cw.request_sent();
+                }
+            }
+            if let Some(keyring) = feed_keyring.as_ref() {
+                publish_key(&mut locked_cw, &feed_name, keyring, &mut feed_connection);
             }
         }
     });
@@ -107,6 +366,14 @@ fn main() {
                             let mut locked_chat_window = lock_chat_window(&cw_clone2);
                             locked_chat_window.scroll_down();
                         },
+                        WindowActions::Sent => {
+                            let mut locked_chat_window = lock_chat_window(&cw_clone2);
+                            locked_chat_window.request_sent();
+                        },
+                        WindowActions::NotSent(message) => {
+                            let mut locked_chat_window = lock_chat_window(&cw_clone2);
+                            locked_chat_window.not_sent(message);
+                        },
                         WindowActions::Resize(x, y) => {
                             let mut locked_chat_window = lock_chat_window(&cw_clone2);
                             locked_chat_window.dimensions.width = x;
@@ -124,20 +391,41 @@ fn main() {
                                 Dimensions { width: x, height: y},
                             );
                         }
+                        WindowActions::Quit => { break; },
                         _ => {}
                     }
                 },
-                _ => {},
+                // ChatInput is gone
+                _ => { break; },
+            }
+        }
+    });
+
+    // Thread 4: PINGs the server now and then, so neither side takes a quiet session for a dead one
+    let mut heartbeat_connection = connection.clone();
+    let heartbeat_quit = quit.clone();
+    let heartbeat_name = name.clone();
+    let h4 = thread::spawn(move || {
+        while !heartbeat_quit.wait_timeout(HEARTBEAT_INTERVAL) {
+            if heartbeat.load(Ordering::SeqCst) {
+                // Fails while reconnecting, which is fine
+                heartbeat_connection.write_all(ping_request(heartbeat_name.clone()).to_string_opt().unwrap().as_bytes()).unwrap_or(());
             }
         }
     });
 
     let h3 = thread::spawn(move || {
         let mut chat_input = ChatInput::new(name, width, height);
-        chat_input.capture_events(client_socket.as_str(), tx.clone());
+        chat_input.keyring = keyring;
+        chat_input.quit = quit;
+        chat_input.max_frame = max_frame;
+        chat_input.rooms = rooms;
+        chat_input.capture_events(connection, tx.clone())
     });
-    h1.join().expect("sad h1");
+    h3.join().expect("sad h3").unwrap_or(());
     h2.join().expect("sad h2");
-    h3.join().expect("sad h3");
+    h1.join().expect("sad h1");
+    h4.join().expect("sad h4");
+    restore_terminal(&mut stdout());
 
 }
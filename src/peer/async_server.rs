@@ -0,0 +1,378 @@
+use std::{
+    io::{Error, ErrorKind},
+    net::TcpListener as StdTcpListener,
+    sync::{Arc, mpsc::Sender},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}},
+    runtime::{Builder, Runtime},
+    sync::{Mutex, Notify, broadcast::error::RecvError},
+    task,
+    time,
+};
+
+use crate::request::request::ChatRequest;
+use crate::request::response::ChatResponse;
+use crate::peer::chatlog::{ChatLogHandle, FeedBatch, FeedCursor, Submission, feed_lines, parse_feed_handshake};
+use crate::peer::logstore::ChatStore;
+use crate::peer::session::{Action, Opening, Session, answer};
+use crate::peer::auth::{Credentials, authenticate};
+use crate::peer::server::Limits;
+use crate::peer::ratelimit::RateLimiter;
+use crate::peer::framing::{AsyncFrameReader, Frame};
+use crate::peer::shutdown::ShutdownSignal;
+use crate::peer::subscribers::Admitted;
+
+// How long a session waits for its feed to write the last lines after the client is gone
+const FEED_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+// How long to back off when accepting fails (e.g. out of file descriptors)
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/**
+ * Async server
+ * ------------
+ * The same session and feed protocols as `peer::server` and `peer::chatlog`, on a tokio runtime
+ * (the `async` feature). Every connection is a task instead of a thread, so one process can hold
+ * thousands of idle connections; feeds sleep on the chatlog Notifier's broadcast channel.
+ *
+ * The chatlog itself is unchanged: requests still go to `InMemoryChatBuffer::listen_for_updates`
+ * on its own thread, so both servers can share one chatlog. There is no TLS and no client mode
+ * (write-only connections) yet; use `peer::server` for those.
+ *
+ * `cargo run --release --features async --example load_test` compares the two servers.
+ */
+pub struct AsyncServer {
+    socket: String,
+    // Who may connect. Anyone may if there are none.
+    credentials: Option<Arc<Credentials>>,
+    // Stops the accept loop (see `peer::shutdown`). Connections end with the chatlog's closing notice.
+    shutdown: Option<ShutdownSignal>,
+    // What every connection is held to (see `peer::server::Server`)
+    limits: Limits,
+}
+
+// Both the feed task and the session write to the connection; each write goes out in one piece
+type Writer = Arc<Mutex<OwnedWriteHalf>>;
+type RequestFrames = AsyncFrameReader<BufReader<OwnedReadHalf>>;
+
+async fn respond(writer: &Writer, response: &ChatResponse) -> Result<(), Error> {
+    writer.lock().await.write_all(format!("{}\n", response.to_json()).as_bytes()).await
+}
+
+// The next line, None at EOF, or a TimedOut error after `idle_timeout` without one. A line cut
+// short by the timeout is kept by the reader, so it can be called again.
+async fn next_frame(frames: &mut RequestFrames, idle_timeout: Option<Duration>) -> Result<Option<Frame>, Error> {
+    match idle_timeout {
+        Some(idle_timeout) => match time::timeout(idle_timeout, frames.next_frame()).await {
+            Ok(frame) => frame,
+            Err(_) => Err(Error::from(ErrorKind::TimedOut)),
+        },
+        None => frames.next_frame().await,
+    }
+}
+
+// Writes the feed for a subject (see `chatlog::write_feed`). Returns once the subject has
+// disconnected, the connection is gone, or after writing the chatlog's closing notice.
+async fn write_feed<S: ChatStore>(writer: Writer, mut cursor: FeedCursor, log: ChatLogHandle<S>) {
+    // Subscribe before the first batch, so no change slips through in between
+    let mut changes = log.notifier.subscribe();
+    let subject = cursor.subject().to_string();
+    let mut subscription = log.subscribers.subscribe(&subject);
+    let write_timeout = subscription.limits().write_timeout;
+    loop {
+        let (pending, last) = match cursor.next_batch(&log) {
+            FeedBatch::Entries(pending) => (pending, false),
+            FeedBatch::Closing(pending) => (pending, true),
+            FeedBatch::Ended => { return; },
+        };
+        let (pending, last) = match subscription.admit(pending) {
+            Admitted::Write(pending) => (pending, last),
+            Admitted::HangUp(pending) => (pending, true),
+        };
+        if !pending.is_empty() {
+            let lines = feed_lines(&pending);
+            let mut writer = writer.lock().await;
+            let written = match write_timeout {
+                Some(write_timeout) => match time::timeout(write_timeout, writer.write_all(lines.as_bytes())).await {
+                    Ok(written) => written,
+                    Err(_) => Err(Error::from(ErrorKind::TimedOut)),
+                },
+                None => writer.write_all(lines.as_bytes()).await,
+            };
+            match written {
+                Ok(()) => { subscription.wrote(pending.len()); },
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    println!("{} stopped reading its feed, disconnecting", subject);
+                    return;
+                },
+                Err(e) => {
+                    println!("stream write error: {:?}", e);
+                    return;
+                },
+            }
+        }
+        if last {
+            return;
+        }
+        match changes.recv().await {
+            // Every batch reads the whole state, so missed changes don't matter
+            Ok(_) | Err(RecvError::Lagged(_)) => {},
+            Err(RecvError::Closed) => { return; },
+        }
+    }
+}
+
+// A session (see `peer::session`) on its own task
+async fn handle_session<S: ChatStore>(tcp: TcpStream, tx: Sender<Submission>, log: ChatLogHandle<S>, credentials: Option<Arc<Credentials>>, limits: Limits) -> Result<(), Error> {
+    let (reader, writer) = tcp.into_split();
+    let writer: Writer = Arc::new(Mutex::new(writer));
+    let mut frames = AsyncFrameReader::new(BufReader::new(reader), limits.max_frame);
+    // Every connection must say something within the idle timeout
+    let init = match next_frame(&mut frames, limits.idle_timeout).await {
+        Ok(Some(Frame::Line(line))) => ChatRequest::from(line),
+        _ => { return Ok(()); },
+    };
+    let (mut session, init, handshake) = match Session::open(init, &log, &credentials, &limits) {
+        Opening::Admitted { session, init, handshake } => (session, init, handshake),
+        Opening::Refused(Some(response)) => { return respond(&writer, &response).await; },
+        Opening::Refused(None) => { return Ok(()); },
+    };
+    if let Some(response) = handshake {
+        respond(&writer, &response).await?;
+    }
+    let idle_timeout = session.idle_timeout(&limits);
+    // Another session took the name over: hang up on this one and wake its feed so it sees it
+    let replaced = Arc::new(Notify::new());
+    let hang_up = replaced.clone();
+    let notifier = log.notifier.clone();
+    session.on_replaced(move || {
+        hang_up.notify_one();
+        notifier.notify();
+    });
+    let cursor = FeedCursor::new(&session.subject, log.backfill_for(&init), &log).with_claim(session.claim_id());
+    if tx.send(init.into()).is_err() {
+        return Ok(());
+    }
+
+    let mut feed = task::spawn(write_feed(writer.clone(), cursor, log.clone()));
+    let mut feed_done = false;
+    loop {
+        let frame = tokio::select! {
+            frame = next_frame(&mut frames, idle_timeout) => frame,
+            // The chatlog closed, or the client stopped taking the feed
+            _ = &mut feed => {
+                feed_done = true;
+                break;
+            },
+            _ = replaced.notified() => { break; },
+        };
+        let frame = match frame {
+            Ok(Some(frame)) => frame,
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                println!("{} went quiet, hanging up", session.subject);
+                break;
+            },
+            _ => { break; },
+        };
+        let outcome = match session.handle(frame, &limits) {
+            Action::Submit(request) => match session.submit(&tx, request) {
+                Ok(outcome) => outcome,
+                _ => { break; },
+            },
+            Action::Respond(response) => match respond(&writer, &response).await {
+                Ok(()) => { continue; },
+                _ => { break; },
+            },
+            Action::Skip => { continue; },
+            Action::HangUp => { break; },
+        };
+        if let Some(outcome) = outcome {
+            // The reply channel blocks, so wait for it off the runtime's workers
+            let response = match task::spawn_blocking(move || outcome.recv()).await {
+                Ok(outcome) => answer(outcome),
+                _ => None,
+            };
+            match response {
+                Some(response) if respond(&writer, &response).await.is_ok() => {},
+                _ => { break; },
+            }
+        }
+        if session.has_ended() {
+            break;
+        }
+    }
+    if let Some(end) = session.farewell(&log) {
+        tx.send(end.into()).unwrap_or(());
+    }
+    if !feed_done && time::timeout(FEED_DRAIN_TIMEOUT, &mut feed).await.is_err() {
+        feed.abort();
+        feed.await.unwrap_or(());
+    }
+    // The name is free again once the feed has stopped, so it can't read for the next session
+    drop(session);
+    writer.lock().await.shutdown().await.unwrap_or(());
+    Ok(())
+}
+
+// A feed subscriber (see `chatlog::parse_feed_handshake`)
+async fn handle_feed<S: ChatStore>(tcp: TcpStream, log: ChatLogHandle<S>, credentials: Option<Arc<Credentials>>, limits: Limits) -> Result<(), Error> {
+    let (reader, writer) = tcp.into_split();
+    let mut frames = AsyncFrameReader::new(BufReader::new(reader), limits.max_frame);
+    let handshake = match next_frame(&mut frames, limits.idle_timeout).await {
+        Ok(Some(Frame::Line(line))) => line,
+        _ => { return Ok(()); },
+    };
+    let mut init = parse_feed_handshake(&handshake);
+    if !authenticate(&mut init, &credentials) {
+        println!("refusing feed for {:?}: authentication failed", init.subject);
+        return Ok(());
+    }
+    if let Some(subject) = log.held_by_session(&init) {
+        println!("refusing feed for {}: a session has the name", subject);
+        return Ok(());
+    }
+    let cursor = FeedCursor::new(init.subject.as_deref().unwrap_or_default(), log.backfill_for(&init), &log);
+    let writer: Writer = Arc::new(Mutex::new(writer));
+    write_feed(writer.clone(), cursor, log).await;
+    writer.lock().await.shutdown().await.unwrap_or(());
+    Ok(())
+}
+
+// A runtime with `worker_count` threads
+fn runtime(worker_count: usize) -> Result<Runtime, Error> {
+    Builder::new_multi_thread()
+        .worker_threads(worker_count.max(1))
+        .enable_all()
+        .build()
+}
+
+impl AsyncServer {
+
+    pub fn new(socket: &str) -> AsyncServer {
+        AsyncServer {
+            socket: String::from(socket),
+            credentials: None,
+            shutdown: None,
+            limits: Limits::default(),
+        }
+    }
+
+    // Only let subjects in the credentials file connect (see `peer::auth`)
+    pub fn with_credentials(mut self, credentials: impl Into<Arc<Credentials>>) -> AsyncServer {
+        self.credentials = Some(credentials.into());
+        self
+    }
+
+    // Stop accepting once `shutdown` is triggered (see `peer::shutdown`)
+    pub fn with_shutdown(mut self, shutdown: ShutdownSignal) -> AsyncServer {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    // Hang up on quiet connections, like `Server::with_idle_timeout`
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> AsyncServer {
+        self.limits.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    // Refuse long request lines like `Server::with_max_frame`
+    pub fn with_max_frame(mut self, max_frame: usize) -> AsyncServer {
+        self.limits.max_frame = max_frame;
+        self
+    }
+
+    // Throttle and mute subjects like `Server::with_rate_limit`
+    pub fn with_rate_limit(mut self, rate_limiter: RateLimiter) -> AsyncServer {
+        self.limits.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    // Bind the socket. Hand the listener to `serve_sessions_on` or `serve_feeds_on` (or their
+    // `start_` versions) to learn its address (e.g. with port 0) before accepting. It is bound with
+    // std, so the shutdown signal can wake up the accept loop.
+    pub fn listen(&self) -> Result<StdTcpListener, Error> {
+        let listener = StdTcpListener::bind(self.socket.clone())?;
+        if let Some(shutdown) = self.shutdown.as_ref() {
+            shutdown.listen(&listener);
+        }
+        Ok(listener)
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(|shutdown| shutdown.is_triggered())
+    }
+
+    // Accept connections until the shutdown, handing each to `handle` on its own task
+    async fn accept<F, T>(&self, listener: StdTcpListener, handle: F) -> Result<(), Error>
+    where
+        F: Fn(TcpStream) -> T,
+        T: std::future::Future<Output = Result<(), Error>> + Send + 'static
+    {
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        loop {
+            let tcp = match listener.accept().await {
+                Ok((tcp, _)) => tcp,
+                Err(e) => {
+                    println!("accept failed: {:?}", e);
+                    time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                },
+            };
+            if self.is_shutting_down() {
+                break;
+            }
+            let connection = handle(tcp);
+            task::spawn(async move {
+                connection.await.unwrap_or_else(|e| {
+                    println!("session error: {:?}", e);
+                });
+            });
+        }
+        Ok(())
+    }
+
+    // Accepts session-mode connections (see `peer::server::Server::start_session`)
+    pub async fn serve_sessions<S: ChatStore>(&self, tx: Sender<Submission>, log: ChatLogHandle<S>) -> Result<(), Error> {
+        self.serve_sessions_on(self.listen()?, tx, log).await
+    }
+
+    // `serve_sessions` on a listener from `listen`
+    pub async fn serve_sessions_on<S: ChatStore>(&self, listener: StdTcpListener, tx: Sender<Submission>, log: ChatLogHandle<S>) -> Result<(), Error> {
+        self.accept(listener, |tcp| {
+            handle_session(tcp, tx.clone(), log.clone(), self.credentials.clone(), self.limits.clone())
+        }).await
+    }
+
+    // Accepts feed subscribers (see `chatlog::create_listening_threads_from_inmemory_buffer`)
+    pub async fn serve_feeds<S: ChatStore>(&self, log: ChatLogHandle<S>) -> Result<(), Error> {
+        self.serve_feeds_on(self.listen()?, log).await
+    }
+
+    // `serve_feeds` on a listener from `listen`
+    pub async fn serve_feeds_on<S: ChatStore>(&self, listener: StdTcpListener, log: ChatLogHandle<S>) -> Result<(), Error> {
+        self.accept(listener, |tcp| handle_feed(tcp, log.clone(), self.credentials.clone(), self.limits.clone())).await
+    }
+
+    // `serve_sessions` on a runtime of its own with `worker_count` threads (BLOCKING)
+    pub fn start_session<S: ChatStore>(&self, worker_count: usize, tx: Sender<Submission>, log: ChatLogHandle<S>) -> Result<(), Error> {
+        self.start_session_on(self.listen()?, worker_count, tx, log)
+    }
+
+    // `start_session` on a listener from `listen`
+    pub fn start_session_on<S: ChatStore>(&self, listener: StdTcpListener, worker_count: usize, tx: Sender<Submission>, log: ChatLogHandle<S>) -> Result<(), Error> {
+        runtime(worker_count)?.block_on(self.serve_sessions_on(listener, tx, log))
+    }
+
+    // `serve_feeds` on a runtime of its own with `worker_count` threads (BLOCKING)
+    pub fn start_feed<S: ChatStore>(&self, worker_count: usize, log: ChatLogHandle<S>) -> Result<(), Error> {
+        self.start_feed_on(self.listen()?, worker_count, log)
+    }
+
+    // `start_feed` on a listener from `listen`
+    pub fn start_feed_on<S: ChatStore>(&self, listener: StdTcpListener, worker_count: usize, log: ChatLogHandle<S>) -> Result<(), Error> {
+        runtime(worker_count)?.block_on(self.serve_feeds_on(listener, log))
+    }
+}
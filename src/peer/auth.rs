@@ -0,0 +1,100 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error, ErrorKind},
+    path::Path,
+    sync::Arc,
+};
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
+
+use crate::request::request::{ChatRequest, remove_option};
+
+/**
+ * Credentials file
+ * ----------------
+ * Who may open a session, one user per line: the name, a space, and the argon2 hash (PHC string)
+ * of their password or pre-shared token. Blank lines and lines starting with `#` are skipped.
+ *
+ * Dan $argon2id$v=19$m=19456,t=2,p=1$...
+ *
+ * `cargo run --example credentials -- <file> <name>` hashes a password read from stdin and adds
+ * the line. Names are matched regardless of case, like the name registry (see `peer::registry`).
+ */
+pub struct Credentials {
+    users: HashMap<String, String>,
+}
+
+impl Credentials {
+    pub fn load(path: &Path) -> Result<Credentials, Error> {
+        let mut users = HashMap::new();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(' ') {
+                Some((name, hash)) if PasswordHash::new(hash.trim()).is_ok() => {
+                    users.insert(name.to_lowercase(), hash.trim().to_string());
+                },
+                _ => {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("bad credentials on line {}", number + 1)));
+                }
+            }
+        }
+        Ok(Credentials { users })
+    }
+
+    // Whether `secret` is the password (or token) of `name`
+    pub fn verify(&self, name: &str, secret: &str) -> bool {
+        let hash = match self.users.get(&name.to_lowercase()) {
+            Some(hash) => hash,
+            None => { return false; },
+        };
+        match PasswordHash::new(hash) {
+            Ok(hash) => Argon2::default().verify_password(secret.as_bytes(), &hash).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+// Hash a password (or token) for the credentials file
+pub fn hash_secret(secret: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    match Argon2::default().hash_password(secret.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => Err(Error::other(e.to_string())),
+    }
+}
+
+// Secrets travel in the INIT object as hex, so they may contain spaces or `=`
+pub fn encode_secret(secret: &str) -> String {
+    secret.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn decode_secret(hex: &str) -> Option<String> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect();
+    String::from_utf8(bytes?).ok()
+}
+
+// Check an INIT against the credentials, if the server has any, and strip its password so it is
+// never logged. Returns false if the INIT doesn't authenticate its subject.
+pub(crate) fn authenticate(init: &mut ChatRequest, credentials: &Option<Arc<Credentials>>) -> bool {
+    let secret = init.password().and_then(|hex| decode_secret(&hex));
+    if let Some(object) = init.object.clone() {
+        init.object = Some(remove_option(&object, "password"));
+    }
+    match (credentials, init.subject.as_ref(), secret) {
+        (None, _, _) => true,
+        (Some(credentials), Some(subject), Some(secret)) => credentials.verify(subject, &secret),
+        _ => false,
+    }
+}
@@ -1,111 +1,870 @@
 use std::{
-    io::{Write, Error},
+    collections::{HashMap, HashSet},
+    io::{Write, Error, ErrorKind, BufReader},
+    path::{Path, PathBuf},
     net::{TcpListener, TcpStream},
     sync::{
         Arc,
         Mutex,
+        Condvar,
         mpsc::{self, Receiver, Sender},
     },
     thread::{self, JoinHandle}
 };
 use crate::{
-    request::request::ChatRequest,
-    threadpool::threadpool::Threadpool
+    request::{
+        request::{ChatRequest, ChatRequestStatus, ChatRequestVerb, Backfill, DEFAULT_ROOM, MAX_REQUEST_LENGTH},
+        entry::LogEntry,
+        response::ErrorCode,
+    },
+    threadpool::threadpool::{Overflow, Threadpool},
+    peer::{
+        logstore::{ChatStore, FileLog, IdCounter, SyncPolicy, open_log_dir, room_log_path},
+        transport::accept_tls,
+        e2e::{Sealed, parse_public_key},
+        shutdown::ShutdownSignal,
+        framing::{Frame, FrameReader},
+        subscribers::{Admitted, FeedLimits, Subscribers},
+        registry::NameRegistry,
+        auth::{Credentials, authenticate},
+    },
 };
+use rustls::ServerConfig;
+
+// How many feeds the feed listener serves at once unless told otherwise. Feeds live as long as
+// their subscriber, so there are many more of them than of other connections.
+pub const FEED_EXECUTORS: usize = 1000;
 
-type TextLog = Arc<Mutex<Vec<String>>>;
+// Room name -> entries logged in that room
+type TextLog<S> = Arc<Mutex<HashMap<String, S>>>;
+// Creates the store for a room we haven't seen before
+type StoreFactory<S> = Box<dyn Fn(&str) -> Result<S, Error> + Send>;
+// Subject -> rooms the subject has joined
+type Memberships = Arc<Mutex<HashMap<String, HashSet<String>>>>;
+// (Subject, id of the session holding its name) -> direct messages for the subject, kept until it
+// ENDs. Subjects without a session share theirs with every feed for them.
+type Inboxes = Arc<Mutex<HashMap<(String, Option<u64>), Vec<LogEntry>>>>;
+// Subject -> public key (hex) published for encrypted DMs
+type PublicKeys = Arc<Mutex<HashMap<String, String>>>;
+// What the chatlog made of a request: the id it was logged under, or why it wasn't logged
+pub type Outcome = Result<u64, ErrorCode>;
 
-pub struct InMemoryChatBuffer {
-    pub text: TextLog,
-    receiver: Receiver<ChatRequest>,
-    sender: Sender<ChatRequest>,
+// Where a chatlog persisted with `InMemoryChatBuffer::open` reserves its ids (see `logstore::IdCounter`)
+const IDS_FILE_NAME: &str = "next-id";
+
+// Shared so feeds can tell what was logged before they started (see `ChatLogHandle::next_id`)
+type Ids = Arc<Mutex<IdCounter>>;
+// The notice written to every feed when the chatlog shuts down, once it has been logged
+type Closing = Arc<Mutex<Option<LogEntry>>>;
+// The chatlog thread, the feed listener thread, and a Sender to submit requests to the chatlog
+pub type ListeningThreads = (JoinHandle<Result<(), Error>>, JoinHandle<Result<(), Error>>, Sender<Submission>);
+
+/**
+ * What the chatlog thread receives
+ */
+pub enum Submission {
+    // A request. If `reply` is set, the chatlog sends the request's Outcome back on it once the
+    // request has been applied.
+    Request { request: ChatRequest, reply: Option<Sender<Outcome>> },
+    // Write `notice` to every feed and end them, flush the stores, and stop the chatlog thread
+    Shutdown { notice: String },
 }
 
-// TODO: Consider tightly coupling this to ChatRequest
-fn handle_connection(stream: Result<TcpStream, Error>, text: TextLog) -> Result<(), Error> {
-    match stream {
+impl From<ChatRequest> for Submission {
+    fn from(request: ChatRequest) -> Submission {
+        Submission::Request { request, reply: None }
+    }
+}
+
+/**
+ * The chatlog. Requests are stored as LogEntries (see `request::entry`) in a ChatStore per room
+ * (see `peer::logstore`); memberships, direct-message inboxes and published keys always live in
+ * memory.
+ */
+pub struct InMemoryChatBuffer<S: ChatStore = Vec<LogEntry>> {
+    pub text: TextLog<S>,
+    pub members: Memberships,
+    pub inboxes: Inboxes,
+    pub keys: PublicKeys,
+    pub notifier: Notifier,
+    // Stops the feed listener (see `peer::shutdown`). Share it with the servers.
+    pub shutdown: ShutdownSignal,
+    // The feeds open, and what slow ones may do (see `with_feed_limits`)
+    pub subscribers: Subscribers,
+    // Subjects with a session open on any server (see `peer::registry`)
+    pub names: NameRegistry,
+    // Sent to every new feed (see `with_motd`)
+    pub motd: Option<String>,
+    // Who may open a feed. Anyone may if there are none.
+    credentials: Option<Arc<Credentials>>,
+    closing: Closing,
+    new_store: StoreFactory<S>,
+    // Hands out entry ids, which are unique across rooms and survive restarts with a FileLog
+    ids: Ids,
+    receiver: Receiver<Submission>,
+    sender: Sender<Submission>,
+}
+
+// Cloneable handle to the shared state of a chatlog, for threads that write feeds.
+pub struct ChatLogHandle<S: ChatStore = Vec<LogEntry>> {
+    pub text: TextLog<S>,
+    pub members: Memberships,
+    pub inboxes: Inboxes,
+    pub notifier: Notifier,
+    pub shutdown: ShutdownSignal,
+    pub subscribers: Subscribers,
+    pub names: NameRegistry,
+    pub motd: Option<String>,
+    closing: Closing,
+    ids: Ids,
+}
+
+impl<S: ChatStore> Clone for ChatLogHandle<S> {
+    fn clone(&self) -> Self {
+        ChatLogHandle {
+            text: self.text.clone(),
+            members: self.members.clone(),
+            inboxes: self.inboxes.clone(),
+            notifier: self.notifier.clone(),
+            shutdown: self.shutdown.clone(),
+            subscribers: self.subscribers.clone(),
+            names: self.names.clone(),
+            motd: self.motd.clone(),
+            closing: self.closing.clone(),
+            ids: self.ids.clone(),
+        }
+    }
+}
+
+impl<S: ChatStore> ChatLogHandle<S> {
+    // The subject of a feed handshake's INIT, if a session holds its name. Its feed is the
+    // session's own (see `FeedCursor`).
+    pub fn held_by_session(&self, init: &ChatRequest) -> Option<String> {
+        init.subject.clone().filter(|subject| self.names.session(subject).is_some())
+    }
+    // The id the next entry will get. Everything logged so far has a lower one.
+    pub fn next_id(&self) -> u64 {
+        match self.ids.lock() {
+            Ok(ids) => ids.peek(),
+            Err(poisoned) => poisoned.into_inner().peek(),
+        }
+    }
+
+    // Which log the ids are from (see `logstore::IdCounter`)
+    pub fn epoch(&self) -> u64 {
+        match self.ids.lock() {
+            Ok(ids) => ids.epoch(),
+            Err(poisoned) => poisoned.into_inner().epoch(),
+        }
+    }
+
+    // The backfill a feed opened with `init` starts with. An id from another log (the INIT's
+    // `epoch=` isn't ours) says nothing about what the client has seen here, so it gets everything.
+    pub fn backfill_for(&self, init: &ChatRequest) -> Backfill {
+        match (init.backfill(), init.epoch()) {
+            (Backfill::Since(_), Some(epoch)) if epoch != self.epoch() => Backfill::All,
+            (backfill, _) => backfill,
+        }
+    }
+
+    // Whether the chatlog has shut down (see `Submission::Shutdown`)
+    pub fn is_closing(&self) -> bool {
+        self.closing_notice().is_some()
+    }
+
+    fn closing_notice(&self) -> Option<LogEntry> {
+        match self.closing.lock() {
+            Ok(closing) => closing.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+/**
+ * Wakes up feed writers whenever the chatlog changes, so they can sleep instead of polling.
+ * Holds a generation counter that is bumped on every change. With the `async` feature, every
+ * change is also broadcast to the tasks of `peer::async_server`.
+ */
+#[derive(Clone)]
+pub struct Notifier {
+    generation: Arc<(Mutex<u64>, Condvar)>,
+    #[cfg(feature = "async")]
+    changes: tokio::sync::broadcast::Sender<u64>,
+}
+
+impl Notifier {
+    pub fn new() -> Notifier {
+        Notifier {
+            generation: Arc::new((Mutex::new(0), Condvar::new())),
+            // Feeds only need to know that something changed, so lagging behind is harmless
+            #[cfg(feature = "async")]
+            changes: tokio::sync::broadcast::channel(16).0,
+        }
+    }
+
+    // Receives the new generation on every change
+    #[cfg(feature = "async")]
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    pub fn generation(&self) -> u64 {
+        match self.generation.0.lock() {
+            Ok(generation) => *generation,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    // Bump the generation and wake every waiting feed writer
+    pub fn notify(&self) {
+        let (lock, condvar) = &*self.generation;
+        match lock.lock() {
+            Ok(mut generation) => { *generation += 1; },
+            Err(poisoned) => { *poisoned.into_inner() += 1; },
+        }
+        condvar.notify_all();
+        // Nobody may be listening
+        #[cfg(feature = "async")]
+        self.changes.send(self.generation()).unwrap_or(0);
+    }
+
+    // Sleep until the generation moves past `seen` (BLOCKING). Returns the new generation.
+    pub fn wait_for_change(&self, seen: u64) -> u64 {
+        let (lock, condvar) = &*self.generation;
+        let guard = match lock.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match condvar.wait_while(guard, |generation| *generation == seen) {
+            Ok(generation) => *generation,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier::new()
+    }
+}
+
+// Feed subscribers start by sending a single line with their subject, optionally followed by
+// the same options as an INIT request, e.g. `Dan backfill=last:20\n`. Returns the INIT it stands
+// for: with credentials, it must authenticate its subject like a session's INIT does (see
+// `auth::authenticate`), e.g. `Dan backfill=all password=<hex>`.
+pub fn parse_feed_handshake(handshake: &str) -> ChatRequest {
+    let (subject, options) = handshake.trim().split_once(' ').unwrap_or((handshake.trim(), ""));
+    ChatRequest {
+        subject: Some(subject.to_string()),
+        verb: ChatRequestVerb::INIT,
+        object: Some(options.to_string()),
+        status: ChatRequestStatus::Valid,
+    }
+}
+
+fn handle_connection<S: ChatStore>(stream: Result<TcpStream, Error>, tls: Option<Arc<ServerConfig>>, credentials: Option<Arc<Credentials>>, log: ChatLogHandle<S>) -> Result<(), Error> {
+    let _tracked = stream.as_ref().ok().and_then(|tcp| log.shutdown.track(tcp));
+    match stream.and_then(|tcp| accept_tls(tcp, &tls)) {
         Ok(mut stream_obj) => {
-            // Adds deduping so we only write what hasn't been written yet.
-            let mut start_from: usize = 0;
-            loop {
-                match text.try_lock() {
-                    Ok(array) => {
-                        let end_at = array.len();
-                        if end_at - start_from > usize::MIN {
-                            match stream_obj.write(
-                                format!(
-                                    "{}\n",
-                                    array[start_from..end_at].join("\n")
-                                ).as_bytes()) {
-                                Err(e) => {
-                                    println!("stream write error: {:?}", e);
-                                    break;
-                                },
-                                _ => {}
-                            }
-                            ;
-                            start_from = end_at;
-                        }
-                    },
-                    _ => { },
-                }
+            stream_obj.set_write_timeout(log.subscribers.limits.write_timeout)?;
+            let handshake = match FrameReader::new(BufReader::new(stream_obj.try_clone()?), MAX_REQUEST_LENGTH).next_frame()? {
+                Some(Frame::Line(handshake)) => handshake,
+                _ => { return Ok(()); },
+            };
+            let mut init = parse_feed_handshake(&handshake);
+            if !authenticate(&mut init, &credentials) {
+                println!("refusing feed for {:?}: authentication failed", init.subject);
+                return Ok(());
+            }
+            if let Some(subject) = log.held_by_session(&init) {
+                println!("refusing feed for {}: a session has the name", subject);
+                return Ok(());
             }
+            let cursor = FeedCursor::new(init.subject.as_deref().unwrap_or_default(), log.backfill_for(&init), &log);
+            write_feed(&mut stream_obj, cursor, log)?;
         },
         Err(e) => { println!("Connection broke: {:?}", e)},
     }
     Ok(())
 }
 
-//TODO turn functional parts into a trait and re-implement with trait
-impl InMemoryChatBuffer {
-    pub fn new() -> InMemoryChatBuffer {
+// Where a feed starts reading a room it hasn't seen before. `live` feeds start with whatever was
+// logged since they were created, when the chatlog's next id was `first_live_id`, even if they
+// only see the room later.
+fn backfill_start<S: ChatStore>(backfill: Backfill, store: &S, first_live_id: u64) -> usize {
+    match backfill {
+        Backfill::All => 0,
+        Backfill::Live => store.position_after(first_live_id.saturating_sub(1)),
+        Backfill::Last(n) => store.len().saturating_sub(n),
+        Backfill::Since(id) => store.position_after(id),
+    }
+}
+
+/**
+ * Where a subject's feed is up to. Each `next_batch` takes what was logged for the subject since
+ * the last one: entries from the rooms it has joined, plus its direct messages, in id order.
+ * `backfill` decides how much of a room's history goes out when the feed first sees the room.
+ * A session's direct messages only go to its own feed, which ends once another session takes its
+ * name over (see `peer::session`). Other feeds end as soon as a session holds the name.
+ */
+pub struct FeedCursor {
+    subject: String,
+    // The session the feed is for (see `with_claim`)
+    claim: Option<u64>,
+    // How much of the inbox has been written
+    inbox_from: usize,
+    backfill: Backfill,
+    // Adds deduping so we only write what hasn't been written yet (per room).
+    start_from: HashMap<String, usize>,
+    was_member: bool,
+    // The chatlog's next id when the feed was created (see `backfill_start`)
+    first_live_id: u64,
+    greeted: bool,
+}
+
+/**
+ * What a feed should write next
+ */
+pub enum FeedBatch {
+    // Write these (maybe none), then wait for the next change
+    Entries(Vec<LogEntry>),
+    // Write these, ending with the chatlog's closing notice, and stop
+    Closing(Vec<LogEntry>),
+    // The subject has disconnected
+    Ended,
+}
+
+impl FeedCursor {
+    // A feed for `subject` from `log`. Sessions create theirs before their INIT is submitted, so
+    // `live` ones start with it.
+    pub fn new<S: ChatStore>(subject: &str, backfill: Backfill, log: &ChatLogHandle<S>) -> FeedCursor {
+        FeedCursor {
+            subject: subject.to_string(),
+            claim: None,
+            inbox_from: 0,
+            backfill,
+            start_from: HashMap::new(),
+            was_member: false,
+            first_live_id: log.next_id(),
+            greeted: false,
+        }
+    }
+
+    // The feed of the session holding `claim` on the subject's name (see `peer::registry`)
+    pub fn with_claim(mut self, claim: u64) -> Self {
+        self.claim = Some(claim);
+        self
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn next_batch<S: ChatStore>(&mut self, log: &ChatLogHandle<S>) -> FeedBatch {
+        // Everything logged before the chatlog closed is already in place, so it goes out first
+        let closing = log.closing_notice();
+        // Someone else's session has the name, and with it the subject's direct messages
+        if log.names.session(&self.subject).is_some_and(|holder| Some(holder) != self.claim) {
+            return FeedBatch::Ended;
+        }
+        let rooms: Vec<String> = match log.members.lock() {
+            Ok(map) => match map.get(&self.subject) {
+                Some(rooms) => rooms.iter().cloned().collect(),
+                None if self.was_member => { return FeedBatch::Ended; },
+                None => vec![],
+            },
+            _ => { return FeedBatch::Ended; },
+        };
+        self.was_member = self.was_member || !rooms.is_empty();
+        let mut pending: Vec<LogEntry> = match log.inboxes.lock() {
+            Ok(map) => match map.get(&(self.subject.clone(), self.claim)) {
+                Some(entries) => {
+                    let unread = entries.get(self.inbox_from..).unwrap_or_default().to_vec();
+                    self.inbox_from = entries.len();
+                    unread
+                },
+                None => vec![],
+            },
+            _ => vec![],
+        };
+        if let Ok(logs) = log.text.lock() {
+            for room in rooms {
+                let store = match logs.get(&room) {
+                    Some(store) => store,
+                    None => { continue; },
+                };
+                let (backfill, first_live_id) = (self.backfill, self.first_live_id);
+                let cursor = self.start_from.entry(room).or_insert_with(|| backfill_start(backfill, store, first_live_id));
+                pending.extend(store.follow(cursor));
+            }
+        }
+        // An END is logged to every room the subject was in, but it is still one message
+        pending.sort_by_key(|entry| entry.id);
+        pending.dedup_by_key(|entry| entry.id);
+        if !std::mem::replace(&mut self.greeted, true) {
+            if let Some(motd) = log.motd.as_ref() {
+                pending.push(LogEntry::notice(0, motd.clone()));
+            }
+        }
+        match closing {
+            Some(notice) => {
+                pending.push(notice);
+                FeedBatch::Closing(pending)
+            },
+            None => FeedBatch::Entries(pending),
+        }
+    }
+}
+
+// The lines a feed writes for a batch of entries
+pub fn feed_lines(entries: &[LogEntry]) -> String {
+    let lines: Vec<String> = entries.iter().map(|entry| entry.to_json()).collect();
+    format!("{}\n", lines.join("\n"))
+}
+
+// Writes a subject's feed to a stream (BLOCKING), one JSON LogEntry per line (see `FeedCursor`).
+// Returns once the subject has disconnected, after writing the chatlog's closing notice, or when the
+// subscriber can't keep up (see `peer::subscribers`; the caller sets the write timeout on the stream).
+// Sleeps on the chatlog's Notifier between updates.
+pub fn write_feed<S: ChatStore, W: Write>(stream_obj: &mut W, mut cursor: FeedCursor, log: ChatLogHandle<S>) -> Result<(), Error> {
+    let subject = cursor.subject().to_string();
+    let mut subscription = log.subscribers.subscribe(&subject);
+    let mut seen = log.notifier.generation();
+    loop {
+        let (pending, last) = match cursor.next_batch(&log) {
+            FeedBatch::Entries(pending) => (pending, false),
+            FeedBatch::Closing(pending) => (pending, true),
+            FeedBatch::Ended => { return Ok(()); },
+        };
+        let (pending, last) = match subscription.admit(pending) {
+            Admitted::Write(pending) => (pending, last),
+            Admitted::HangUp(pending) => (pending, true),
+        };
+        // Write outside of the lock so a slow reader doesn't hold up the chatlog
+        if !pending.is_empty() {
+            match stream_obj.write_all(feed_lines(&pending).as_bytes()) {
+                Ok(()) => { subscription.wrote(pending.len()); },
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    println!("{} stopped reading its feed, disconnecting", subject);
+                    return Ok(());
+                },
+                Err(e) => {
+                    println!("stream write error: {:?}", e);
+                    return Ok(());
+                },
+            }
+        }
+        if last {
+            return Ok(());
+        }
+        seen = log.notifier.wait_for_change(seen);
+    }
+}
+
+// The id after the newest entry in any of `rooms`
+fn first_free_id<S: ChatStore>(rooms: &HashMap<String, S>) -> u64 {
+    rooms.values()
+        .filter_map(|store| store.last())
+        .map(|entry| entry.id + 1)
+        .max()
+        .unwrap_or(1)
+}
+
+impl InMemoryChatBuffer<Vec<LogEntry>> {
+    pub fn new() -> InMemoryChatBuffer<Vec<LogEntry>> {
+        InMemoryChatBuffer::with_store(HashMap::new(), |_| Ok(vec![]))
+    }
+}
+
+impl Default for InMemoryChatBuffer<Vec<LogEntry>> {
+    fn default() -> Self {
+        InMemoryChatBuffer::new()
+    }
+}
+
+impl InMemoryChatBuffer<FileLog> {
+    // A chat buffer whose rooms are persisted to `log_dir`. Rooms already in the directory are
+    // loaded, so a restarted server resumes where it left off.
+    pub fn open(log_dir: &Path, policy: SyncPolicy) -> Result<InMemoryChatBuffer<FileLog>, Error> {
+        let rooms = open_log_dir(log_dir, policy)?;
+        let ids = IdCounter::open(&log_dir.join(IDS_FILE_NAME), first_free_id(&rooms))?;
+        let log_dir: PathBuf = log_dir.to_path_buf();
+        let mut chat_buffer = InMemoryChatBuffer::with_store(rooms, move |room| {
+            FileLog::open(&room_log_path(&log_dir, room), policy)
+        });
+        chat_buffer.ids = Arc::new(Mutex::new(ids));
+        Ok(chat_buffer)
+    }
+}
+
+impl<S: ChatStore> InMemoryChatBuffer<S> {
+    // A chat buffer starting with `rooms`, using `new_store` to create stores for new rooms
+    pub fn with_store<F>(rooms: HashMap<String, S>, new_store: F) -> InMemoryChatBuffer<S>
+    where
+        F: Fn(&str) -> Result<S, Error> + Send + 'static
+    {
         let (tx, rx) = mpsc::channel();
+        let next_id = first_free_id(&rooms);
         InMemoryChatBuffer {
-            text: Arc::new(Mutex::new(vec![])),
+            ids: Arc::new(Mutex::new(IdCounter::new(next_id))),
+            text: Arc::new(Mutex::new(rooms)),
+            members: Arc::new(Mutex::new(HashMap::new())),
+            inboxes: Arc::new(Mutex::new(HashMap::new())),
+            keys: Arc::new(Mutex::new(HashMap::new())),
+            notifier: Notifier::new(),
+            shutdown: ShutdownSignal::new(),
+            subscribers: Subscribers::default(),
+            names: NameRegistry::new(),
+            motd: None,
+            credentials: None,
+            closing: Arc::new(Mutex::new(None)),
+            new_store: Box::new(new_store),
             receiver: rx,
             sender: tx,
         }
     }
 
+    // Hold every feed, on any server, to `limits` (see `peer::subscribers`)
+    pub fn with_feed_limits(mut self, limits: FeedLimits) -> InMemoryChatBuffer<S> {
+        self.subscribers = Subscribers::new(limits);
+        self
+    }
+
+    // Greet every feed with `motd`: its first batch ends with it as a notice, after the backfill.
+    // Notices from outside the log have id 0, so clients resuming from the last id they saw skip it.
+    pub fn with_motd(mut self, motd: String) -> InMemoryChatBuffer<S> {
+        self.motd = Some(motd);
+        self
+    }
+
+    // Only open feeds for subjects whose handshake carries their password (see
+    // `parse_feed_handshake` and `peer::auth`)
+    pub fn with_credentials(mut self, credentials: impl Into<Arc<Credentials>>) -> InMemoryChatBuffer<S> {
+        self.credentials = Some(credentials.into());
+        self
+    }
+
     // Create Senders that can send data to the chatlog
-    pub fn create_tx(&self) -> Sender<ChatRequest> {
+    pub fn create_tx(&self) -> Sender<Submission> {
         self.sender.clone()
     }
 
-    // Listen for updates to the chatlog (BLOCKING)
-    pub fn listen_for_updates(&self) -> Result<(), Error> {
-        for chat_request in self.receiver.iter() {
-            match self.text.clone().lock() {
-                Ok(mut arr) => {
-                    arr.push(chat_request.to_log()); 
+    // Create a handle that can write feeds from the chatlog
+    pub fn create_handle(&self) -> ChatLogHandle<S> {
+        ChatLogHandle {
+            text: self.text.clone(),
+            members: self.members.clone(),
+            inboxes: self.inboxes.clone(),
+            notifier: self.notifier.clone(),
+            shutdown: self.shutdown.clone(),
+            subscribers: self.subscribers.clone(),
+            names: self.names.clone(),
+            motd: self.motd.clone(),
+            closing: self.closing.clone(),
+            ids: self.ids.clone(),
+        }
+    }
+
+    // Apply membership changes for a request. Returns the rooms the request should be logged to.
+    fn update_members(&self, chat_request: &ChatRequest) -> Vec<String> {
+        let subject = match chat_request.subject.as_ref() {
+            Some(subject) => subject.clone(),
+            None => { return vec![]; },
+        };
+        let mut members = match self.members.lock() {
+            Ok(members) => members,
+            _ => {
+                println!("Membership update failed");
+                return vec![];
+            }
+        };
+        match chat_request.verb {
+            ChatRequestVerb::INIT => {
+                members.entry(subject).or_default().insert(String::from(DEFAULT_ROOM));
+                vec![String::from(DEFAULT_ROOM)]
+            },
+            ChatRequestVerb::JOIN => match chat_request.room() {
+                Some(room) => {
+                    members.entry(subject).or_default().insert(room.clone());
+                    vec![room]
+                },
+                None => vec![],
+            },
+            ChatRequestVerb::PART => match chat_request.room() {
+                Some(room) => {
+                    let left = match members.get_mut(&subject) {
+                        Some(rooms) => rooms.remove(&room),
+                        None => false,
+                    };
+                    if left { vec![room] } else { vec![] }
                 },
-                _ => { println!("Update listener failed"); }
+                None => vec![],
+            },
+            ChatRequestVerb::TX | ChatRequestVerb::ROOMTX => match chat_request.room() {
+                Some(room) if members.get(&subject).is_some_and(|rooms| rooms.contains(&room)) => vec![room],
+                _ => vec![],
+            },
+            ChatRequestVerb::END => match members.remove(&subject) {
+                Some(rooms) => rooms.into_iter().collect(),
+                None => vec![],
+            },
+            ChatRequestVerb::DM | ChatRequestVerb::EDM | ChatRequestVerb::KEY | ChatRequestVerb::GETKEY |
+            ChatRequestVerb::PING | ChatRequestVerb::NOTICE | ChatRequestVerb::NONE => vec![],
+        }
+    }
+
+    fn take_id(&self) -> u64 {
+        match self.ids.lock() {
+            Ok(mut ids) => ids.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        }
+    }
+
+    // The inbox for `subject`'s direct messages: its session's, if it has one
+    fn inbox(&self, subject: String) -> (String, Option<u64>) {
+        let claim = self.names.session(&subject);
+        (subject, claim)
+    }
+
+    // Deliver a DM or EDM to the target's inbox (and echo it to the sender). They never touch the
+    // TextLog.
+    fn deliver_direct(&self, chat_request: &ChatRequest) -> Outcome {
+        let (subject, target) = match (chat_request.subject.as_ref(), chat_request.target()) {
+            (Some(subject), Some(target)) => (subject.clone(), target),
+            _ => { return Err(ErrorCode::Malformed); },
+        };
+        let target_connected = match self.members.lock() {
+            Ok(members) => members.contains_key(&target),
+            _ => false,
+        };
+        match self.inboxes.lock() {
+            Ok(mut inboxes) => {
+                if target_connected {
+                    let id = self.take_id();
+                    let entry = LogEntry::from_request(id, None, chat_request);
+                    if target != subject {
+                        inboxes.entry(self.inbox(subject)).or_default().push(entry.clone());
+                    }
+                    inboxes.entry(self.inbox(target)).or_default().push(entry);
+                    Ok(id)
+                } else {
+                    inboxes.entry(self.inbox(subject)).or_default().push(
+                        LogEntry::notice(self.take_id(), format!("{} is not connected", target))
+                    );
+                    Err(ErrorCode::NotConnected)
+                }
+            },
+            _ => {
+                println!("Direct message delivery failed");
+                Err(ErrorCode::Internal)
+            }
+        }
+    }
+
+    // Remember the key a subject published for encrypted DMs. Keys are never logged.
+    fn publish_key(&self, chat_request: &ChatRequest) -> Outcome {
+        let (subject, key) = match (chat_request.subject.as_ref(), chat_request.object.as_ref()) {
+            (Some(subject), Some(key)) if parse_public_key(key).is_some() => (subject.clone(), key.trim().to_string()),
+            _ => { return Err(ErrorCode::Malformed); },
+        };
+        match self.keys.lock() {
+            Ok(mut keys) => {
+                keys.insert(subject, key);
+                Ok(self.take_id())
+            },
+            _ => Err(ErrorCode::Internal),
+        }
+    }
+
+    // Answer a GETKEY with a KEY entry on the asking subject's feed, or a notice if there's no key
+    fn lookup_key(&self, chat_request: &ChatRequest) -> Outcome {
+        let (subject, owner) = match (chat_request.subject.as_ref(), chat_request.object.as_ref()) {
+            (Some(subject), Some(owner)) if !owner.trim().is_empty() => (subject.clone(), owner.trim().to_string()),
+            _ => { return Err(ErrorCode::Malformed); },
+        };
+        let key = match self.keys.lock() {
+            Ok(keys) => keys.get(&owner).cloned(),
+            _ => { return Err(ErrorCode::Internal); },
+        };
+        let mut inboxes = match self.inboxes.lock() {
+            Ok(inboxes) => inboxes,
+            _ => { return Err(ErrorCode::Internal); },
+        };
+        let id = self.take_id();
+        match key {
+            Some(key) => {
+                let answer = ChatRequest {
+                    subject: Some(owner),
+                    verb: ChatRequestVerb::KEY,
+                    object: Some(key),
+                    status: chat_request.status,
+                };
+                inboxes.entry(self.inbox(subject)).or_default().push(LogEntry::from_request(id, None, &answer));
+                Ok(id)
+            },
+            None => {
+                inboxes.entry(self.inbox(subject)).or_default().push(
+                    LogEntry::notice(id, format!("{} has no key for encrypted messages", owner))
+                );
+                Err(ErrorCode::NoKey)
+            },
+        }
+    }
+
+    // Apply a request to the chatlog
+    fn apply(&self, chat_request: &ChatRequest) -> Outcome {
+        match chat_request.verb {
+            ChatRequestVerb::DM => { return self.deliver_direct(chat_request); },
+            // The server can't read EDMs, but it can refuse ones that aren't sealed
+            ChatRequestVerb::EDM => {
+                return match chat_request.body().and_then(|body| Sealed::parse(&body)) {
+                    Some(_) => self.deliver_direct(chat_request),
+                    None => Err(ErrorCode::Malformed),
+                };
+            },
+            ChatRequestVerb::KEY => { return self.publish_key(chat_request); },
+            ChatRequestVerb::GETKEY => { return self.lookup_key(chat_request); },
+            // Only the server sends notices, and it answers PINGs itself
+            ChatRequestVerb::PING | ChatRequestVerb::NOTICE | ChatRequestVerb::NONE => { return Err(ErrorCode::UnknownVerb); },
+            _ => {},
+        }
+        let rooms = self.update_members(chat_request);
+        if let ChatRequestVerb::END = chat_request.verb {
+            if let (Ok(mut inboxes), Some(subject)) = (self.inboxes.lock(), chat_request.subject.as_ref()) {
+                inboxes.retain(|(owner, _), _| owner != subject);
+            }
+            // The next session under this name may belong to someone else
+            if let (Ok(mut keys), Some(subject)) = (self.keys.lock(), chat_request.subject.as_ref()) {
+                keys.remove(subject);
+            }
+        }
+        if rooms.is_empty() {
+            return match (chat_request.verb, chat_request.room()) {
+                (ChatRequestVerb::JOIN | ChatRequestVerb::PART | ChatRequestVerb::ROOMTX, None) => Err(ErrorCode::Malformed),
+                _ => Err(ErrorCode::NotAMember),
+            };
+        }
+        let id = self.take_id();
+        let mut outcome = Ok(id);
+        match self.text.clone().lock() {
+            Ok(mut logs) => {
+                for room in rooms {
+                    if !logs.contains_key(&room) {
+                        match (self.new_store)(&room) {
+                            Ok(store) => { logs.insert(room.clone(), store); },
+                            Err(e) => {
+                                println!("could not create log for #{}: {:?}", room, e);
+                                outcome = Err(ErrorCode::Internal);
+                                continue;
+                            }
+                        }
+                    }
+                    if let Some(store) = logs.get_mut(&room) {
+                        store.append(LogEntry::from_request(id, Some(room.clone()), chat_request)).unwrap_or_else(|e| {
+                            println!("could not write to log for #{}: {:?}", room, e);
+                            outcome = Err(ErrorCode::Internal);
+                        });
+                    }
+                }
+            },
+            _ => {
+                println!("Update listener failed");
+                outcome = Err(ErrorCode::Internal);
+            }
+        }
+        outcome
+    }
+
+    // Log the closing notice for every feed to write, and flush the stores
+    fn close(&self, notice: String) -> Result<(), Error> {
+        let entry = LogEntry::notice(self.take_id(), notice);
+        match self.closing.lock() {
+            Ok(mut closing) => { *closing = Some(entry); },
+            Err(poisoned) => { *poisoned.into_inner() = Some(entry); },
+        }
+        self.notifier.notify();
+        let mut result = Ok(());
+        if let Ok(mut logs) = self.text.lock() {
+            for (room, store) in logs.iter_mut() {
+                if let Err(e) = store.flush() {
+                    println!("could not flush log for #{}: {:?}", room, e);
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    // Listen for updates to the chatlog (BLOCKING). Returns after a `Submission::Shutdown`.
+    pub fn listen_for_updates(&self) -> Result<(), Error> {
+        for submission in self.receiver.iter() {
+            let (request, reply) = match submission {
+                Submission::Request { request, reply } => (request, reply),
+                Submission::Shutdown { notice } => { return self.close(notice); },
+            };
+            let outcome = self.apply(&request);
+            // Memberships may have changed even if nothing was logged
+            self.notifier.notify();
+            if let Some(reply) = reply {
+                // The submitter may have hung up already
+                reply.send(outcome).unwrap_or(());
             }
         }
         Ok(())
     }
 }
 
-fn create_listener(text: TextLog, socket: &str, executor_count: usize) -> Result<(), Error> {
-    let listener = TcpListener::bind(socket)?;
-    let mut tp = Threadpool::new(executor_count);
+fn create_listener<S: ChatStore>(log: ChatLogHandle<S>, listener: TcpListener, executor_count: usize, tls: Option<Arc<ServerConfig>>, credentials: Option<Arc<Credentials>>) -> Result<(), Error> {
+    log.shutdown.listen(&listener);
+    // Feeds live as long as their subscriber, so start workers as subscribers come in
+    let mut tp = Threadpool::with_config(1, 0, Overflow::Grow(executor_count));
     for stream in listener.incoming() {
-        let cloned_text = text.clone();
-        tp.execute(move || { 
-            handle_connection(stream, cloned_text).expect("Connection failed");
+        if log.shutdown.is_triggered() {
+            break;
+        }
+        let cloned_log = log.clone();
+        let cloned_tls = tls.clone();
+        let cloned_credentials = credentials.clone();
+        tp.execute(move || {
+            handle_connection(stream, cloned_tls, cloned_credentials, cloned_log).unwrap_or_else(|e| {
+                println!("Connection failed: {:?}", e);
+            });
+        }).unwrap_or_else(|e| {
+            println!("Feed connection dropped: {:?}", e);
         });
     }
     Ok(())
 }
 
-pub fn create_listening_threads_from_inmemory_buffer(chat_buffer: InMemoryChatBuffer, socket_feed: String) -> (JoinHandle<Result<(), Error>>, JoinHandle<Result<(), Error>>, Sender<ChatRequest>) {
-    let text = chat_buffer.text.clone();
+pub fn create_listening_threads_from_inmemory_buffer<S: ChatStore>(chat_buffer: InMemoryChatBuffer<S>, socket_feed: String) -> ListeningThreads {
+    create_listening_threads_with_tls(chat_buffer, socket_feed, None, FEED_EXECUTORS)
+}
+
+// Same as `create_listening_threads_from_inmemory_buffer`, with the feed served over TLS if there's a
+// config, and at most `feed_executors` feeds at once
+pub fn create_listening_threads_with_tls<S: ChatStore>(chat_buffer: InMemoryChatBuffer<S>, socket_feed: String, tls: Option<Arc<ServerConfig>>, feed_executors: usize) -> ListeningThreads {
+    create_threads(chat_buffer, move || TcpListener::bind(socket_feed.as_str()), tls, feed_executors)
+}
+
+// Same as `create_listening_threads_with_tls`, with the feed on a listener that is already bound
+// (e.g. to port 0, to learn the address first)
+pub fn create_listening_threads_on<S: ChatStore>(chat_buffer: InMemoryChatBuffer<S>, listener: TcpListener, tls: Option<Arc<ServerConfig>>) -> ListeningThreads {
+    create_threads(chat_buffer, move || Ok(listener), tls, FEED_EXECUTORS)
+}
+
+fn create_threads<S, F>(chat_buffer: InMemoryChatBuffer<S>, listen: F, tls: Option<Arc<ServerConfig>>, feed_executors: usize) -> ListeningThreads
+where
+    S: ChatStore,
+    F: FnOnce() -> Result<TcpListener, Error> + Send + 'static
+{
+    let log = chat_buffer.create_handle();
     let sender = chat_buffer.create_tx();
+    let credentials = chat_buffer.credentials.clone();
     let handle0 = thread::spawn(move || {
         chat_buffer.listen_for_updates()
     });
     let handle1 = thread::spawn(move|| {
-        create_listener(text, socket_feed.clone().as_str(), 1000)
+        create_listener(log, listen()?, feed_executors, tls, credentials)
     });
     (handle0, handle1, sender)
 }
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error, ErrorKind},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use crypto_box::{
+    Nonce, PublicKey, SalsaBox, SecretKey,
+    aead::{Aead, AeadCore, OsRng},
+};
+use sha2::{Digest, Sha256};
+
+use crate::request::{entry::LogEntry, request::ChatRequestVerb};
+
+/**
+ * End-to-end encrypted direct messages
+ * ------------------------------------
+ * Opt-in (see the `e2e` capability). Every client has a long-lived X25519 key pair and publishes
+ * the public key with a KEY request. To write to someone, a client asks the server for their key
+ * with GETKEY, seals the message with NaCl's crypto_box (X25519 + XSalsa20-Poly1305), and sends it
+ * as an EDM request:
+ *
+ * [1:Dan][2:edm][3:Ann <sender key>:<nonce>:<ciphertext>]
+ *
+ * All three parts are hex. The server relays EDMs like DMs but only ever sees the ciphertext.
+ * Both ends derive the same shared key, so the sender can read the echo of its own message too.
+ *
+ * The server hands out the keys, so it could hand out its own. Clients pin the first key they see
+ * for a subject and show its fingerprint (see `fingerprint`), which people compare out of band.
+ */
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+// Poly1305 adds this much to every ciphertext
+const TAG_SIZE: usize = 16;
+
+// How long the sealed hex of a message of `plaintext_len` bytes is (see `Sealed::to_hex`)
+pub fn sealed_length(plaintext_len: usize) -> usize {
+    2 * KEY_SIZE + 1 + 2 * NONCE_SIZE + 1 + 2 * (plaintext_len + TAG_SIZE)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+// A public key as published with KEY (hex)
+pub fn parse_public_key(hex: &str) -> Option<PublicKey> {
+    let bytes = from_hex(hex.trim())?;
+    match bytes.len() {
+        KEY_SIZE => PublicKey::from_slice(&bytes).ok(),
+        _ => None,
+    }
+}
+
+// Short, human-comparable digest of a public key: the first 16 bytes of its SHA-256, in groups
+// of four hex digits (e.g. `3f2a 9c01 ...`)
+pub fn fingerprint(key: &PublicKey) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    digest[..16]
+        .chunks(2)
+        .map(to_hex)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/**
+ * The body of an EDM request: who sealed it, and the sealed message
+ */
+pub struct Sealed {
+    pub sender_key: PublicKey,
+    pub nonce: Nonce,
+    pub ciphertext: Vec<u8>,
+}
+
+impl Sealed {
+    pub fn parse(string: &str) -> Option<Sealed> {
+        let mut parts = string.trim().split(':');
+        let sender_key = parse_public_key(parts.next()?)?;
+        let nonce = from_hex(parts.next()?)?;
+        let ciphertext = from_hex(parts.next()?)?;
+        if parts.next().is_some() || nonce.len() != NONCE_SIZE || ciphertext.is_empty() {
+            return None;
+        }
+        Some(Sealed { sender_key, nonce: *Nonce::from_slice(&nonce), ciphertext })
+    }
+
+    pub fn to_hex(&self) -> String {
+        format!("{}:{}:{}", to_hex(self.sender_key.as_bytes()), to_hex(&self.nonce), to_hex(&self.ciphertext))
+    }
+}
+
+/**
+ * A client's own key pair
+ */
+pub struct Identity {
+    secret: SecretKey,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity { secret: SecretKey::generate(&mut OsRng) }
+    }
+
+    // Load the secret key kept in `path` (hex), creating the file with a new key if it's missing.
+    // Keep the file: a new key means a new fingerprint for everyone who pinned the old one.
+    pub fn load_or_create(path: &Path) -> Result<Identity, Error> {
+        if path.exists() {
+            let bytes = match from_hex(fs::read_to_string(path)?.trim()) {
+                Some(bytes) if bytes.len() == KEY_SIZE => bytes,
+                _ => { return Err(Error::new(ErrorKind::InvalidData, format!("no key in {:?}", path))); },
+            };
+            return match SecretKey::from_slice(&bytes) {
+                Ok(secret) => Ok(Identity { secret }),
+                Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+            };
+        }
+        let identity = Identity::generate();
+        fs::write(path, format!("{}\n", to_hex(&identity.secret.to_bytes())))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.secret.public_key()
+    }
+
+    // The public key as published with KEY
+    pub fn public_hex(&self) -> String {
+        to_hex(self.public_key().as_bytes())
+    }
+
+    pub fn seal(&self, recipient: &PublicKey, plaintext: &str) -> Option<Sealed> {
+        let nonce = SalsaBox::generate_nonce(&mut OsRng);
+        let ciphertext = SalsaBox::new(recipient, &self.secret).encrypt(&nonce, plaintext.as_bytes()).ok()?;
+        Some(Sealed { sender_key: self.public_key(), nonce, ciphertext })
+    }
+
+    // Open a message exchanged with `peer` (whichever side sealed it)
+    pub fn open(&self, peer: &PublicKey, sealed: &Sealed) -> Option<String> {
+        let plaintext = SalsaBox::new(peer, &self.secret).decrypt(&sealed.nonce, sealed.ciphertext.as_slice()).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+// What learning a subject's key did to the keyring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyChange {
+    // First key seen for the subject; it is now pinned
+    New,
+    // Same as the pinned key
+    Known,
+    // Differs from the pinned key (fingerprint of the pinned one). The pin is kept.
+    Changed(String),
+}
+
+pub type SharedKeyring = Arc<Mutex<Keyring>>;
+
+pub fn lock_keyring(keyring: &SharedKeyring) -> MutexGuard<'_, Keyring> {
+    match keyring.lock() {
+        Ok(keyring) => keyring,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/**
+ * Client side of E2E: our identity, the keys pinned for other subjects, and messages waiting for
+ * a key to arrive. Subjects are looked up ignoring case, as the server's names are (see
+ * `peer::registry`), so `Ann` and `ann` share one pinned key.
+ */
+pub struct Keyring {
+    pub identity: Identity,
+    keys: HashMap<String, PublicKey>,
+    waiting: HashMap<String, Vec<String>>,
+}
+
+impl Keyring {
+    pub fn new(identity: Identity) -> Keyring {
+        Keyring { identity, keys: HashMap::new(), waiting: HashMap::new() }
+    }
+
+    pub fn key_of(&self, subject: &str) -> Option<&PublicKey> {
+        self.keys.get(&subject.to_lowercase())
+    }
+
+    // Pin `key` for `subject` unless a key is already pinned (trust on first use)
+    pub fn learn(&mut self, subject: &str, key: PublicKey) -> KeyChange {
+        let subject = subject.to_lowercase();
+        match self.keys.get(&subject) {
+            Some(pinned) if pinned == &key => KeyChange::Known,
+            Some(pinned) => KeyChange::Changed(fingerprint(pinned)),
+            None => {
+                self.keys.insert(subject, key);
+                KeyChange::New
+            },
+        }
+    }
+
+    // Hold a message for `target` until their key arrives
+    pub fn wait_for_key(&mut self, target: &str, message: String) {
+        self.waiting.entry(target.to_lowercase()).or_default().push(message);
+    }
+
+    pub fn take_waiting(&mut self, target: &str) -> Vec<String> {
+        self.waiting.remove(&target.to_lowercase()).unwrap_or_default()
+    }
+
+    // The EDM object carrying `message` for `target`, if we have their key
+    pub fn seal_for(&self, target: &str, message: &str) -> Option<String> {
+        let sealed = self.identity.seal(self.key_of(target)?, message)?;
+        Some(format!("{} {}", target, sealed.to_hex()))
+    }
+
+    // Decrypt an EDM entry from the feed. `me` is our own subject: our own messages are opened
+    // with the recipient's pinned key, everyone else's with the sender's. The key an EDM names is
+    // never trusted on its own, since whoever relays it can put any key there: messages from a
+    // sender whose key isn't pinned, or that name another key, aren't opened.
+    pub fn open_entry(&self, entry: &LogEntry, me: &str) -> Option<String> {
+        let request = entry.to_request();
+        if request.verb != ChatRequestVerb::EDM {
+            return None;
+        }
+        let sealed = Sealed::parse(&request.body()?)?;
+        let peer = match entry.subject.to_lowercase() == me.to_lowercase() {
+            true => self.key_of(&request.target()?)?,
+            false => match self.key_of(&entry.subject) {
+                Some(pinned) if pinned == &sealed.sender_key => pinned,
+                _ => { return None; },
+            },
+        };
+        self.identity.open(peer, &sealed)
+    }
+}
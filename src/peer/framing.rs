@@ -0,0 +1,145 @@
+use std::io::{BufRead, Error, ErrorKind};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/**
+ * Bounded request lines
+ * ---------------------
+ * `BufRead::lines` buffers a line for as long as it goes on, so one client that never sends a
+ * newline could take all of the server's memory. A FrameReader keeps at most `max_frame` bytes of
+ * a line (not counting its `\n` or `\r\n`): the rest of a longer line is read and thrown away, and
+ * the line comes out as `Frame::TooLong`. The connection stays usable after it.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Line(String),
+    TooLong,
+}
+
+// One line being put together from whatever the reader had buffered
+struct LineBuilder {
+    max_frame: usize,
+    line: Vec<u8>,
+    too_long: bool,
+}
+
+impl LineBuilder {
+    fn new(max_frame: usize) -> LineBuilder {
+        LineBuilder { max_frame, line: vec![], too_long: false }
+    }
+
+    // Take `chunk` up to and including the next newline. Returns how many bytes were taken and
+    // whether they finished the line.
+    fn push(&mut self, chunk: &[u8]) -> (usize, bool) {
+        let (taken, complete) = match chunk.iter().position(|byte| *byte == b'\n') {
+            Some(at) => (at + 1, true),
+            None => (chunk.len(), false),
+        };
+        if !self.too_long {
+            self.line.extend_from_slice(&chunk[..taken]);
+            // Leaves room for the line ending, which doesn't count
+            if self.line.len() > self.max_frame + 2 {
+                self.too_long = true;
+                self.line = vec![];
+            }
+        }
+        (taken, complete)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.line.is_empty() && !self.too_long
+    }
+
+    // The line so far, without its line ending. Starts the next one.
+    fn take(&mut self) -> Result<Frame, Error> {
+        let mut line = std::mem::take(&mut self.line);
+        let too_long = std::mem::replace(&mut self.too_long, false);
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        if too_long || line.len() > self.max_frame {
+            return Ok(Frame::TooLong);
+        }
+        match String::from_utf8(line) {
+            Ok(line) => Ok(Frame::Line(line)),
+            Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    builder: LineBuilder,
+}
+
+impl<R: BufRead> FrameReader<R> {
+    pub fn new(reader: R, max_frame: usize) -> FrameReader<R> {
+        FrameReader { reader, builder: LineBuilder::new(max_frame) }
+    }
+
+    // BLOCKING. The next line, or None at EOF. Like `lines()`, invalid UTF-8 is an error.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        loop {
+            let (taken, complete) = {
+                let chunk = self.reader.fill_buf()?;
+                if chunk.is_empty() {
+                    // A last line without a newline still counts
+                    return match self.builder.is_empty() {
+                        true => Ok(None),
+                        false => self.builder.take().map(Some),
+                    };
+                }
+                self.builder.push(chunk)
+            };
+            self.reader.consume(taken);
+            if complete {
+                return self.builder.take().map(Some);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for FrameReader<R> {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+// A FrameReader for the async server
+#[cfg(feature = "async")]
+pub struct AsyncFrameReader<R> {
+    reader: R,
+    builder: LineBuilder,
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncBufRead + Unpin> AsyncFrameReader<R> {
+    pub fn new(reader: R, max_frame: usize) -> AsyncFrameReader<R> {
+        AsyncFrameReader { reader, builder: LineBuilder::new(max_frame) }
+    }
+
+    // The next line, or None at EOF (see `FrameReader::next_frame`)
+    pub async fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        loop {
+            let (taken, complete) = {
+                let chunk = self.reader.fill_buf().await?;
+                if chunk.is_empty() {
+                    return match self.builder.is_empty() {
+                        true => Ok(None),
+                        false => self.builder.take().map(Some),
+                    };
+                }
+                self.builder.push(chunk)
+            };
+            self.reader.consume(taken);
+            if complete {
+                return self.builder.take().map(Some);
+            }
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use crate::request::entry::LogEntry;
+
+/**
+ * Chat log storage
+ * ----------------
+ * Every room keeps its log entries in a ChatStore. Entries are addressed by their absolute
+ * position in the room (the first entry ever appended is 0), even if a store has since forgotten
+ * old entries. Entry ids increase with their position.
+ *
+ * * `Vec<LogEntry>`: everything in memory.
+ * * `FileLog`: an append-only file per room (`<log dir>/<room>.log`), one JSON entry per line.
+ *   The whole file is read back into memory when it is opened, so reads are served from memory
+ *   and only appends touch the disk.
+ * * `RingBufferStore`: only the most recent N entries, in memory.
+ */
+pub trait ChatStore: Send + 'static {
+    fn append(&mut self, entry: LogEntry) -> Result<(), Error>;
+
+    // Entries in [from, to). Entries the store no longer has are skipped.
+    fn range(&self, from: usize, to: usize) -> Vec<LogEntry>;
+
+    // Total number of entries ever appended
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The most recently appended entry
+    fn last(&self) -> Option<LogEntry> {
+        self.range(self.len().saturating_sub(1), self.len()).pop()
+    }
+
+    // Position of the first entry with an id greater than `id`
+    fn position_after(&self, id: u64) -> usize {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.range(mid, mid + 1).first() {
+                Some(entry) if entry.id > id => { high = mid; },
+                // Forgotten entries are older than anything we still have
+                _ => { low = mid + 1; },
+            }
+        }
+        low
+    }
+
+    // Everything appended since `cursor`, moving the cursor to the end of the log
+    fn follow(&self, cursor: &mut usize) -> Vec<LogEntry> {
+        let end_at = self.len();
+        let entries = self.range(*cursor, end_at);
+        *cursor = end_at;
+        entries
+    }
+
+    // Make sure everything appended so far is durable
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ChatStore for Vec<LogEntry> {
+    fn append(&mut self, entry: LogEntry) -> Result<(), Error> {
+        self.push(entry);
+        Ok(())
+    }
+
+    fn range(&self, from: usize, to: usize) -> Vec<LogEntry> {
+        let to = to.min(self.len());
+        match from < to {
+            true => self[from..to].to_vec(),
+            false => vec![],
+        }
+    }
+
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+}
+
+const LOG_FILE_EXTENSION: &str = "log";
+
+// When to fsync appended entries to disk
+#[derive(Debug, Clone, Copy)]
+pub enum SyncPolicy {
+    // fsync after every appended entry
+    Always,
+    // fsync after every N appended entries
+    EveryN(usize),
+    // Leave it to the OS
+    Never,
+}
+
+impl SyncPolicy {
+    // Parses `always`, `never` or a number N (fsync every N entries)
+    pub fn parse(string: &str) -> Option<SyncPolicy> {
+        match string {
+            "always" => Some(SyncPolicy::Always),
+            "never" => Some(SyncPolicy::Never),
+            _ => match string.parse::<usize>() {
+                Ok(0) => Some(SyncPolicy::Never),
+                Ok(1) => Some(SyncPolicy::Always),
+                Ok(n) => Some(SyncPolicy::EveryN(n)),
+                _ => None,
+            },
+        }
+    }
+}
+
+pub struct FileLog {
+    file: File,
+    entries: Vec<LogEntry>,
+    policy: SyncPolicy,
+    unsynced: usize,
+}
+
+impl FileLog {
+    // Opens (or creates) a log file and loads the entries already in it
+    pub fn open(path: &Path, policy: SyncPolicy) -> Result<FileLog, Error> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+        // Anything after the last newline is a record whose append never finished (e.g. a crash
+        // mid-append). It is cut off, so the next append starts a line of its own.
+        let complete = match contents.iter().rposition(|byte| *byte == b'\n') {
+            Some(newline) => newline + 1,
+            None => 0,
+        };
+        let mut entries = vec![];
+        for record in contents[..complete].split(|byte| *byte == b'\n') {
+            if record.is_empty() {
+                continue;
+            }
+            match std::str::from_utf8(record).ok().and_then(LogEntry::from_json) {
+                Some(entry) => entries.push(entry),
+                None => { println!("skipping bad log record in {:?}: {}", path, String::from_utf8_lossy(record)); },
+            }
+        }
+        if complete < contents.len() {
+            println!("cutting off a torn record at the end of {:?}", path);
+            file.set_len(complete as u64)?;
+        }
+        Ok(FileLog { file, entries, policy, unsynced: 0 })
+    }
+
+    // fsync everything appended so far
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+impl ChatStore for FileLog {
+    fn append(&mut self, entry: LogEntry) -> Result<(), Error> {
+        self.file.write_all(format!("{}\n", entry.to_json()).as_bytes())?;
+        self.entries.push(entry);
+        self.unsynced += 1;
+        match self.policy {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::EveryN(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn range(&self, from: usize, to: usize) -> Vec<LogEntry> {
+        self.entries.range(from, to)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.sync()
+    }
+}
+
+impl Drop for FileLog {
+    fn drop(&mut self) {
+        self.sync().unwrap_or_else(|e| { println!("log sync failed: {:?}", e); });
+    }
+}
+
+// Keeps only the most recent `capacity` entries
+pub struct RingBufferStore {
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+    // Entries that have been pushed out of the buffer
+    dropped: usize,
+}
+
+impl RingBufferStore {
+    pub fn new(capacity: usize) -> RingBufferStore {
+        RingBufferStore {
+            entries: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            dropped: 0,
+        }
+    }
+}
+
+impl ChatStore for RingBufferStore {
+    fn append(&mut self, entry: LogEntry) -> Result<(), Error> {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+            self.dropped += 1;
+        }
+        self.entries.push_back(entry);
+        Ok(())
+    }
+
+    fn range(&self, from: usize, to: usize) -> Vec<LogEntry> {
+        let from = from.max(self.dropped) - self.dropped;
+        let to = to.min(self.len()).max(self.dropped) - self.dropped;
+        self.entries.iter().skip(from).take(to.saturating_sub(from)).cloned().collect()
+    }
+
+    fn len(&self) -> usize {
+        self.dropped + self.entries.len()
+    }
+}
+
+/**
+ * Entry ids
+ * ---------
+ * Ids are never reused, not even those of entries no room stores (direct messages, keys and
+ * notices): clients that resume from an id would skip whatever reuses it. An IdCounter hands them
+ * out, and with a file it reserves them there a block at a time before handing them out, so a
+ * restarted server starts past every id the last one handed out.
+ *
+ * Ids only mean something in the log that handed them out. The epoch names that log: it is kept in
+ * the file along with the reservation, and counters without a file get a new one every time, so a
+ * client that resumes from an id can tell when the server's log has started over.
+ */
+pub struct IdCounter {
+    next: u64,
+    // Ids below this are reserved in the file
+    reserved: u64,
+    file: Option<PathBuf>,
+    epoch: u64,
+}
+
+// An epoch for a log that starts now
+fn new_epoch() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_nanos() as u64).unwrap_or(0)
+}
+
+// How many ids are reserved at once
+const ID_BLOCK: u64 = 1000;
+
+impl IdCounter {
+    // Starts at `next`, forgotten on restart
+    pub fn new(next: u64) -> IdCounter {
+        IdCounter { next, reserved: u64::MAX, file: None, epoch: new_epoch() }
+    }
+
+    // Starts at `next` or past the ids reserved in `path`, whichever is later, in the epoch kept
+    // there. Files from before epochs get a new one.
+    pub fn open(path: &Path, next: u64) -> Result<IdCounter, Error> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => { return Err(e); },
+        };
+        let mut fields = contents.split_whitespace();
+        let reserved = match fields.next().map(|reserved| reserved.parse::<u64>()) {
+            Some(Ok(reserved)) => reserved,
+            Some(Err(_)) => {
+                println!("ignoring bad id reservation in {:?}", path);
+                0
+            },
+            None => 0,
+        };
+        let epoch = fields.next().and_then(|epoch| epoch.parse::<u64>().ok()).unwrap_or_else(new_epoch);
+        let next = next.max(reserved);
+        Ok(IdCounter { next, reserved: next, file: Some(path.to_path_buf()), epoch })
+    }
+
+    // Which log the ids are from
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    // The id the next `take` hands out
+    pub fn peek(&self) -> u64 {
+        self.next
+    }
+
+    // The next id. If the reservation can't be written, the id is still handed out, but a restarted
+    // server may hand it out again.
+    pub fn take(&mut self) -> u64 {
+        if self.next >= self.reserved {
+            self.reserved = self.next + ID_BLOCK;
+            self.reserve().unwrap_or_else(|e| { println!("could not reserve ids: {:?}", e); });
+        }
+        self.next += 1;
+        self.next - 1
+    }
+
+    // Write the reservation next to the file and move it over, so a crash leaves one or the other
+    fn reserve(&self) -> Result<(), Error> {
+        let path = match self.file.as_ref() {
+            Some(path) => path,
+            None => { return Ok(()); },
+        };
+        let staged = path.with_extension("tmp");
+        let mut file = File::create(&staged)?;
+        file.write_all(format!("{} {}\n", self.reserved, self.epoch).as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&staged, path)
+    }
+}
+
+// Room names may contain characters that aren't safe in file names, so everything
+// except ASCII alphanumerics, `-` and `_` is written as `%XX`.
+pub fn room_file_name(room: &str) -> String {
+    let mut name = String::new();
+    for byte in room.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!("{}.{}", name, LOG_FILE_EXTENSION)
+}
+
+fn room_from_file_name(file_name: &str) -> Option<String> {
+    let stem = file_name.strip_suffix(&format!(".{}", LOG_FILE_EXTENSION))?;
+    let mut bytes = vec![];
+    let mut chars = stem.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'%' => {
+                let hex = [chars.next()?, chars.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            },
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Path of the log file for a room
+pub fn room_log_path(log_dir: &Path, room: &str) -> PathBuf {
+    log_dir.join(room_file_name(room))
+}
+
+// Opens every room log in a directory (creating the directory if needed)
+pub fn open_log_dir(log_dir: &Path, policy: SyncPolicy) -> Result<HashMap<String, FileLog>, Error> {
+    fs::create_dir_all(log_dir)?;
+    let mut logs = HashMap::new();
+    for entry in fs::read_dir(log_dir)? {
+        let entry = entry?;
+        let room = match entry.file_name().to_str().and_then(room_from_file_name) {
+            Some(room) => room,
+            None => { continue; },
+        };
+        logs.insert(room, FileLog::open(&entry.path(), policy)?);
+    }
+    Ok(logs)
+}
@@ -1,2 +1,15 @@
 pub mod server;
-pub mod chatlog;
\ No newline at end of file
+pub mod chatlog;
+pub mod logstore;
+pub mod registry;
+pub mod auth;
+pub mod transport;
+pub mod e2e;
+pub mod shutdown;
+pub mod reconnect;
+pub mod ratelimit;
+pub mod framing;
+pub mod session;
+pub mod subscribers;
+#[cfg(feature = "async")]
+pub mod async_server;
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+// Past this many subjects, `RateLimiter::check` forgets the ones that have calmed down
+const PRUNE_THRESHOLD: usize = 1024;
+
+/**
+ * Flood protection
+ * ----------------
+ * Every subject gets a token bucket: it holds up to `burst` requests and refills at `per_second`.
+ * A request takes a token; without one it is throttled (dropped, and answered with a
+ * `rate-limited` error if the client takes acks). Each throttled request is a strike, and a
+ * subject that collects `mute_after` strikes before its bucket is full again is muted: every
+ * request is refused until `mute_for` has passed.
+ *
+ * Sessions use their subject's bucket. Connections on the client port use the bucket of the first
+ * subject they spoke as for as long as they are open, so they can't get a fresh one by moving on
+ * to another subject.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+    // Strikes before a mute. Never muted if None.
+    pub mute_after: Option<u32>,
+    pub mute_for: Duration,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, per_second: f64) -> RateLimit {
+        RateLimit {
+            burst,
+            per_second,
+            mute_after: None,
+            mute_for: Duration::ZERO,
+        }
+    }
+
+    // Mute subjects for `mute_for` once they are throttled `strikes` times before their bucket fills up again
+    pub fn with_mute(mut self, strikes: u32, mute_for: Duration) -> RateLimit {
+        self.mute_after = Some(strikes);
+        self.mute_for = mute_for;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Allowed,
+    Throttled,
+    // Refused for this much longer
+    Muted(Duration),
+}
+
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+    strikes: u32,
+    muted_until: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            last: now,
+            strikes: 0,
+            muted_until: None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.last = now;
+        // Behaving long enough to fill the bucket wipes the slate
+        if self.tokens >= self.limit.burst as f64 {
+            self.strikes = 0;
+        }
+    }
+
+    // Take a token for a request made at `now`
+    pub fn check(&mut self, now: Instant) -> Verdict {
+        match self.muted_until {
+            Some(until) if until > now => { return Verdict::Muted(until - now); },
+            Some(_) => {
+                self.muted_until = None;
+                self.tokens = self.limit.burst as f64;
+                self.strikes = 0;
+                self.last = now;
+            },
+            None => {},
+        }
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Verdict::Allowed;
+        }
+        self.strikes += 1;
+        match self.limit.mute_after {
+            Some(strikes) if self.strikes >= strikes => {
+                self.muted_until = Some(now + self.limit.mute_for);
+                Verdict::Muted(self.limit.mute_for)
+            },
+            _ => Verdict::Throttled,
+        }
+    }
+
+    pub fn is_muted(&self, now: Instant) -> bool {
+        self.muted_until.is_some_and(|until| until > now)
+    }
+
+    // Full and not muted: it would behave the same if it were made afresh
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        !self.is_muted(now) && self.tokens >= self.limit.burst as f64
+    }
+}
+
+/**
+ * The buckets of every subject, shared by the connections (and servers) that should count
+ * together. Buckets are kept by subject rather than by connection, so reconnecting doesn't lift
+ * a mute.
+ */
+#[derive(Clone)]
+pub struct RateLimiter {
+    pub limit: RateLimit,
+    buckets: Arc<Mutex<HashMap<String, TokenBucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter {
+            limit,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, TokenBucket>> {
+        match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Take a token for a request from `subject`
+    pub fn check(&self, subject: &str) -> Verdict {
+        let now = Instant::now();
+        let mut buckets = self.lock();
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| !bucket.is_idle(now));
+        }
+        let limit = self.limit;
+        let bucket = buckets
+            .entry(subject.to_lowercase())
+            .or_insert_with(|| TokenBucket::new(limit, now));
+        let was_muted = bucket.is_muted(now);
+        let verdict = bucket.check(now);
+        if let (false, Verdict::Muted(duration)) = (was_muted, verdict) {
+            println!("muting {} for {:?}", subject, duration);
+        }
+        verdict
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{Error, ErrorKind, Read, Write},
+    net::Shutdown,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use rustls::ClientConfig;
+use crate::peer::{shutdown::ShutdownSignal, transport::{Stream, connect}};
+use crate::request::{
+    entry::LogEntry,
+    request::{ChatRequest, ChatRequestStatus, ChatRequestVerb, Backfill, MAX_REQUEST_LENGTH},
+    response::{ChatResponse, ErrorCode},
+};
+
+// How long to wait for the server to answer INIT. Servers from before the handshake never do.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+// How long the feed of a server that answers PINGs may stay silent (not even a pong) before the
+// connection is taken for dead
+pub const FEED_SILENCE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/**
+ * Reconnecting clients
+ * --------------------
+ * A client that reconnects needs every thread that writes to the server to switch to the new
+ * connection. `SharedConnection` is the one place they all write to; the thread that reads the
+ * feed notices the disconnect, swaps in the new Stream with `replace`, and resumes the feed from
+ * the last message it saw (`backfill=since:<id>` on the new INIT, see `Resume`), and joins the
+ * rooms the client was in again (see `Rooms`).
+ *
+ * While there is no connection, writes fail with `ErrorKind::NotConnected`.
+ */
+#[derive(Clone)]
+pub struct SharedConnection(Arc<Mutex<Option<Stream>>>);
+
+impl SharedConnection {
+    pub fn new(stream: Stream) -> SharedConnection {
+        SharedConnection(Arc::new(Mutex::new(Some(stream))))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Stream>> {
+        match self.0.lock() {
+            Ok(stream) => stream,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn replace(&self, stream: Stream) {
+        *self.lock() = Some(stream);
+    }
+
+    // Drop the connection (if it isn't gone already). Writes fail until the next `replace`.
+    pub fn disconnect(&self) {
+        if let Some(stream) = self.lock().take() {
+            stream.shutdown(Shutdown::Both).unwrap_or(());
+        }
+    }
+}
+
+impl Write for SharedConnection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self.lock().as_mut() {
+            Some(stream) => stream.write(buf),
+            None => Err(Error::new(ErrorKind::NotConnected, "reconnecting")),
+        }
+    }
+
+    // Whole requests go out under one lock, so they never interleave with another thread's
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        match self.lock().as_mut() {
+            Some(stream) => stream.write_all(buf),
+            None => Err(Error::new(ErrorKind::NotConnected, "reconnecting")),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.lock().as_mut() {
+            Some(stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/**
+ * Exponential backoff between reconnect attempts: `first`, then twice as long each time, up to `max`
+ */
+pub struct Backoff {
+    first: Duration,
+    max: Duration,
+    next: Duration,
+    pub attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(first: Duration, max: Duration) -> Backoff {
+        Backoff { first, max, next: first, attempt: 0 }
+    }
+
+    // How long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        self.attempt += 1;
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.first;
+        self.attempt = 0;
+    }
+}
+
+// Read one line without buffering past it, so the rest of the stream can be handed to a BufReader
+pub fn read_line_unbuffered(stream: &mut Stream) -> Result<String, Error> {
+    let mut line = vec![];
+    let mut byte = [0u8; 1];
+    loop {
+        match stream.read(&mut byte)? {
+            0 if line.is_empty() => { return Err(Error::from(ErrorKind::UnexpectedEof)); },
+            0 => break,
+            _ if byte[0] == b'\n' => break,
+            _ => line.push(byte[0]),
+        }
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+// What the server's welcome told us
+#[derive(Clone, Copy)]
+pub struct Granted {
+    // Whether the server answers PINGs
+    pub pings: bool,
+    // The longest request line it takes
+    pub max_frame: usize,
+    // Which log the feed's ids are from
+    pub epoch: Option<u64>,
+}
+
+impl Granted {
+    // What servers from before the handshake do
+    pub fn legacy() -> Granted {
+        Granted { pings: false, max_frame: MAX_REQUEST_LENGTH, epoch: None }
+    }
+}
+
+// What the server made of our INIT
+pub enum Handshake {
+    // Welcomed, under this name
+    Welcome(Stream, String, Granted),
+    Rejected(String),
+    // Someone holds the name, e.g. our own session from before the connection dropped, until the
+    // server notices it is gone
+    NameTaken(String),
+    // A server from before the handshake, and the first feed line if it sent one already
+    Legacy(Stream, Option<String>),
+}
+
+// Connect to `socket` and open a session with `init`
+pub fn handshake(socket: &str, tls: &Option<Arc<ClientConfig>>, init: &ChatRequest) -> Result<Handshake, Error> {
+    let mut stream = connect(socket, tls)?;
+    stream.write_all(init.to_string_opt().unwrap().as_bytes())?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let first_line = read_line_unbuffered(&mut stream);
+    stream.set_read_timeout(None)?;
+    match first_line {
+        Ok(line) => match ChatResponse::from_json(&line) {
+            Some(ChatResponse::Welcome { subject, caps, max_frame, epoch, .. }) => {
+                // We PING it, so silence means the connection is gone
+                let pings = caps.iter().any(|cap| cap == "ping");
+                if pings {
+                    stream.set_read_timeout(Some(FEED_SILENCE_TIMEOUT))?;
+                }
+                Ok(Handshake::Welcome(stream, subject, Granted { pings, max_frame, epoch }))
+            },
+            Some(ChatResponse::Rejected { reason, code: Some(ErrorCode::NameTaken), .. }) => Ok(Handshake::NameTaken(reason)),
+            Some(ChatResponse::Rejected { reason, .. }) => Ok(Handshake::Rejected(reason)),
+            _ => Ok(Handshake::Legacy(stream, Some(line))),
+        },
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Ok(Handshake::Legacy(stream, None))
+        },
+        Err(e) => Err(e),
+    }
+}
+
+// How a reconnect went
+pub enum Reconnected {
+    // Back in, with the first feed line if the server skipped the handshake
+    Resumed(Stream, Option<String>, Granted),
+    // The server turned the INIT down (e.g. the password changed). It would again, so there's no
+    // point retrying. A taken name is retried instead: it is most likely our old session.
+    Rejected(String),
+    // The user quit while we were waiting
+    Quit,
+}
+
+/**
+ * Where to open the session again after losing the connection
+ */
+pub struct Reconnect {
+    socket: String,
+    tls: Option<Arc<ClientConfig>>,
+    first_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Reconnect {
+    pub fn new(socket: String, tls: Option<Arc<ClientConfig>>) -> Reconnect {
+        Reconnect { socket, tls, first_delay: Duration::from_millis(500), max_delay: Duration::from_secs(30) }
+    }
+
+    // Wait `first` before the first attempt, and twice as long after every failed one, up to `max`
+    pub fn with_backoff(mut self, first: Duration, max: Duration) -> Self {
+        self.first_delay = first;
+        self.max_delay = max;
+        self
+    }
+
+    // Retry with exponential backoff until the server takes us back with `init` or turns it down
+    // (BLOCKING). `waiting` hears about every attempt before we wait for it.
+    pub fn run(&self, init: &ChatRequest, quit: &ShutdownSignal, mut waiting: impl FnMut(u32, Duration)) -> Reconnected {
+        let mut backoff = Backoff::new(self.first_delay, self.max_delay);
+        loop {
+            let delay = backoff.next_delay();
+            waiting(backoff.attempt, delay);
+            if quit.wait_timeout(delay) {
+                return Reconnected::Quit;
+            }
+            match handshake(&self.socket, &self.tls, init) {
+                Ok(Handshake::Welcome(stream, _, granted)) => { return Reconnected::Resumed(stream, None, granted); },
+                Ok(Handshake::Legacy(stream, first_line)) => { return Reconnected::Resumed(stream, first_line, Granted::legacy()); },
+                Ok(Handshake::Rejected(reason)) => { return Reconnected::Rejected(reason); },
+                // The server hasn't let go of our old session yet, or is still down
+                Ok(Handshake::NameTaken(_)) | Err(_) => {},
+            }
+        }
+    }
+}
+
+/**
+ * What the feed has shown so far, so a resumed feed neither repeats nor skips anything. Entry ids
+ * only mean something in the log that handed them out (see `ChatResponse::Welcome`), so when a
+ * welcome names another log, everything seen in the old one is forgotten.
+ *
+ * A room's entries reach the feed in id order, and so do the direct messages, so the newest id of
+ * each is all there is to remember. A room joined later starts with older entries than the rest.
+ */
+#[derive(Default)]
+pub struct Resume {
+    // Room (None: direct messages and notices) -> the newest id seen in it
+    newest: HashMap<Option<String>, u64>,
+    // Whether a notice from outside the log (id 0, e.g. the motd) has been shown
+    greeted: bool,
+    last_id: Option<u64>,
+    // None until the first welcome, and with servers from before epochs
+    epoch: Option<u64>,
+}
+
+impl Resume {
+    // A server welcomed us with `granted`
+    pub fn welcomed(&mut self, granted: &Granted) {
+        if granted.epoch != self.epoch {
+            self.newest.clear();
+            self.greeted = false;
+            self.last_id = None;
+            self.epoch = granted.epoch;
+        }
+    }
+
+    // Whether `entry` is new to the feed. It isn't, the next time.
+    pub fn saw(&mut self, entry: &LogEntry) -> bool {
+        if entry.id == 0 {
+            return !std::mem::replace(&mut self.greeted, true);
+        }
+        match self.newest.get(&entry.room) {
+            Some(&newest) if entry.id <= newest => { return false; },
+            _ => { self.newest.insert(entry.room.clone(), entry.id); },
+        }
+        self.last_id = Some(self.last_id.unwrap_or(0).max(entry.id));
+        true
+    }
+
+    // What to backfill on reconnect: everything after the last entry we saw, or the last `history`
+    // entries per room if we haven't seen any
+    pub fn backfill(&self, history: usize) -> Backfill {
+        match self.last_id {
+            Some(id) => Backfill::Since(id),
+            None => Backfill::Last(history),
+        }
+    }
+
+    // The log the backfill counts in, for the INIT's `epoch` option
+    pub fn epoch(&self) -> Option<u64> {
+        self.epoch
+    }
+}
+
+/**
+ * The rooms a client has joined. The server forgets them when the connection ends, so they are
+ * joined again after a reconnect. They're tracked from the JOINs and PARTs the client sends: the
+ * feed doesn't carry a client's own PARTs back to it.
+ */
+#[derive(Clone, Default)]
+pub struct Rooms(Arc<Mutex<BTreeSet<String>>>);
+
+impl Rooms {
+    fn lock(&self) -> MutexGuard<'_, BTreeSet<String>> {
+        match self.0.lock() {
+            Ok(rooms) => rooms,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // `request` went out to the server
+    pub fn sent(&self, request: &ChatRequest) {
+        match (request.verb, request.room()) {
+            (ChatRequestVerb::JOIN, Some(room)) => { self.lock().insert(room); },
+            (ChatRequestVerb::PART, Some(room)) => { self.lock().remove(&room); },
+            _ => {},
+        }
+    }
+
+    // The JOINs that put `name` back in every room
+    pub fn rejoin(&self, name: &str) -> Vec<ChatRequest> {
+        self.lock()
+            .iter()
+            .map(|room| ChatRequest {
+                subject: Some(name.to_string()),
+                verb: ChatRequestVerb::JOIN,
+                object: Some(room.clone()),
+                status: ChatRequestStatus::Valid,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, atomic::{AtomicU64, Ordering}},
+};
+
+/**
+ * Subjects that currently have a session open. A name can only be held by one session at a time,
+ * and names that differ only in case count as the same name, so nobody can pass as someone else.
+ *
+ * Every claim gets an id of its own, so whatever belongs to one session (e.g. its direct messages,
+ * see `peer::chatlog`) can't be picked up by the next one to use the name. A claim can be taken
+ * over (see `take_over`): its holder is told through the hook it set with `Claim::on_replaced`.
+ * Connections on the requests port `pin` their name instead: they read their feed from the feed
+ * port, so their claim keeps nothing of its own.
+ */
+#[derive(Clone, Default)]
+pub struct NameRegistry {
+    active: Arc<Mutex<HashMap<String, Holder>>>,
+    // Claim ids start at 1
+    last_id: Arc<AtomicU64>,
+}
+
+// Called once when the claim is taken over
+type ReplacedHook = Box<dyn FnOnce() + Send>;
+
+// Whoever holds a name
+struct Holder {
+    id: u64,
+    // Whether it is a session (see `peer::session`) rather than a pinned connection
+    session: bool,
+    replaced: Option<ReplacedHook>,
+}
+
+// A name held by a session. The name is released when the claim is dropped.
+pub struct Claim {
+    registry: NameRegistry,
+    name: String,
+    id: u64,
+}
+
+impl Claim {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Whether someone else has taken the name over since it was claimed
+    pub fn is_replaced(&self) -> bool {
+        self.registry.holder(&self.name) != Some(self.id)
+    }
+
+    // Run `hook` when the claim is taken over, e.g. to hang up on the old session. Runs it right
+    // away if that has happened already.
+    pub fn on_replaced(&self, hook: impl FnOnce() + Send + 'static) {
+        let mut active = self.registry.lock();
+        match active.get_mut(&self.name.to_lowercase()) {
+            Some(holder) if holder.id == self.id => { holder.replaced = Some(Box::new(hook)); },
+            _ => {
+                drop(active);
+                hook();
+            },
+        }
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        let mut active = self.registry.lock();
+        let key = self.name.to_lowercase();
+        // The name may have been taken over already
+        if active.get(&key).is_some_and(|holder| holder.id == self.id) {
+            active.remove(&key);
+        }
+    }
+}
+
+impl NameRegistry {
+    pub fn new() -> NameRegistry {
+        NameRegistry::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Holder>> {
+        match self.active.lock() {
+            Ok(active) => active,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Hold `name` in `active` with a new claim
+    fn insert(&self, active: &mut HashMap<String, Holder>, name: String, session: bool) -> (Claim, Option<Holder>) {
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let replaced = active.insert(name.to_lowercase(), Holder { id, session, replaced: None });
+        (Claim { registry: self.clone(), name, id }, replaced)
+    }
+
+    pub fn is_active(&self, name: &str) -> bool {
+        self.lock().contains_key(&name.to_lowercase())
+    }
+
+    // The id of the claim holding `name`, if anyone holds it
+    pub fn holder(&self, name: &str) -> Option<u64> {
+        self.lock().get(&name.to_lowercase()).map(|holder| holder.id)
+    }
+
+    // The id of the session holding `name`, if a session holds it
+    pub fn session(&self, name: &str) -> Option<u64> {
+        self.lock().get(&name.to_lowercase()).filter(|holder| holder.session).map(|holder| holder.id)
+    }
+
+    // Claim `name` for a session. Returns None if someone else holds it.
+    pub fn claim(&self, name: &str) -> Option<Claim> {
+        self.claim_free(name, true)
+    }
+
+    // Claim `name` for a connection on the requests port (see `server::pin`). Returns None if
+    // someone else holds it.
+    pub fn pin(&self, name: &str) -> Option<Claim> {
+        self.claim_free(name, false)
+    }
+
+    fn claim_free(&self, name: &str, session: bool) -> Option<Claim> {
+        let mut active = self.lock();
+        if active.contains_key(&name.to_lowercase()) {
+            return None;
+        }
+        Some(self.insert(&mut active, name.to_string(), session).0)
+    }
+
+    // Claim `name` for a session, or the first free one of `name2`, `name3`, ... if it is taken
+    pub fn claim_with_suffix(&self, name: &str) -> Claim {
+        let mut active = self.lock();
+        let mut candidate = name.to_string();
+        let mut suffix = 1;
+        while active.contains_key(&candidate.to_lowercase()) {
+            suffix += 1;
+            candidate = format!("{}{}", name, suffix);
+        }
+        self.insert(&mut active, candidate, true).0
+    }
+
+    // Claim `name` for a session even if someone holds it. The old holder's `on_replaced` hook
+    // runs, and its claim no longer frees the name when dropped. Only for subjects that proved who
+    // they are.
+    pub fn take_over(&self, name: &str) -> Claim {
+        let (claim, replaced) = self.insert(&mut self.lock(), name.to_string(), true);
+        // Outside of the lock: the hook may take it
+        if let Some(hook) = replaced.and_then(|holder| holder.replaced) {
+            hook();
+        }
+        claim
+    }
+}
@@ -1,45 +1,363 @@
 
 use std::{
-    sync::mpsc::Sender,
-    io::{BufReader, BufRead, Error},
-    net::{TcpListener, TcpStream, Shutdown},
+    sync::{Arc, Mutex, mpsc::Sender},
+    io::{BufReader, Error, ErrorKind, Write},
+    net::{TcpListener, Shutdown},
     result::Result,
+    thread,
+    time::Duration,
 };
 
-use crate::threadpool::threadpool::Threadpool;
-use crate::request::request::{ChatRequest, ChatRequestStatus};
+use crate::threadpool::threadpool::{Overflow, Threadpool};
+use crate::request::request::{ChatRequest, ChatRequestStatus, ChatRequestVerb, MAX_REQUEST_LENGTH};
+use crate::request::response::{ChatResponse, ErrorCode};
+use crate::peer::chatlog::{ChatLogHandle, FeedCursor, Submission, write_feed};
+use crate::peer::logstore::ChatStore;
+use crate::peer::registry::{Claim, NameRegistry};
+use crate::peer::session::{Action, Opening, Session, answer};
+use crate::peer::auth::{Credentials, authenticate};
+use crate::peer::transport::{Stream, accept_tls};
+use crate::peer::shutdown::ShutdownSignal;
+use crate::peer::ratelimit::{RateLimiter, Verdict};
+use crate::peer::framing::{Frame, FrameReader};
+use rustls::ServerConfig;
 
 pub struct Server {
     socket: String,
+    // Who may connect. Anyone may if there are none.
+    credentials: Option<Arc<Credentials>>,
+    // Connections are plain TCP without one
+    tls: Option<Arc<ServerConfig>>,
+    // What to do with new connections while every executor is busy
+    overflow: Overflow,
+    // Stops the accept loop and tracks connections, so the server can be shut down
+    shutdown: Option<ShutdownSignal>,
+    // What every connection is held to
+    limits: Limits,
     // log_path: String
 }
 
-// BLOCKING
-fn handle_connection(mut stream: TcpStream, tx: Sender<ChatRequest>) {
-    let buf_reader = BufReader::new(&mut stream);
-    let mut body = buf_reader
-        .lines()            
-        .map(|item| match item {
-            Ok(it) => it,
-            _ => String::from("")
-        });
-    while let Some(msg) = body.next() {
-        let message = msg.clone();
-        let request = ChatRequest::from(message);
-        match request.status {
-            ChatRequestStatus::Valid => {
-                match tx.send(request) {
-                    Err(_) => { break; },
-                    _ => {}
+// What a connection may do before the server refuses its requests or hangs up on it
+#[derive(Clone)]
+pub(crate) struct Limits {
+    // Hang up on connections that go quiet for this long (see `Server::with_idle_timeout`)
+    pub idle_timeout: Option<Duration>,
+    // Throttles and mutes subjects that send too much (see `peer::ratelimit`)
+    pub rate_limiter: Option<RateLimiter>,
+    // Longest request line accepted (see `Server::with_max_frame`)
+    pub max_frame: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            idle_timeout: None,
+            rate_limiter: None,
+            max_frame: MAX_REQUEST_LENGTH,
+        }
+    }
+}
+
+// A Stream shared by the threads writing to one connection. Each `write_all` goes out in one
+// piece, so response frames and feed lines never interleave.
+#[derive(Clone)]
+pub struct SharedStream(Arc<Mutex<Stream>>);
+
+impl SharedStream {
+    pub fn new(stream: Stream) -> SharedStream {
+        SharedStream(Arc::new(Mutex::new(stream)))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Stream> {
+        match self.0.lock() {
+            Ok(stream) => stream,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Write one response frame
+    pub fn respond(&mut self, response: &ChatResponse) -> Result<(), Error> {
+        self.write_all(format!("{}\n", response.to_json()).as_bytes())
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.lock().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.lock().write_all(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.lock().flush()
+    }
+}
+
+// Check a line read from a client and decode it
+pub(crate) fn decode(frame: Frame) -> Result<ChatRequest, ErrorCode> {
+    let request = match frame {
+        Frame::Line(line) => ChatRequest::from(line),
+        Frame::TooLong => { return Err(ErrorCode::TooLong); },
+    };
+    match (request.status, request.verb) {
+        (ChatRequestStatus::Invalid, _) => Err(ErrorCode::Malformed),
+        (ChatRequestStatus::Valid, ChatRequestVerb::NONE | ChatRequestVerb::NOTICE) => Err(ErrorCode::UnknownVerb),
+        (ChatRequestStatus::Valid, _) => Ok(request),
+    }
+}
+
+pub(crate) fn describe(code: ErrorCode) -> String {
+    let message = match code {
+        ErrorCode::Malformed => "malformed request",
+        ErrorCode::TooLong => "request is too long",
+        ErrorCode::UnknownVerb => "unknown verb",
+        ErrorCode::RateLimited => "slow down",
+        ErrorCode::Unauthorized => "log in with INIT first",
+        ErrorCode::NameTaken => "that name is taken",
+        ErrorCode::NotAMember => "you are not in that room",
+        ErrorCode::NotConnected => "they are not connected",
+        ErrorCode::NoKey => "they have not published a key",
+        ErrorCode::Internal => "the server could not store the request",
+    };
+    String::from(message)
+}
+
+// Take a token for `request` from `bucket`'s bucket if the server limits rates. The bucket is the
+// connection's, never whatever subject the request claims. ENDs always go through, so anyone can
+// leave.
+pub(crate) fn throttle(rate_limiter: &Option<RateLimiter>, bucket: &str, request: &ChatRequest) -> Verdict {
+    match (rate_limiter, request.verb) {
+        (Some(_), ChatRequestVerb::END | ChatRequestVerb::PING) => Verdict::Allowed,
+        (Some(rate_limiter), _) => rate_limiter.check(bucket),
+        (None, _) => Verdict::Allowed,
+    }
+}
+
+// The error frame for a request that `throttle` refused
+pub(crate) fn rate_limited(verdict: Verdict) -> ChatResponse {
+    let message = match verdict {
+        Verdict::Muted(remaining) => format!("you are muted for {}s", remaining.as_secs().max(1)),
+        _ => describe(ErrorCode::RateLimited),
+    };
+    ChatResponse::Error { code: ErrorCode::RateLimited, message }
+}
+
+// The END for a client that went away without sending one, so everyone else still sees it leave
+pub(crate) fn end_request(subject: String) -> ChatRequest {
+    ChatRequest {
+        subject: Some(subject),
+        verb: ChatRequestVerb::END,
+        object: Some(String::new()),
+        status: ChatRequestStatus::Valid
+    }
+}
+
+// A connection arrives with the idle timeout set on its socket (see `Server::with_idle_timeout`).
+// It stays set for connections that heartbeat, and is lifted for those that don't, so idle
+// clients from before PING aren't dropped.
+fn keep_idle_timeout(stream: &Stream, heartbeats: bool, idle_timeout: Option<Duration>) -> Result<(), Error> {
+    stream.set_read_timeout(match heartbeats {
+        true => idle_timeout,
+        false => None,
+    })
+}
+
+// Check `request` against the name the connection speaks as, claiming its subject's name in
+// `names` if it has none yet. With credentials, only an INIT that authenticates its subject can
+// claim the name.
+fn pin(mut request: ChatRequest, pinned: &mut Option<Claim>, names: &NameRegistry, credentials: &Option<Arc<Credentials>>) -> Result<ChatRequest, ErrorCode> {
+    let subject = match request.subject.clone() {
+        Some(subject) if !subject.is_empty() => subject,
+        _ => { return Err(ErrorCode::Malformed); },
+    };
+    match pinned {
+        // Names differing only in case are the same name (see `peer::registry`)
+        Some(claim) if claim.name().to_lowercase() == subject.to_lowercase() => {
+            request.subject = Some(claim.name().to_string());
+            return Ok(request);
+        },
+        Some(_) => { return Err(ErrorCode::Unauthorized); },
+        None => {},
+    }
+    let is_init = matches!(request.verb, ChatRequestVerb::INIT);
+    if credentials.is_some() && !(is_init && authenticate(&mut request, credentials)) {
+        return Err(ErrorCode::Unauthorized);
+    }
+    match names.pin(&subject) {
+        Some(claim) => {
+            *pinned = Some(claim);
+            Ok(request)
+        },
+        None => Err(ErrorCode::NameTaken),
+    }
+}
+
+// BLOCKING. The first request pins the connection to its subject, whose name is claimed until the
+// subject ENDs or the connection closes, so nobody else (on this port or in a session) can speak
+// as it meanwhile. Later requests for anyone else are refused. If the server has credentials, the
+// connection must start with an INIT that authenticates its subject.
+fn handle_connection(mut stream: Stream, tx: Sender<Submission>, names: NameRegistry, credentials: Option<Arc<Credentials>>, limits: Limits) {
+    let reader = match stream.try_clone() {
+        Ok(reader) => reader,
+        _ => { return; },
+    };
+    // A read error (e.g. the idle timeout) ends the connection like EOF does
+    let body = FrameReader::new(BufReader::new(reader), limits.max_frame)
+        .map_while(|item| item.ok());
+    // Lifted after the first request, unless the client PINGs (see `keep_idle_timeout`)
+    let mut heartbeats = false;
+    // The name this connection speaks as (see `pin`)
+    let mut pinned: Option<Claim> = None;
+    // The first name it spoke as. It keeps that subject's bucket when it moves on to another.
+    let mut bucket: Option<String> = None;
+    // Whoever this connection INITed as and hasn't ENDed yet
+    let mut joined: Option<String> = None;
+    for frame in body {
+        let msg = match &frame {
+            Frame::Line(line) => line.clone(),
+            Frame::TooLong => String::new(),
+        };
+        match decode(frame).and_then(|request| pin(request, &mut pinned, &names, &credentials)) {
+            // Nobody reads this connection, so there is nothing to answer
+            Ok(request) if request.verb == ChatRequestVerb::PING => {
+                heartbeats = true;
+                keep_idle_timeout(&stream, heartbeats, limits.idle_timeout).unwrap_or(());
+            },
+            Ok(request) => {
+                let bucket = bucket.get_or_insert_with(|| request.subject.clone().unwrap_or_default());
+                // Nobody reads this connection, so a throttled request is just dropped
+                if throttle(&limits.rate_limiter, bucket, &request) != Verdict::Allowed {
+                    continue;
+                }
+                if !heartbeats {
+                    keep_idle_timeout(&stream, heartbeats, limits.idle_timeout).unwrap_or(());
+                }
+                let is_end = matches!(request.verb, ChatRequestVerb::END);
+                match request.verb {
+                    ChatRequestVerb::INIT => { joined = request.subject.clone(); },
+                    ChatRequestVerb::END => { joined = None; },
+                    _ => {},
+                }
+                if tx.send(request.into()).is_err() {
+                    break;
+                }
+                // The name is free again once its subject has left
+                if is_end {
+                    pinned = None;
                 }
             },
-            ChatRequestStatus::Invalid => {
-                println!("error: {:?}", request);
-                stream.shutdown(Shutdown::Both);
+            Err(code) => {
+                println!("error: {} {:?}", code, ChatRequest::from(msg).subject);
+                let message = match (code, pinned.as_ref()) {
+                    (ErrorCode::Unauthorized, Some(claim)) => format!("this connection speaks as {}", claim.name()),
+                    _ => describe(code),
+                };
+                // Nobody reads this connection as a rule, but tell whoever might before hanging up
+                let response = ChatResponse::Error { code, message };
+                stream.write_all(format!("{}\n", response.to_json()).as_bytes()).unwrap_or(());
+                stream.shutdown(Shutdown::Both).unwrap_or(());
+                break;
+            }
+        }
+    }
+    if let Some(subject) = joined {
+        tx.send(end_request(subject).into()).unwrap_or(());
+    }
+}
+
+// BLOCKING. A session (see `peer::session`) on its own thread.
+fn handle_session<S: ChatStore>(stream: Stream, tx: Sender<Submission>, log: ChatLogHandle<S>, credentials: Option<Arc<Credentials>>, limits: Limits) -> Result<(), Error> {
+    let mut lines = FrameReader::new(BufReader::new(stream.try_clone()?), limits.max_frame);
+    let init = match lines.next() {
+        Some(Ok(Frame::Line(line))) => ChatRequest::from(line),
+        _ => { return Ok(()); },
+    };
+    let mut writer = SharedStream::new(stream.try_clone()?);
+    let (mut session, init, handshake) = match Session::open(init, &log, &credentials, &limits) {
+        Opening::Admitted { session, init, handshake } => (session, init, handshake),
+        Opening::Refused(response) => {
+            if let Some(response) = response {
+                writer.respond(&response)?;
+            }
+            stream.shutdown(Shutdown::Both)?;
+            return Ok(());
+        },
+    };
+    if let Some(response) = handshake {
+        writer.respond(&response)?;
+    }
+    stream.set_read_timeout(session.idle_timeout(&limits))?;
+    // Another session took the name over: hang up on this one and wake its feed so it sees it
+    let replaced = stream.try_clone()?;
+    let notifier = log.notifier.clone();
+    session.on_replaced(move || {
+        replaced.tcp().shutdown(Shutdown::Both).unwrap_or(());
+        notifier.notify();
+    });
+    let cursor = FeedCursor::new(&session.subject, log.backfill_for(&init), &log).with_claim(session.claim_id());
+    if tx.send(init.into()).is_err() {
+        return Ok(());
+    }
+
+    // Push the feed back to the client on its own thread. A client that stops reading it is
+    // disconnected (see `peer::subscribers`), and so are its responses.
+    stream.set_write_timeout(log.subscribers.limits.write_timeout)?;
+    let mut feed_stream = writer.clone();
+    let feed_log = log.clone();
+    let reader = stream.try_clone()?;
+    let feed = thread::spawn(move || {
+        let result = write_feed(&mut feed_stream, cursor, feed_log);
+        // The chatlog is gone, the session ended, or the client fell behind: stop reading requests too
+        reader.tcp().shutdown(Shutdown::Read).unwrap_or(());
+        result
+    });
+
+    for line in lines {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                println!("{} went quiet, hanging up", session.subject);
                 break;
+            },
+            _ => { break; },
+        };
+        let outcome = match session.handle(line, &limits) {
+            Action::Submit(request) => match session.submit(&tx, request) {
+                Ok(outcome) => outcome,
+                _ => { break; },
+            },
+            Action::Respond(response) => match writer.respond(&response) {
+                Ok(()) => { continue; },
+                _ => { break; },
+            },
+            Action::Skip => { continue; },
+            Action::HangUp => { break; },
+        };
+        if let Some(outcome) = outcome {
+            match answer(outcome.recv()) {
+                Some(response) if writer.respond(&response).is_ok() => {},
+                _ => { break; },
             }
         }
+        if session.has_ended() {
+            break;
+        }
+    }
+    if let Some(end) = session.farewell(&log) {
+        tx.send(end.into()).unwrap_or(());
     }
+    // If the chatlog is closing, the feed is writing the closing notice: let it finish before
+    // hanging up. Otherwise it stops once the END is applied.
+    if !log.is_closing() {
+        stream.shutdown(Shutdown::Both).unwrap_or(());
+    }
+    let result = feed.join().unwrap_or(Ok(()));
+    stream.shutdown(Shutdown::Both).unwrap_or(());
+    // The name is free again once the feed has stopped, so it can't read for the next session
+    drop(session);
+    result
 }
 
 impl Server {
@@ -47,16 +365,140 @@ impl Server {
     pub fn new(socket: &str) -> Server {
         Server {
             socket: String::from(socket),
+            credentials: None,
+            tls: None,
+            overflow: Overflow::Block,
+            shutdown: None,
+            limits: Limits::default(),
         }
     }
 
-    pub fn start(&self, executor_count: usize, tx: Sender<ChatRequest>) -> Result<(), Error> {
-        let mut threadpool = Threadpool::new(executor_count);
+    // Refuse request lines longer than `max_frame` bytes with a `too-long` error, without reading
+    // more than that into memory (see `peer::framing`). Sessions learn the limit from the welcome.
+    pub fn with_max_frame(mut self, max_frame: usize) -> Server {
+        self.limits.max_frame = max_frame;
+        self
+    }
+
+    // Hang up on connections that send nothing for this long. It applies to every connection until
+    // its first request, and afterwards only to sessions granted the `ping` capability and
+    // connections that have sent a PING; they get an END logged for them when they're dropped.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Server {
+        self.limits.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    // Throttle subjects that send more than the limiter allows, and mute repeat offenders. Servers
+    // sharing a limiter count every subject's requests together.
+    pub fn with_rate_limit(mut self, rate_limiter: RateLimiter) -> Server {
+        self.limits.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    // Stop accepting once `shutdown` is triggered (see `peer::shutdown`)
+    pub fn with_shutdown(mut self, shutdown: ShutdownSignal) -> Server {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    // Bind the socket. Hand the listener to `start_on` or `start_session_on` to learn its address
+    // (e.g. with port 0) before accepting.
+    pub fn listen(&self) -> Result<TcpListener, Error> {
         let listener = TcpListener::bind(self.socket.clone())?;
-        while let Ok((stream, _)) = listener.accept() {
+        if let Some(shutdown) = self.shutdown.as_ref() {
+            shutdown.listen(&listener);
+        }
+        Ok(listener)
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(|shutdown| shutdown.is_triggered())
+    }
+
+    // Block (the default), reject or grow the pool when all executors are busy (see `threadpool`)
+    pub fn with_overflow(mut self, overflow: Overflow) -> Server {
+        self.overflow = overflow;
+        self
+    }
+
+    fn threadpool(&self, executor_count: usize) -> Threadpool {
+        Threadpool::with_config(executor_count, executor_count, self.overflow)
+    }
+
+    // Only accept TLS connections (see `peer::transport`)
+    pub fn with_tls(mut self, tls: Arc<ServerConfig>) -> Server {
+        self.tls = Some(tls);
+        self
+    }
+
+    // Only let subjects in the credentials file connect (see `peer::auth`). Servers on one chatlog
+    // can share them.
+    pub fn with_credentials(mut self, credentials: impl Into<Arc<Credentials>>) -> Server {
+        self.credentials = Some(credentials.into());
+        self
+    }
+
+    // Accepts write-only connections. Their subjects' names are claimed in the chatlog's registry
+    // (see `handle_connection`).
+    pub fn start<S: ChatStore>(&self, executor_count: usize, tx: Sender<Submission>, log: ChatLogHandle<S>) -> Result<(), Error> {
+        self.start_on(self.listen()?, executor_count, tx, log)
+    }
+
+    // `start` on a listener from `listen`
+    pub fn start_on<S: ChatStore>(&self, listener: TcpListener, executor_count: usize, tx: Sender<Submission>, log: ChatLogHandle<S>) -> Result<(), Error> {
+        let mut threadpool = self.threadpool(executor_count);
+        while let Ok((tcp, _)) = listener.accept() {
+            if self.is_shutting_down() {
+                break;
+            }
+            let tx_main = tx.clone();
+            let names = log.names.clone();
+            let credentials = self.credentials.clone();
+            let tls = self.tls.clone();
+            let shutdown = self.shutdown.clone();
+            let limits = self.limits.clone();
+            threadpool.execute(move || {
+                let _tracked = shutdown.and_then(|shutdown| shutdown.track(&tcp));
+                tcp.set_read_timeout(limits.idle_timeout).unwrap_or(());
+                match accept_tls(tcp, &tls) {
+                    Ok(stream) => handle_connection(stream, tx_main, names, credentials, limits),
+                    Err(e) => { println!("tls handshake failed: {:?}", e); },
+                }
+            }).unwrap_or_else(|e| {
+                println!("connection dropped: {:?}", e);
+            });
+        }
+        Ok(())
+    }
+
+    // Accepts session-mode connections, which both send requests and receive the feed.
+    pub fn start_session<S: ChatStore>(&self, executor_count: usize, tx: Sender<Submission>, log: ChatLogHandle<S>) -> Result<(), Error> {
+        self.start_session_on(self.listen()?, executor_count, tx, log)
+    }
+
+    // `start_session` on a listener from `listen`
+    pub fn start_session_on<S: ChatStore>(&self, listener: TcpListener, executor_count: usize, tx: Sender<Submission>, log: ChatLogHandle<S>) -> Result<(), Error> {
+        let mut threadpool = self.threadpool(executor_count);
+        while let Ok((tcp, _)) = listener.accept() {
+            if self.is_shutting_down() {
+                break;
+            }
             let tx_main = tx.clone();
+            let log_main = log.clone();
+            let credentials = self.credentials.clone();
+            let tls = self.tls.clone();
+            let shutdown = self.shutdown.clone();
+            let limits = self.limits.clone();
             threadpool.execute(move || {
-                handle_connection(stream, tx_main);
+                let _tracked = shutdown.and_then(|shutdown| shutdown.track(&tcp));
+                tcp.set_read_timeout(limits.idle_timeout).unwrap_or(());
+                accept_tls(tcp, &tls)
+                    .and_then(|stream| handle_session(stream, tx_main, log_main, credentials, limits))
+                    .unwrap_or_else(|e| {
+                        println!("session error: {:?}", e);
+                    });
+            }).unwrap_or_else(|e| {
+                println!("session dropped: {:?}", e);
             });
         }
         Ok(())
@@ -0,0 +1,214 @@
+use std::{
+    sync::{Arc, mpsc::{self, Receiver, RecvError, SendError, Sender}},
+    time::Duration,
+};
+
+use crate::request::request::{ChatRequest, ChatRequestStatus, ChatRequestVerb};
+use crate::request::response::{ChatResponse, ErrorCode, PROTOCOL_VERSION, negotiate};
+use crate::peer::chatlog::{ChatLogHandle, Outcome, Submission};
+use crate::peer::logstore::ChatStore;
+use crate::peer::registry::Claim;
+use crate::peer::auth::{Credentials, authenticate};
+use crate::peer::server::{Limits, decode, describe, end_request, rate_limited, throttle};
+use crate::peer::ratelimit::Verdict;
+use crate::peer::framing::Frame;
+
+/**
+ * Session mode
+ * ------------
+ * One connection carries both directions. The client must start with an INIT request, which fixes
+ * the subject for the rest of the connection. Only one session at a time may use a subject (see
+ * `peer::registry`), whichever server it is on: the names are held in the chatlog's registry. If
+ * the server has credentials, the INIT must also carry the subject's password, and a session that
+ * authenticates takes the name over from the one holding it (e.g. the same client's session from
+ * before its connection dropped, which the server hasn't noticed yet). Afterwards the
+ * client sends ChatRequests and the server pushes the subject's feed (the same lines as the
+ * chatlog feed) back on the same socket.
+ *
+ * If the INIT announces a protocol version, the server answers it with a welcome or rejected
+ * frame (see `request::response`) before anything else. Clients granted the `acks` capability get
+ * an ack or error frame for every later request; other clients are disconnected on bad requests.
+ *
+ * A Session decides what happens; `peer::server` (a thread per connection) and
+ * `peer::async_server` (a task per connection) do the reading and writing.
+ */
+pub(crate) struct Session {
+    pub subject: String,
+    // The capabilities the client was granted
+    pub acks: bool,
+    pub pings: bool,
+    // Held until the session is dropped. Authenticated names are never renamed.
+    claim: Claim,
+    // Whether the client's END has been submitted
+    ended: bool,
+}
+
+// How a connection's first line went
+pub(crate) enum Opening {
+    // Send the handshake response, if the client asked for one, then submit `init`
+    Admitted { session: Session, init: ChatRequest, handshake: Option<ChatResponse> },
+    // Send the response, if there is one, and hang up. Clients from before the handshake just get
+    // disconnected.
+    Refused(Option<ChatResponse>),
+}
+
+// What to do with a line the client sent
+pub(crate) enum Action {
+    // Hand the request to the chatlog (see `Session::submit`)
+    Submit(ChatRequest),
+    // Write the frame and read on
+    Respond(ChatResponse),
+    // Read on
+    Skip,
+    HangUp,
+}
+
+// The rejected frame for a client that asked for a handshake
+fn rejected(handshake: &Option<ChatResponse>, reason: String, code: ErrorCode) -> Option<ChatResponse> {
+    handshake.as_ref().map(|_| ChatResponse::Rejected { version: PROTOCOL_VERSION, reason, code: Some(code) })
+}
+
+// The ack or error frame for a submitted request, once the chatlog has applied it. None if the
+// chatlog is gone.
+pub(crate) fn answer(outcome: Result<Outcome, RecvError>) -> Option<ChatResponse> {
+    match outcome {
+        Ok(Ok(id)) => Some(ChatResponse::Ack { id }),
+        Ok(Err(code)) => Some(ChatResponse::Error { code, message: describe(code) }),
+        Err(_) => None,
+    }
+}
+
+impl Session {
+    // Check the INIT a connection starts with, negotiate the protocol, authenticate the subject and
+    // claim its name in the chatlog's registry
+    pub fn open<S: ChatStore>(mut init: ChatRequest, log: &ChatLogHandle<S>, credentials: &Option<Arc<Credentials>>, limits: &Limits) -> Opening {
+        let names = &log.names;
+        let subject = match (&init.status, &init.verb, init.subject.clone()) {
+            (ChatRequestStatus::Valid, ChatRequestVerb::INIT, Some(subject)) if !subject.is_empty() => subject,
+            _ => {
+                println!("session must start with init: {:?}", init);
+                return Opening::Refused(None);
+            }
+        };
+        let mut handshake = negotiate(&init);
+        if let Some(response @ ChatResponse::Rejected { .. }) = handshake {
+            println!("rejecting session for {}: {:?}", subject, response);
+            return Opening::Refused(Some(response));
+        }
+        if !authenticate(&mut init, credentials) {
+            println!("rejecting session for {}: authentication failed", subject);
+            return Opening::Refused(rejected(&handshake, String::from("wrong name or password"), ErrorCode::Unauthorized));
+        }
+        let claim = match (credentials.is_some(), init.auto_rename()) {
+            // It proved it is the subject, so whoever holds the name is a session of its own
+            (true, _) => Some(names.take_over(&subject)),
+            (false, true) => Some(names.claim_with_suffix(&subject)),
+            (false, false) => names.claim(&subject),
+        };
+        let claim = match claim {
+            Some(claim) => claim,
+            None => {
+                println!("rejecting session for {}: name is taken", subject);
+                return Opening::Refused(rejected(&handshake, format!("the name {} is taken", subject), ErrorCode::NameTaken));
+            }
+        };
+        let subject = claim.name().to_string();
+        init.subject = Some(subject.clone());
+        if let Some(ChatResponse::Welcome { subject: ref mut welcome_subject, max_frame: ref mut welcome_max_frame, ref mut epoch, .. }) = handshake {
+            *welcome_subject = subject.clone();
+            *welcome_max_frame = limits.max_frame;
+            *epoch = Some(log.epoch());
+        }
+        let (acks, pings) = match &handshake {
+            Some(ChatResponse::Welcome { caps, .. }) => (
+                caps.iter().any(|cap| cap == "acks"),
+                caps.iter().any(|cap| cap == "ping"),
+            ),
+            _ => (false, false),
+        };
+        let session = Session { subject, acks, pings, claim, ended: false };
+        Opening::Admitted { session, init, handshake }
+    }
+
+    // Only sessions that heartbeat keep the idle timeout (see `Server::with_idle_timeout`)
+    pub fn idle_timeout(&self, limits: &Limits) -> Option<Duration> {
+        match self.pings {
+            true => limits.idle_timeout,
+            false => None,
+        }
+    }
+
+    pub fn handle(&mut self, frame: Frame, limits: &Limits) -> Action {
+        let mut request = match decode(frame) {
+            Ok(request) => request,
+            Err(code) => {
+                println!("error from {}: {}", self.subject, code);
+                return match self.acks {
+                    true => Action::Respond(ChatResponse::Error { code, message: describe(code) }),
+                    false => Action::HangUp,
+                };
+            },
+        };
+        if request.verb == ChatRequestVerb::PING {
+            return match self.pings {
+                true => Action::Respond(ChatResponse::Pong),
+                false => Action::Skip,
+            };
+        }
+        // The session owns the subject; clients can't speak for anyone else.
+        request.subject = Some(self.subject.clone());
+        let verdict = throttle(&limits.rate_limiter, &self.subject, &request);
+        if verdict != Verdict::Allowed {
+            // Clients without acks just lose the request
+            return match self.acks {
+                true => Action::Respond(rate_limited(verdict)),
+                false => Action::Skip,
+            };
+        }
+        self.ended = request.verb == ChatRequestVerb::END;
+        Action::Submit(request)
+    }
+
+    // Hand `request` to the chatlog. Sessions with acks get back the channel its outcome arrives on,
+    // and wait for it so acks go out in request order.
+    pub fn submit(&self, tx: &Sender<Submission>, request: ChatRequest) -> Result<Option<Receiver<Outcome>>, SendError<Submission>> {
+        match self.acks {
+            true => {
+                let (reply, outcome) = mpsc::channel();
+                tx.send(Submission::Request { request, reply: Some(reply) })?;
+                Ok(Some(outcome))
+            },
+            false => {
+                tx.send(request.into())?;
+                Ok(None)
+            },
+        }
+    }
+
+    // The session's claim on its name. Its feed reads the direct messages kept for it (see
+    // `chatlog::FeedCursor`).
+    pub fn claim_id(&self) -> u64 {
+        self.claim.id()
+    }
+
+    // Run `hook` when another session takes the name over (see `Session::open`). The server hangs
+    // up on this one.
+    pub fn on_replaced(&self, hook: impl FnOnce() + Send + 'static) {
+        self.claim.on_replaced(hook);
+    }
+
+    // Whether the client's END has been submitted. Nothing it sends afterwards is read.
+    pub fn has_ended(&self) -> bool {
+        self.ended
+    }
+
+    // The END to submit for a client that vanished (or sent something we hang up on) without one.
+    // Nobody is left to hear it if the chatlog is closing, and the subject hasn't left if another
+    // session took its name over.
+    pub fn farewell<S: ChatStore>(&self, log: &ChatLogHandle<S>) -> Option<ChatRequest> {
+        match self.ended || log.is_closing() || self.claim.is_replaced() {
+            true => None,
+            false => Some(end_request(self.subject.clone())),
+        }
+    }
+}
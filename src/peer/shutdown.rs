@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        Condvar,
+        Mutex,
+        MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/**
+ * Shutdown signal
+ * ---------------
+ * A cloneable switch shared by everything that accepts connections. Once it's triggered, accept
+ * loops stop (they are woken up by a connection to their own socket) and `wait` returns.
+ *
+ * Open connections are tracked so they can be closed at the end: `close_connections` shuts down
+ * their read half, so threads blocked reading from a client see EOF while feeds can still write
+ * their last lines.
+ *
+ * The order of a full shutdown (see `src/bin/chat-server.rs`):
+ * 1. `trigger` (e.g. from a SIGINT/SIGTERM handler): stop accepting.
+ * 2. Send `Submission::Shutdown` to the chatlog: every feed gets a notice and ends, and the stores
+ *    are flushed.
+ * 3. `close_connections`, then join the server threads.
+ *
+ * The CLI client uses one too, to stop its threads on Ctrl+C.
+ */
+#[derive(Clone, Default)]
+pub struct ShutdownSignal {
+    triggered: Arc<(Mutex<bool>, Condvar)>,
+    listeners: Arc<Mutex<Vec<SocketAddr>>>,
+    connections: Arc<Mutex<HashMap<u64, TcpStream>>>,
+    next_connection: Arc<AtomicU64>,
+}
+
+/**
+ * An open connection, tracked until this is dropped
+ */
+pub struct Tracked {
+    id: u64,
+    connections: Arc<Mutex<HashMap<u64, TcpStream>>>,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.remove(&self.id);
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl ShutdownSignal {
+    pub fn new() -> ShutdownSignal {
+        ShutdownSignal::default()
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *lock(&self.triggered.0)
+    }
+
+    // Stop accepting connections and wake up everyone waiting for the shutdown
+    pub fn trigger(&self) {
+        let (flag, condvar) = &*self.triggered;
+        *lock(flag) = true;
+        condvar.notify_all();
+        for address in lock(&self.listeners).iter() {
+            // The accept loop wakes up, sees the flag and stops. The connection itself is dropped.
+            TcpStream::connect(address).map(|_| ()).unwrap_or(());
+        }
+    }
+
+    // Sleep until the shutdown is triggered (BLOCKING)
+    pub fn wait(&self) {
+        let (flag, condvar) = &*self.triggered;
+        let guard = lock(flag);
+        let _triggered = match condvar.wait_while(guard, |triggered| !*triggered) {
+            Ok(triggered) => triggered,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+    }
+
+    // Sleep until the shutdown is triggered or the timeout runs out (BLOCKING). True if triggered.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (flag, condvar) = &*self.triggered;
+        let guard = lock(flag);
+        match condvar.wait_timeout_while(guard, timeout, |triggered| !*triggered) {
+            Ok((triggered, _)) => *triggered,
+            Err(poisoned) => *poisoned.into_inner().0,
+        }
+    }
+
+    // Register an accept loop's listener, so `trigger` can wake it up
+    pub fn listen(&self, listener: &TcpListener) {
+        let mut address = match listener.local_addr() {
+            Ok(address) => address,
+            _ => { return; },
+        };
+        // A listener on every interface can still be reached on loopback
+        if address.ip().is_unspecified() {
+            address.set_ip(match address.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        lock(&self.listeners).push(address);
+    }
+
+    // Track an accepted connection until the returned guard is dropped
+    pub fn track(&self, tcp: &TcpStream) -> Option<Tracked> {
+        let id = self.next_connection.fetch_add(1, Ordering::SeqCst);
+        lock(&self.connections).insert(id, tcp.try_clone().ok()?);
+        Some(Tracked { id, connections: self.connections.clone() })
+    }
+
+    // Shut down the read half of every tracked connection
+    pub fn close_connections(&self) {
+        for tcp in lock(&self.connections).values() {
+            tcp.shutdown(Shutdown::Read).unwrap_or(());
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::request::entry::LogEntry;
+
+/**
+ * Slow consumers
+ * --------------
+ * A feed writes whatever was logged for its subject since its last write (see
+ * `chatlog::FeedCursor`), so a subscriber that reads slowly falls further and further behind,
+ * and one that stops reading would block its writer for good. Every feed is held to FeedLimits:
+ *
+ * * write_timeout: a write that makes no progress for this long disconnects the subscriber (on
+ *   the async server: a batch that isn't written within this long).
+ * * high_water: the most room entries a feed writes at once. Feeds are cursors into the room logs
+ *   rather than queues per subscriber: nothing is copied for a subscriber, the entries stay in the
+ *   log until its feed gets to them, so the log itself is the queue and `high_water` bounds how
+ *   far behind in it a subscriber may be. One further behind is a laggard, and `laggards` decides
+ *   what happens to it: skip to the newest `high_water` room entries, counting the rest as
+ *   dropped, or disconnect it (it can reconnect and resume with `backfill=since:<id>`). The
+ *   backfill a feed starts with doesn't count. Entries without a room (the subject's direct
+ *   messages and notices) are never dropped: they aren't in any log to resume from, so they are
+ *   written even to a laggard that is being disconnected.
+ *
+ * Subscribers counts what every feed currently open has written and dropped (`stats`), and what
+ * all feeds have since the server started (`totals`). chat-server prints the `summary` now and
+ * then.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Laggards {
+    Drop,
+    Disconnect,
+}
+
+// What a feed should write, once held to its limits (see `Subscription::admit`)
+#[derive(Debug, PartialEq)]
+pub enum Admitted {
+    Write(Vec<LogEntry>),
+    // Write these, then disconnect the laggard
+    HangUp(Vec<LogEntry>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeedLimits {
+    pub high_water: usize,
+    pub write_timeout: Option<Duration>,
+    pub laggards: Laggards,
+}
+
+impl Default for FeedLimits {
+    fn default() -> Self {
+        FeedLimits {
+            high_water: 1000,
+            write_timeout: Some(Duration::from_secs(30)),
+            laggards: Laggards::Disconnect,
+        }
+    }
+}
+
+// What one feed has written and dropped so far
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeedStats {
+    pub subject: String,
+    pub written: u64,
+    pub dropped: u64,
+}
+
+// What every feed there has been has done
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeedTotals {
+    pub feeds: u64,
+    pub written: u64,
+    pub dropped: u64,
+    // Laggards that were disconnected
+    pub disconnected: u64,
+}
+
+#[derive(Default)]
+struct Totals {
+    feeds: AtomicU64,
+    written: AtomicU64,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+struct Counters {
+    subject: String,
+    written: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Counters {
+    fn stats(&self) -> FeedStats {
+        FeedStats {
+            subject: self.subject.clone(),
+            written: self.written.load(Ordering::SeqCst),
+            dropped: self.dropped.load(Ordering::SeqCst),
+        }
+    }
+}
+
+// The feeds currently open, and the limits they're held to
+#[derive(Clone, Default)]
+pub struct Subscribers {
+    pub limits: FeedLimits,
+    active: Arc<Mutex<HashMap<u64, Arc<Counters>>>>,
+    next_id: Arc<AtomicU64>,
+    // Kept after the feeds are gone
+    totals: Arc<Totals>,
+}
+
+impl Subscribers {
+    pub fn new(limits: FeedLimits) -> Subscribers {
+        Subscribers {
+            limits,
+            ..Subscribers::default()
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Arc<Counters>>> {
+        match self.active.lock() {
+            Ok(active) => active,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Count a feed for `subject` until the Subscription is dropped
+    pub fn subscribe(&self, subject: &str) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let counters = Arc::new(Counters {
+            subject: subject.to_string(),
+            written: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        self.lock().insert(id, counters.clone());
+        self.totals.feeds.fetch_add(1, Ordering::SeqCst);
+        Subscription { subscribers: self.clone(), id, counters, backfilled: false }
+    }
+
+    // Every open feed, oldest first
+    pub fn stats(&self) -> Vec<FeedStats> {
+        let active = self.lock();
+        let mut ids: Vec<&u64> = active.keys().collect();
+        ids.sort();
+        ids.into_iter().map(|id| active[id].stats()).collect()
+    }
+
+    pub fn totals(&self) -> FeedTotals {
+        FeedTotals {
+            feeds: self.totals.feeds.load(Ordering::SeqCst),
+            written: self.totals.written.load(Ordering::SeqCst),
+            dropped: self.totals.dropped.load(Ordering::SeqCst),
+            disconnected: self.totals.disconnected.load(Ordering::SeqCst),
+        }
+    }
+
+    // One line on the open feeds and the totals, for the server's log
+    pub fn summary(&self) -> String {
+        let stats = self.stats();
+        let totals = self.totals();
+        let mut summary = format!(
+            "feeds: {} open, {} since start; {} entries written, {} dropped, {} laggards disconnected",
+            stats.len(), totals.feeds, totals.written, totals.dropped, totals.disconnected
+        );
+        let lagging: Vec<String> = stats
+            .iter()
+            .filter(|stats| stats.dropped > 0)
+            .map(|stats| format!("{} ({})", stats.subject, stats.dropped))
+            .collect();
+        if !lagging.is_empty() {
+            summary = format!("{}; open feeds that dropped entries: {}", summary, lagging.join(", "));
+        }
+        summary
+    }
+}
+
+// One feed's place in Subscribers. Removed from it when dropped.
+pub struct Subscription {
+    subscribers: Subscribers,
+    id: u64,
+    counters: Arc<Counters>,
+    // Whether the first batch, which holds the backfill, has gone out
+    backfilled: bool,
+}
+
+impl Subscription {
+    pub fn limits(&self) -> FeedLimits {
+        self.subscribers.limits
+    }
+
+    // Hold the next batch to the high-water mark. Only room entries count: the rest are always
+    // written.
+    pub fn admit(&mut self, mut pending: Vec<LogEntry>) -> Admitted {
+        let limits = self.limits();
+        let backfill = !std::mem::replace(&mut self.backfilled, true);
+        let in_rooms = pending.iter().filter(|entry| entry.room.is_some()).count();
+        if backfill || in_rooms <= limits.high_water {
+            return Admitted::Write(pending);
+        }
+        let behind = in_rooms - limits.high_water;
+        match limits.laggards {
+            Laggards::Drop => {
+                // Entries are in id order, so these are the oldest
+                let mut skipped = 0;
+                pending.retain(|entry| match entry.room.is_some() && skipped < behind {
+                    true => {
+                        skipped += 1;
+                        false
+                    },
+                    false => true,
+                });
+                self.counters.dropped.fetch_add(behind as u64, Ordering::SeqCst);
+                self.subscribers.totals.dropped.fetch_add(behind as u64, Ordering::SeqCst);
+                Admitted::Write(pending)
+            },
+            Laggards::Disconnect => {
+                println!("{} is {} entries behind its feed, disconnecting", self.counters.subject, behind);
+                self.subscribers.totals.disconnected.fetch_add(1, Ordering::SeqCst);
+                pending.retain(|entry| entry.room.is_none());
+                Admitted::HangUp(pending)
+            },
+        }
+    }
+
+    pub fn wrote(&self, count: usize) {
+        self.counters.written.fetch_add(count as u64, Ordering::SeqCst);
+        self.subscribers.totals.written.fetch_add(count as u64, Ordering::SeqCst);
+    }
+
+    pub fn stats(&self) -> FeedStats {
+        self.counters.stats()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let stats = self.stats();
+        if stats.dropped > 0 {
+            println!("feed for {} dropped {} of {} entries", stats.subject, stats.dropped, stats.dropped + stats.written);
+        }
+        self.subscribers.lock().remove(&self.id);
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use rustls::{
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+};
+
+/**
+ * Transport
+ * ---------
+ * Every socket is either a plain TcpStream or TLS (rustls) on top of one. `Stream` hides which,
+ * so servers, the chatlog feed and clients work the same over both.
+ *
+ * A Stream can be cloned with `try_clone` to read on one thread and write on another, like a
+ * TcpStream. For TLS, the clones share the rustls connection; socket reads happen outside of its
+ * lock, so a blocked reader never holds up writers.
+ *
+ * The TLS handshake is finished when the Stream is created (see `accept_tls` and `connect`), so
+ * connections that only ever write (e.g. to port 9000) work as well.
+ */
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+pub struct TlsStream {
+    tcp: TcpStream,
+    connection: Arc<Mutex<Connection>>,
+}
+
+fn tls_error(e: rustls::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+impl TlsStream {
+    fn new(mut tcp: TcpStream, mut connection: Connection) -> Result<TlsStream, Error> {
+        while connection.is_handshaking() {
+            connection.complete_io(&mut tcp)?;
+        }
+        Ok(TlsStream { tcp, connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        match self.connection.lock() {
+            Ok(connection) => connection,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn try_clone(&self) -> Result<TlsStream, Error> {
+        Ok(TlsStream { tcp: self.tcp.try_clone()?, connection: self.connection.clone() })
+    }
+
+    // Send whatever rustls has queued up for the peer
+    fn flush_tls(connection: &mut Connection, mut tcp: &TcpStream) -> Result<(), Error> {
+        while connection.wants_write() {
+            connection.write_tls(&mut tcp)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut ciphertext = [0u8; 16 * 1024];
+        loop {
+            {
+                let mut connection = self.lock();
+                match connection.reader().read(buf) {
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                    result => { return result; },
+                }
+            }
+            // Nothing decrypted yet: wait for more from the socket, without holding the lock
+            let count = self.tcp.read(&mut ciphertext)?;
+            let mut connection = self.lock();
+            let mut received = &ciphertext[..count];
+            loop {
+                connection.read_tls(&mut received)?;
+                connection.process_new_packets().map_err(tls_error)?;
+                if received.is_empty() {
+                    break;
+                }
+            }
+            TlsStream::flush_tls(&mut connection, &self.tcp)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut connection = self.lock();
+        let count = connection.writer().write(buf)?;
+        TlsStream::flush_tls(&mut connection, &self.tcp)?;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut connection = self.lock();
+        connection.writer().flush()?;
+        TlsStream::flush_tls(&mut connection, &self.tcp)
+    }
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> Result<Stream, Error> {
+        match self {
+            Stream::Plain(tcp) => Ok(Stream::Plain(tcp.try_clone()?)),
+            Stream::Tls(tls) => Ok(Stream::Tls(tls.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        match self {
+            Stream::Plain(tcp) => tcp.shutdown(how),
+            Stream::Tls(tls) => {
+                {
+                    let mut connection = tls.lock();
+                    connection.send_close_notify();
+                    // The peer may be gone already
+                    TlsStream::flush_tls(&mut connection, &tls.tcp).unwrap_or(());
+                }
+                tls.tcp.shutdown(how)
+            },
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.tcp().set_write_timeout(timeout)
+    }
+
+    // The socket underneath
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(tcp) => tcp,
+            Stream::Tls(tls) => &tls.tcp,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+// Wrap an accepted socket, running the TLS handshake if the server has a TLS config (BLOCKING).
+// Call it on the connection's own thread, so a slow client can't hold up the accept loop.
+pub fn accept_tls(tcp: TcpStream, tls: &Option<Arc<ServerConfig>>) -> Result<Stream, Error> {
+    match tls {
+        Some(config) => {
+            let connection = ServerConnection::new(config.clone()).map_err(tls_error)?;
+            Ok(Stream::Tls(TlsStream::new(tcp, Connection::from(connection))?))
+        },
+        None => Ok(Stream::Plain(tcp)),
+    }
+}
+
+// Connect to `socket` (`host:port`), over TLS if there's a client config. The certificate must be
+// valid for `host`.
+pub fn connect(socket: &str, tls: &Option<Arc<ClientConfig>>) -> Result<Stream, Error> {
+    let tcp = TcpStream::connect(socket)?;
+    match tls {
+        Some(config) => {
+            let host = match socket.rsplit_once(':') {
+                Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+                None => socket,
+            };
+            let server_name = ServerName::try_from(host.to_string())
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            let connection = ClientConnection::new(config.clone(), server_name).map_err(tls_error)?;
+            Ok(Stream::Tls(TlsStream::new(tcp, Connection::from(connection))?))
+        },
+        None => Ok(Stream::Plain(tcp)),
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)).collect()
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    match rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))? {
+        Some(key) => Ok(key),
+        None => Err(Error::new(ErrorKind::InvalidData, format!("no private key in {:?}", path))),
+    }
+}
+
+// TLS config for a server, from a PEM certificate chain and private key
+pub fn server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>, Error> {
+    server_config_from_der(load_certs(cert_path)?, load_key(key_path)?)
+}
+
+pub fn server_config_from_der(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Arc<ServerConfig>, Error> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+// TLS config for a client that trusts the certificates in a PEM file (e.g. a self-signed
+// server certificate, or the CA that signed it)
+pub fn client_config(ca_path: &Path) -> Result<Arc<ClientConfig>, Error> {
+    client_config_from_der(load_certs(ca_path)?)
+}
+
+pub fn client_config_from_der(certs: Vec<CertificateDer<'static>>) -> Result<Arc<ClientConfig>, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,124 @@
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::request::request::{ChatRequest, ChatRequestStatus, ChatRequestVerb};
+
+/**
+ * Chat Log Entry
+ * --------------
+ * What the chatlog stores and what feeds send, one JSON object per line:
+ *
+ * {"id":42,"timestamp":1671234567890,"subject":"Dan","verb":"tx","object":"hi!","room":"lobby"}
+ *
+ * * id: Monotonic message id, assigned by the server. Unique across rooms.
+ * * timestamp: When the server logged the entry, in milliseconds since the unix epoch.
+ * * subject, verb, object: As in the ChatRequest that produced the entry.
+ * * room: The room the entry was logged to. Missing for direct messages and notices.
+ *
+ * Entries are rendered to display text at the edges (see `render`).
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    pub subject: String,
+    pub verb: ChatRequestVerb,
+    pub object: String,
+    pub room: Option<String>,
+}
+
+// Milliseconds since the unix epoch
+pub fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as u64,
+        _ => 0,
+    }
+}
+
+impl LogEntry {
+    pub fn from_request(id: u64, room: Option<String>, request: &ChatRequest) -> LogEntry {
+        LogEntry {
+            id,
+            timestamp: now_millis(),
+            subject: request.subject.clone().unwrap_or_default(),
+            verb: request.verb,
+            object: request.object.clone().unwrap_or_default(),
+            room,
+        }
+    }
+
+    // A message from the server itself, e.g. to tell a subject their DM went nowhere
+    pub fn notice(id: u64, text: String) -> LogEntry {
+        LogEntry {
+            id,
+            timestamp: now_millis(),
+            subject: String::new(),
+            verb: ChatRequestVerb::NOTICE,
+            object: text,
+            room: None,
+        }
+    }
+
+    // The request this entry was logged from, to reuse its object parsing
+    pub fn to_request(&self) -> ChatRequest {
+        ChatRequest {
+            subject: Some(self.subject.clone()),
+            verb: self.verb,
+            object: Some(self.object.clone()),
+            status: ChatRequestStatus::Valid,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut value = json!({
+            "id": self.id,
+            "timestamp": self.timestamp,
+            "subject": self.subject,
+            "verb": self.verb.to_string(),
+            "object": self.object,
+        });
+        if let Some(room) = self.room.as_ref() {
+            value["room"] = json!(room);
+        }
+        value.to_string()
+    }
+
+    pub fn from_json(string: &str) -> Option<LogEntry> {
+        let value: Value = serde_json::from_str(string).ok()?;
+        Some(LogEntry {
+            id: value.get("id")?.as_u64()?,
+            timestamp: value.get("timestamp")?.as_u64()?,
+            subject: value.get("subject")?.as_str()?.to_string(),
+            verb: ChatRequestVerb::from_str(value.get("verb")?.as_str()?),
+            object: value.get("object")?.as_str()?.to_string(),
+            room: value.get("room").and_then(|room| room.as_str()).map(|room| room.to_string()),
+        })
+    }
+
+    // Display text for the entry, without the timestamp
+    pub fn render(&self) -> String {
+        let request = self.to_request();
+        let body = request.body().unwrap_or_default();
+        match self.verb {
+            ChatRequestVerb::INIT => format!("{} is connected!", self.subject),
+            ChatRequestVerb::TX => format!("{}: {}", self.subject, body),
+            ChatRequestVerb::JOIN => format!("{} joined #{}", self.subject, request.room().unwrap_or_default()),
+            ChatRequestVerb::PART => format!("{} left #{}", self.subject, request.room().unwrap_or_default()),
+            ChatRequestVerb::ROOMTX => format!("#{} {}: {}", request.room().unwrap_or_default(), self.subject, body),
+            ChatRequestVerb::DM => format!("{} -> {}: {}", self.subject, request.target().unwrap_or_default(), body),
+            // Clients with the keys decrypt these themselves (see `peer::e2e`)
+            ChatRequestVerb::EDM => format!("{} -> {}: (encrypted message)", self.subject, request.target().unwrap_or_default()),
+            ChatRequestVerb::KEY => format!("{}'s key: {}", self.subject, self.object),
+            ChatRequestVerb::GETKEY => format!("{} asked for {}'s key", self.subject, self.object),
+            ChatRequestVerb::END => format!("{} disconnected!", self.subject),
+            ChatRequestVerb::NOTICE => self.object.clone(),
+            ChatRequestVerb::PING | ChatRequestVerb::NONE => String::from("error"),
+        }
+    }
+
+    // `HH:MM` (UTC) of the entry's timestamp
+    pub fn time_of_day(&self) -> String {
+        let minutes = self.timestamp / 1000 / 60;
+        format!("{:02}:{:02}", (minutes / 60) % 24, minutes % 60)
+    }
+}
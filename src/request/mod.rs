@@ -1 +1,3 @@
-pub mod request;
\ No newline at end of file
+pub mod request;
+pub mod entry;
+pub mod response;
@@ -1,5 +1,6 @@
 
 use regex::Regex;
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Error};
 
 /** 
@@ -11,6 +12,12 @@ use std::fmt::{Display, Formatter, Error};
  * [1:SUBJECT][2:VERB][3:OBJECT]
  * 
  * SUBJECT, VERB, and OBJECT are all to be escaped and encoded as utf-8 strings.
+ * A request is a single line terminated by `\r\n`.
+ * 
+ * Escaping
+ * --------
+ * Inside a field, `\`, `[`, `]`, newline and carriage return are written as `\\`, `\[`, `\]`,
+ * `\n` and `\r`. Any other character (any UTF-8) is written as-is. See `escape`/`unescape`.
  * 
  * Subject
  * -------
@@ -18,24 +25,63 @@ use std::fmt::{Display, Formatter, Error};
  * 
  * Verbs
  * -----
- * * INIT: Starts a request.
- * * TX: Transmits a message
+ * * INIT: Starts a request. OBJECT holds space-separated `key=value` options (see below).
+ * * TX: Transmits a message to the default room.
+ * * JOIN: Joins the room named in OBJECT.
+ * * PART: Leaves the room named in OBJECT.
+ * * ROOMTX: Transmits a message to a room. OBJECT is `<room> <message>`.
+ * * DM: Transmits a private message to one subject. OBJECT is `<target> <message>`.
+ * * KEY: Publishes the subject's public key for encrypted DMs. OBJECT is the key (hex).
+ * * GETKEY: Asks for the public key of the subject named in OBJECT. The server answers on the
+ *   feed with a KEY entry whose subject is the key's owner.
+ * * EDM: Transmits an end-to-end encrypted private message. OBJECT is `<target> <sealed message>`
+ *   (see `peer::e2e`).
  * * END: Ends the request.
+ * * PING: Keeps the connection alive. Never logged; sessions granted the `ping` capability get a
+ *   pong frame back (see `request::response`).
+ * * NOTICE: Sent by the server only, e.g. when a DM could not be delivered. OBJECT is the text.
+ * 
+ * Rooms
+ * -----
+ * Room names are a single word with an optional leading `#` (e.g. `#dev`).
+ * 
+ * INIT options
+ * ------------
+ * * `backfill`: How much history to send a new feed subscriber, per room.
+ *   `all` (default), `live` (nothing, live only), `last:<N>` or `since:<message id>`.
+ *   A message id is the `id` of a log entry (see `request::entry`).
+ * * `version`: The protocol version the client speaks. Clients that leave it out are treated as
+ *   version 1 and get no handshake response.
+ * * `caps`: Comma-separated capabilities the client would like (e.g. `caps=rooms,dm`).
+ *   The server replies with the version and capabilities it accepted (see `request::response`).
+ * * `rename`: `auto` lets the server pick a free name (`Dan2`, `Dan3`, ...) if the subject is
+ *   already taken. Otherwise an INIT for a taken name is rejected.
+ * * `password`: The subject's password or token, as hex-encoded UTF-8. Required if the server has
+ *   a credentials file (see `peer::auth`). The server strips it before the INIT is logged.
  * 
  * **/
 
- #[derive(Debug)]
+ #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 
 pub enum ChatRequestStatus {
     Valid,
     Invalid,
  }
 
- #[derive(Debug)]
+ #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChatRequestVerb {
     INIT,
     TX,
+    JOIN,
+    PART,
+    ROOMTX,
+    DM,
+    KEY,
+    GETKEY,
+    EDM,
     END,
+    PING,
+    NOTICE,
     NONE,
 }
 
@@ -44,7 +90,16 @@ impl ChatRequestVerb {
         match string {
             "init" => ChatRequestVerb::INIT,
             "tx" => ChatRequestVerb::TX,
+            "join" => ChatRequestVerb::JOIN,
+            "part" => ChatRequestVerb::PART,
+            "roomtx" => ChatRequestVerb::ROOMTX,
+            "dm" => ChatRequestVerb::DM,
+            "key" => ChatRequestVerb::KEY,
+            "getkey" => ChatRequestVerb::GETKEY,
+            "edm" => ChatRequestVerb::EDM,
             "end" => ChatRequestVerb::END,
+            "ping" => ChatRequestVerb::PING,
+            "notice" => ChatRequestVerb::NOTICE,
             _ => ChatRequestVerb::NONE
         }
     }
@@ -52,7 +107,16 @@ impl ChatRequestVerb {
         match self {
             ChatRequestVerb::INIT => "init",
             ChatRequestVerb::TX => "tx",
+            ChatRequestVerb::JOIN => "join",
+            ChatRequestVerb::PART => "part",
+            ChatRequestVerb::ROOMTX => "roomtx",
+            ChatRequestVerb::DM => "dm",
+            ChatRequestVerb::KEY => "key",
+            ChatRequestVerb::GETKEY => "getkey",
+            ChatRequestVerb::EDM => "edm",
             ChatRequestVerb::END => "end",
+            ChatRequestVerb::PING => "ping",
+            ChatRequestVerb::NOTICE => "notice",
             ChatRequestVerb::NONE => "none"
         }
     }
@@ -64,7 +128,113 @@ impl Display for ChatRequestVerb {
     }
 }
 
-#[derive(Debug)]
+pub const DEFAULT_ROOM: &str = "lobby";
+
+// Longest request line (in bytes) a server accepts unless it says otherwise (see `Server::with_max_frame`)
+pub const MAX_REQUEST_LENGTH: usize = 64 * 1024;
+
+// Escape a field for the wire format
+pub fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for character in field.chars() {
+        match character {
+            '\\' => escaped.push_str("\\\\"),
+            '[' => escaped.push_str("\\["),
+            ']' => escaped.push_str("\\]"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+// Undo `escape`. Returns None for unknown escapes or a dangling backslash.
+pub fn unescape(field: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut characters = field.chars();
+    while let Some(character) = characters.next() {
+        match character {
+            '\\' => match characters.next()? {
+                '\\' => unescaped.push('\\'),
+                '[' => unescaped.push('['),
+                ']' => unescaped.push(']'),
+                'n' => unescaped.push('\n'),
+                'r' => unescaped.push('\r'),
+                _ => { return None; },
+            },
+            _ => unescaped.push(character),
+        }
+    }
+    Some(unescaped)
+}
+
+// Normalize a room name (strips a leading `#`). Returns None if the name is not a single word.
+pub fn parse_room_name(string: &str) -> Option<String> {
+    let name = string.trim().trim_start_matches('#');
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+// Parse `key=value` options separated by spaces. Words without `=` are ignored.
+pub fn parse_options(string: &str) -> HashMap<String, String> {
+    string
+        .split_whitespace()
+        .filter_map(|word| word.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+// Drop the `key=value` option from an options string, keeping everything else as it was
+pub fn remove_option(string: &str, key: &str) -> String {
+    let prefix = format!("{}=", key);
+    string
+        .split_whitespace()
+        .filter(|word| !word.starts_with(&prefix))
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/**
+ * How much history a new feed subscriber receives from each room
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backfill {
+    All,
+    Live,
+    Last(usize),
+    Since(u64),
+}
+
+impl Backfill {
+    pub fn parse(string: &str) -> Option<Backfill> {
+        match string.split_once(':') {
+            Some(("last", n)) => n.parse::<usize>().ok().map(Backfill::Last),
+            Some(("since", id)) => id.parse::<u64>().ok().map(Backfill::Since),
+            _ => match string {
+                "all" => Some(Backfill::All),
+                "live" => Some(Backfill::Live),
+                _ => None,
+            },
+        }
+    }
+
+}
+
+impl Display for Backfill {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Backfill::All => write!(f, "all"),
+            Backfill::Live => write!(f, "live"),
+            Backfill::Last(n) => write!(f, "last:{}", n),
+            Backfill::Since(id) => write!(f, "since:{}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ChatRequest {
     pub subject: Option<String>,
     pub verb: ChatRequestVerb,
@@ -73,6 +243,7 @@ pub struct ChatRequest {
 }
 
  impl ChatRequest {
+    // Decode a request from its wire format (without the trailing `\r\n`)
     pub fn from(string: String) -> ChatRequest {
         let default_result = ChatRequest {
             subject: None,
@@ -80,34 +251,40 @@ pub struct ChatRequest {
             object: None,
             status: ChatRequestStatus::Invalid,
         };
-        let parser = match Regex::new(r"^\[1:(.*)\]\[2:(.*)\]\[3:(.*)\]$") {
+        // Each field is a run of unescaped characters other than brackets and backslashes,
+        // or backslash escapes.
+        let parser = match Regex::new(r"^\[1:((?:[^\[\]\\]|\\.)*)\]\[2:((?:[^\[\]\\]|\\.)*)\]\[3:((?:[^\[\]\\]|\\.)*)\]$") {
             Ok(v) => Some(v),
             _ => None
         };
         match parser {
             Some(x) => match x.captures(&string) {
                 Some(captures) => {
-                    return ChatRequest {
-                        subject: Some(captures[1].to_string()),
-                        verb: ChatRequestVerb::from_str(&captures[2]),
-                        object: Some(captures[3].to_string()),
-                        status: ChatRequestStatus::Valid
-                    };
+                    match (unescape(&captures[1]), unescape(&captures[2]), unescape(&captures[3])) {
+                        (Some(subject), Some(verb), Some(object)) => ChatRequest {
+                            subject: Some(subject),
+                            verb: ChatRequestVerb::from_str(&verb),
+                            object: Some(object),
+                            status: ChatRequestStatus::Valid
+                        },
+                        _ => default_result,
+                    }
                 },
-                _ => { return default_result; },
+                _ => default_result,
             },
-            None => { return default_result; },
+            None => default_result,
         }
     }
 
+    // Encode a request to its wire format, including the trailing `\r\n`
     pub fn to_string_opt(&self) -> Option<String> {
         match self.status {
             ChatRequestStatus::Valid => {
                 Some(
                     format!("[1:{}][2:{}][3:{}]\r\n",
-                        self.subject.as_ref().unwrap().clone(),
-                        self.verb,
-                        self.object.as_ref().unwrap().clone()
+                        escape(self.subject.as_deref().unwrap_or("")),
+                        escape(self.verb.to_string()),
+                        escape(self.object.as_deref().unwrap_or(""))
                     )
                 )
             },
@@ -118,39 +295,104 @@ pub struct ChatRequest {
         
     }
 
-    pub fn to_log(&self) -> String {
-        match self.status {
-            ChatRequestStatus::Valid => {
-                match self.verb {
-                    ChatRequestVerb::INIT => format!(
-                        "{} is connected!\r\n",
-                        match self.subject.as_ref() {
-                            Some(string) => string,
-                            _ => "(none)",
-                        },
-                    ),
-                    ChatRequestVerb::TX => format!(
-                        "{}: {}\r\n",
-                        match self.subject.as_ref() {
-                            Some(string) => string,
-                            _ => "(none)"
-                        },
-                        match self.object.as_ref() {
-                            Some(string) => string,
-                            _ => "(none)"
-                        },
-                    ),
-                    ChatRequestVerb::END => format!(
-                        "{} disconnected!",
-                        match self.subject.as_ref() {
-                            Some(string) => string,
-                            _ => "(none)"
-                        }
-                    ),
-                    _ => "error".to_string(),
+    // The room this request targets, if any. TX always targets the default room.
+    pub fn room(&self) -> Option<String> {
+        match self.verb {
+            ChatRequestVerb::TX => Some(String::from(DEFAULT_ROOM)),
+            ChatRequestVerb::JOIN | ChatRequestVerb::PART => {
+                parse_room_name(self.object.as_ref()?)
+            },
+            ChatRequestVerb::ROOMTX => {
+                let object = self.object.as_ref()?;
+                let (room, _) = object.split_once(' ')?;
+                parse_room_name(room)
+            },
+            _ => None,
+        }
+    }
+
+    // The backfill an INIT request asks for (defaults to everything)
+    pub fn backfill(&self) -> Backfill {
+        match self.object.as_ref() {
+            Some(object) => match parse_options(object).get("backfill") {
+                Some(value) => Backfill::parse(value).unwrap_or(Backfill::All),
+                None => Backfill::All,
+            },
+            None => Backfill::All,
+        }
+    }
+
+    // The log a resuming INIT's `backfill=since:<id>` counts from (see `ChatLogHandle::epoch`)
+    pub fn epoch(&self) -> Option<u64> {
+        match self.verb {
+            ChatRequestVerb::INIT => parse_options(self.object.as_ref()?).get("epoch")?.parse::<u64>().ok(),
+            _ => None,
+        }
+    }
+
+    // The protocol version an INIT request announces. None for clients that predate the handshake.
+    pub fn version(&self) -> Option<String> {
+        match self.verb {
+            ChatRequestVerb::INIT => parse_options(self.object.as_ref()?).remove("version"),
+            _ => None,
+        }
+    }
+
+    // The password (still hex-encoded) an INIT request authenticates with
+    pub fn password(&self) -> Option<String> {
+        match self.verb {
+            ChatRequestVerb::INIT => parse_options(self.object.as_ref()?).remove("password"),
+            _ => None,
+        }
+    }
+
+    // Whether an INIT request lets the server rename the subject if the name is taken
+    pub fn auto_rename(&self) -> bool {
+        match (self.verb, self.object.as_ref()) {
+            (ChatRequestVerb::INIT, Some(object)) => parse_options(object).get("rename").is_some_and(|value| value == "auto"),
+            _ => false,
+        }
+    }
+
+    // The capabilities an INIT request asks for (`caps=a,b,c`)
+    pub fn capabilities(&self) -> Vec<String> {
+        let options = match (self.verb, self.object.as_ref()) {
+            (ChatRequestVerb::INIT, Some(object)) => parse_options(object),
+            _ => { return vec![]; },
+        };
+        match options.get("caps") {
+            Some(caps) => caps
+                .split(',')
+                .filter(|cap| !cap.is_empty())
+                .map(|cap| cap.to_string())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // The recipient of a DM or EDM request.
+    pub fn target(&self) -> Option<String> {
+        match self.verb {
+            ChatRequestVerb::DM | ChatRequestVerb::EDM => {
+                let (target, body) = self.object.as_ref()?.split_once(' ')?;
+                match target.is_empty() || body.is_empty() {
+                    true => None,
+                    false => Some(target.to_string()),
                 }
             },
-            _ => "error".to_string(),
+            _ => None,
+        }
+    }
+
+    // The message body of a TX, ROOMTX, DM or EDM request. For EDM, the body is still sealed.
+    pub fn body(&self) -> Option<String> {
+        match self.verb {
+            ChatRequestVerb::TX => self.object.clone(),
+            ChatRequestVerb::ROOMTX | ChatRequestVerb::DM | ChatRequestVerb::EDM => {
+                let (_, body) = self.object.as_ref()?.split_once(' ')?;
+                Some(body.to_string())
+            },
+            _ => None,
         }
     }
  }
@@ -0,0 +1,235 @@
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter, Error};
+
+use crate::request::request::{ChatRequest, MAX_REQUEST_LENGTH};
+
+// The protocol version this build speaks. Version 1 is every client from before the handshake.
+pub const PROTOCOL_VERSION: u32 = 2;
+// The oldest protocol version the server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+// Capabilities the server can grant
+pub const CAPABILITIES: [&str; 5] = ["rooms", "dm", "acks", "e2e", "ping"];
+
+// Why the server refused a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    // The line isn't a request (bad framing or escaping), or a required part of the object is missing
+    Malformed,
+    // The request is longer than the server accepts
+    TooLong,
+    // The verb isn't one clients may send
+    UnknownVerb,
+    // The subject is sending too fast
+    RateLimited,
+    // The connection hasn't authenticated (see `peer::auth`), or speaks for a subject it isn't
+    // pinned to
+    Unauthorized,
+    // Another connection holds the subject's name (see `peer::registry`)
+    NameTaken,
+    // The subject isn't in the room the request is for
+    NotAMember,
+    // The target of a DM isn't connected
+    NotConnected,
+    // The subject asked for hasn't published a key for encrypted DMs
+    NoKey,
+    // The server failed to store the request
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn parse(string: &str) -> Option<ErrorCode> {
+        match string {
+            "malformed" => Some(ErrorCode::Malformed),
+            "too-long" => Some(ErrorCode::TooLong),
+            "unknown-verb" => Some(ErrorCode::UnknownVerb),
+            "rate-limited" => Some(ErrorCode::RateLimited),
+            "unauthorized" => Some(ErrorCode::Unauthorized),
+            "name-taken" => Some(ErrorCode::NameTaken),
+            "not-a-member" => Some(ErrorCode::NotAMember),
+            "not-connected" => Some(ErrorCode::NotConnected),
+            "no-key" => Some(ErrorCode::NoKey),
+            "internal" => Some(ErrorCode::Internal),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        let code = match self {
+            ErrorCode::Malformed => "malformed",
+            ErrorCode::TooLong => "too-long",
+            ErrorCode::UnknownVerb => "unknown-verb",
+            ErrorCode::RateLimited => "rate-limited",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::NameTaken => "name-taken",
+            ErrorCode::NotAMember => "not-a-member",
+            ErrorCode::NotConnected => "not-connected",
+            ErrorCode::NoKey => "no-key",
+            ErrorCode::Internal => "internal",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+/**
+ * Server Responses
+ * ----------------
+ * Frames the server writes back on a session connection, one JSON object per line, alongside the
+ * feed's log entries. They are told apart from log entries by their `response` field:
+ *
+ * {"response":"welcome","version":2,"caps":["rooms","dm"],"subject":"Dan","max_frame":65536}
+ * {"response":"rejected","version":2,"reason":"unsupported protocol version 0"}
+ * {"response":"rejected","version":2,"reason":"the name Dan is taken","code":"name-taken"}
+ * {"response":"ack","id":42}
+ * {"response":"error","code":"not-a-member","message":"not in #dev"}
+ * {"response":"pong"}
+ *
+ * * welcome: The INIT was accepted. `version` is the protocol version both sides speak from now
+ *   on and `caps` are the capabilities the server granted (a subset of what the client asked for).
+ *   `subject` is the name the session speaks as, which differs from the INIT's subject if the
+ *   server renamed it (see the `rename` INIT option). `max_frame` is the longest request line (in
+ *   bytes, without the line ending) the server takes; longer ones get a `too-long` error. Servers
+ *   from before it took `MAX_REQUEST_LENGTH`. `epoch` names the log the entry ids are from: a
+ *   client that reconnects sends it back as the `epoch` INIT option along with
+ *   `backfill=since:<id>`, and gets everything instead if the server's log has started over since.
+ *   Servers from before it leave it out.
+ * * rejected: The INIT was refused (e.g. the name is taken) and the server closes the connection.
+ *   `version` is the newest protocol version the server speaks. `code` is an ErrorCode when the
+ *   server has one for the reason: `name-taken` is worth retrying once the name's session is gone,
+ *   `unauthorized` isn't.
+ * * ack: A request was accepted. `id` is the id of the log entry it produced.
+ * * error: A request was refused. `code` is an ErrorCode, `message` is for humans.
+ * * pong: The answer to a PING, for sessions granted the `ping` capability. PINGs get no ack.
+ *
+ * Acks and errors are sent for every request after INIT, in the order the requests were sent, to
+ * session clients that were granted the `acks` capability.
+ *
+ * Servers with an idle timeout hang up on `ping` sessions that go quiet for longer than that, so
+ * those clients should PING well within it (see `peer::server::Server::with_idle_timeout`).
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatResponse {
+    Welcome { version: u32, caps: Vec<String>, subject: String, max_frame: usize, epoch: Option<u64> },
+    Rejected { version: u32, reason: String, code: Option<ErrorCode> },
+    Ack { id: u64 },
+    Error { code: ErrorCode, message: String },
+    Pong,
+}
+
+impl ChatResponse {
+    pub fn to_json(&self) -> String {
+        let value = match self {
+            ChatResponse::Welcome { version, caps, subject, max_frame, epoch } => {
+                let mut value = json!({
+                    "response": "welcome",
+                    "version": version,
+                    "caps": caps,
+                    "subject": subject,
+                    "max_frame": max_frame,
+                });
+                if let Some(epoch) = epoch {
+                    value["epoch"] = json!(epoch);
+                }
+                value
+            },
+            ChatResponse::Rejected { version, reason, code } => {
+                let mut value = json!({
+                    "response": "rejected",
+                    "version": version,
+                    "reason": reason,
+                });
+                if let Some(code) = code {
+                    value["code"] = json!(code.to_string());
+                }
+                value
+            },
+            ChatResponse::Ack { id } => json!({
+                "response": "ack",
+                "id": id,
+            }),
+            ChatResponse::Error { code, message } => json!({
+                "response": "error",
+                "code": code.to_string(),
+                "message": message,
+            }),
+            ChatResponse::Pong => json!({
+                "response": "pong",
+            }),
+        };
+        value.to_string()
+    }
+
+    pub fn from_json(string: &str) -> Option<ChatResponse> {
+        let value: Value = serde_json::from_str(string).ok()?;
+        match value.get("response")?.as_str()? {
+            "welcome" => Some(ChatResponse::Welcome {
+                version: value.get("version")?.as_u64()? as u32,
+                caps: value
+                    .get("caps")?
+                    .as_array()?
+                    .iter()
+                    .filter_map(|cap| cap.as_str())
+                    .map(|cap| cap.to_string())
+                    .collect(),
+                subject: value.get("subject")?.as_str()?.to_string(),
+                max_frame: match value.get("max_frame") {
+                    Some(max_frame) => max_frame.as_u64()? as usize,
+                    None => MAX_REQUEST_LENGTH,
+                },
+                epoch: match value.get("epoch") {
+                    Some(epoch) => Some(epoch.as_u64()?),
+                    None => None,
+                },
+            }),
+            "rejected" => Some(ChatResponse::Rejected {
+                version: value.get("version")?.as_u64()? as u32,
+                reason: value.get("reason")?.as_str()?.to_string(),
+                code: match value.get("code") {
+                    Some(code) => Some(ErrorCode::parse(code.as_str()?)?),
+                    None => None,
+                },
+            }),
+            "ack" => Some(ChatResponse::Ack {
+                id: value.get("id")?.as_u64()?,
+            }),
+            "error" => Some(ChatResponse::Error {
+                code: ErrorCode::parse(value.get("code")?.as_str()?)?,
+                message: value.get("message")?.as_str()?.to_string(),
+            }),
+            "pong" => Some(ChatResponse::Pong),
+            _ => None,
+        }
+    }
+}
+
+// Works out the server's answer to an INIT request. Clients that don't announce a version predate
+// the handshake and get no response at all, so they never see a frame they can't parse.
+pub fn negotiate(init: &ChatRequest) -> Option<ChatResponse> {
+    let requested = init.version()?;
+    let requested_caps = init.capabilities();
+    let response = match requested.parse::<u32>() {
+        Ok(version) if version >= MIN_PROTOCOL_VERSION => ChatResponse::Welcome {
+            // A newer client has to step down to what we speak
+            version: version.min(PROTOCOL_VERSION),
+            caps: CAPABILITIES
+                .iter()
+                .filter(|cap| requested_caps.iter().any(|requested| requested == *cap))
+                .map(|cap| cap.to_string())
+                .collect(),
+            subject: init.subject.clone().unwrap_or_default(),
+            // Servers with another limit put theirs in
+            max_frame: MAX_REQUEST_LENGTH,
+            epoch: None,
+        },
+        _ => ChatResponse::Rejected {
+            version: PROTOCOL_VERSION,
+            reason: format!(
+                "unsupported protocol version {} (server speaks {} to {})",
+                requested, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            code: None,
+        },
+    };
+    Some(response)
+}
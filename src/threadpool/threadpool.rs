@@ -1,51 +1,133 @@
 use std::thread::{self, JoinHandle};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::panic::{self, AssertUnwindSafe};
 
-type ThreadVector = Vec<Option<JoinHandle<()>>>;
-type Threads = Arc<Mutex<ThreadVector>>;
+type Job = Box<dyn FnOnce() + Send + 'static>;
+type JobQueue = Arc<Mutex<Receiver<Job>>>;
+
+/**
+ * Threadpool
+ * ----------
+ * A fixed set of worker threads pulling jobs off a bounded queue. `execute` hands a job to the
+ * queue; if the queue is full, the pool's Overflow policy decides what happens.
+ *
+ * Servers run one job per connection for as long as the connection lives, so a full pool is
+ * normal under load. With `Overflow::Block` the accept loop waits (without spinning) for a worker.
+ *
+ * `shutdown` (or dropping the pool) stops taking jobs, lets the workers finish the queued ones,
+ * and joins them.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    // Wait until a worker frees up
+    Block,
+    // Drop the job and return `ExecuteError::Rejected`
+    Reject,
+    // Start another worker, up to this many in total, then block
+    Grow(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecuteError {
+    // The queue was full and the policy is `Overflow::Reject`
+    Rejected,
+    // The pool was shut down
+    ShutDown,
+}
 
 pub struct Threadpool {
-    threads: Threads,
+    sender: Option<SyncSender<Job>>,
+    queue: JobQueue,
+    workers: Vec<JoinHandle<()>>,
+    overflow: Overflow,
+}
+
+fn lock_queue(queue: &JobQueue) -> MutexGuard<'_, Receiver<Job>> {
+    match queue.lock() {
+        Ok(receiver) => receiver,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Runs jobs until the pool shuts down. A panicking job doesn't take the worker with it.
+fn spawn_worker(queue: JobQueue) -> JoinHandle<()> {
+    thread::spawn(move || {
+        loop {
+            // Only hold the lock while waiting for a job, not while running it
+            let job = lock_queue(&queue).recv();
+            match job {
+                Ok(job) => {
+                    panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_else(|_| {
+                        println!("threadpool job panicked");
+                    });
+                },
+                Err(_) => { break; },
+            }
+        }
+    })
 }
 
 impl Threadpool {
+    // `pool_count` workers, queueing up to `pool_count` jobs, blocking when that's full
     pub fn new(pool_count: usize) -> Threadpool {
-        let mut thread_vector: ThreadVector = Vec::with_capacity(pool_count);
-        for _ in 0..pool_count {
-            thread_vector.push(None);
-        }
-        let threads: Threads = Arc::new(Mutex::new(thread_vector));
+        Threadpool::with_config(pool_count, pool_count, Overflow::Block)
+    }
+
+    pub fn with_config(pool_count: usize, queue_size: usize, overflow: Overflow) -> Threadpool {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let queue: JobQueue = Arc::new(Mutex::new(receiver));
+        let workers = (0..pool_count.max(1)).map(|_| spawn_worker(queue.clone())).collect();
         Threadpool {
-            threads: threads,
+            sender: Some(sender),
+            queue,
+            workers,
+            overflow,
         }
     }
 
-    pub fn execute<F>(&mut self, closure: F)
-    where 
-        F: FnOnce() -> () + Send + 'static
+    pub fn worker_count(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn execute<F>(&mut self, closure: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static
     {
-        let thread_arc: Threads = self.threads.clone();
-        let mut threads: MutexGuard<ThreadVector>  = thread_arc.lock().unwrap();
-        let thread_count: usize = threads.capacity();
-        let mut keep_loop_alive: bool = true;
-        let mut idx_plop: usize = 0;
-        while keep_loop_alive {
-            for idx in 0..thread_count {
-                match &threads[idx] {
-                    Some(thr) => if thr.is_finished() {
-                        idx_plop = idx;
-                        keep_loop_alive = false;
-                        break;
-                    },
-                    _ => { 
-                        idx_plop = idx;
-                        keep_loop_alive = false;
-                        break; 
-                    }
-                }
-            }
+        let sender = match self.sender.as_ref() {
+            Some(sender) => sender,
+            None => { return Err(ExecuteError::ShutDown); },
+        };
+        let job = match sender.try_send(Box::new(closure)) {
+            Ok(()) => { return Ok(()); },
+            Err(TrySendError::Disconnected(_)) => { return Err(ExecuteError::ShutDown); },
+            Err(TrySendError::Full(job)) => job,
+        };
+        match self.overflow {
+            Overflow::Reject => { return Err(ExecuteError::Rejected); },
+            Overflow::Grow(max_workers) if self.workers.len() < max_workers => {
+                self.workers.push(spawn_worker(self.queue.clone()));
+            },
+            Overflow::Grow(_) | Overflow::Block => {},
+        }
+        match sender.send(job) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(ExecuteError::ShutDown),
         }
-        threads[idx_plop] = Some(thread::spawn(closure)); 
     }
 
+    // Stop taking jobs and wait for the workers to finish the ones already queued (BLOCKING)
+    pub fn shutdown(&mut self) {
+        // Workers stop once the queue is empty and the sender is gone
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for Threadpool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
 }
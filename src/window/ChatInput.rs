@@ -1,11 +1,11 @@
 
 use std::{
     sync::{
+        Arc,
         mpsc::Sender,
+        atomic::AtomicUsize,
     },
-    net::{TcpStream},
     io::{
-        Write,
         Error
     }
 };
@@ -17,12 +17,13 @@ use crossterm::{
 };
 
 use crate::{
+    peer::{reconnect::{Rooms, SharedConnection}, e2e::SharedKeyring, shutdown::ShutdownSignal},
+    request::request::MAX_REQUEST_LENGTH,
     window::{
         helpers::*,
         constants::*,
         handlers::{handle_key_codes, handle_modified_keys},
     },
-    request::request::{ChatRequest, ChatRequestStatus, ChatRequestVerb},
 };
 
 /**
@@ -31,6 +32,14 @@ use crate::{
 pub struct ChatInput {
     pub text: String,
     pub name: String,
+    // Set when DMs are end-to-end encrypted (see `peer::e2e`)
+    pub keyring: Option<SharedKeyring>,
+    // Triggered on Ctrl+C, so every client thread stops
+    pub quit: ShutdownSignal,
+    // The longest request line the server takes, from its welcome. It may change when we reconnect.
+    pub max_frame: Arc<AtomicUsize>,
+    // The rooms we joined, to join again when we reconnect
+    pub rooms: Rooms,
     dimensions: Dimensions
 }
 
@@ -48,22 +57,18 @@ impl ChatInput {
         ChatInput {
             text: String::new(),
             name: name.clone(),
+            keyring: None,
+            quit: ShutdownSignal::new(),
+            max_frame: Arc::new(AtomicUsize::new(MAX_REQUEST_LENGTH)),
+            rooms: Rooms::default(),
             dimensions: Dimensions { width: actual_width, height: actual_height },
         }
     }
 
-    // BLOCKING
-    pub fn capture_events(&mut self, socket: &str, tx: Sender<WindowActions>) -> Result<(), Error> {
+    // BLOCKING. Takes the session connection, after the INIT (see `handlers::init_request`) was sent.
+    // Returns once the user quits.
+    pub fn capture_events(&mut self, mut stream: SharedConnection, tx: Sender<WindowActions>) -> Result<(), Error> {
         let start_at_column = 0;
-        let mut stream = TcpStream::connect(socket)?;
-        let request = ChatRequest {
-            subject: Some(self.name.clone()),
-            verb: ChatRequestVerb::INIT,
-            object: Some(String::from("")),
-            status: ChatRequestStatus::Valid
-        };
-        let target_string = request.to_string_opt().unwrap();
-        stream.write(target_string.as_bytes())?;
         while let Ok(ev) = read() {
             match ev {
                 Event::Key(event) => {
@@ -76,6 +81,9 @@ impl ChatInput {
                         start_at_column,
                         self.dimensions.clone()
                     );
+                    if self.quit.is_triggered() {
+                        break;
+                    }
                     handle_key_codes(
                         self,
                         event.modifiers,
@@ -95,6 +103,7 @@ impl ChatInput {
                 _ => { },
             }
         }
+        tx.send(WindowActions::Quit).unwrap_or(());
         Ok(())
     }
 }
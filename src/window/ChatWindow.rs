@@ -1,10 +1,12 @@
 
 use std::{
     io::{Write, stdout},
+    time::Duration,
     vec,
 };
 
 use crate::window::{constants::*, helpers::*};
+use crate::request::{request::ChatRequestVerb, entry::LogEntry};
 
 use crossterm::{
     execute,
@@ -34,6 +36,12 @@ pub struct ChatWindow {
     pub text: Vec<String>,
     pub dimensions: Dimensions,
     pub current_slice: SliceIndex,
+    // Requests sent that the server hasn't acked yet
+    pending: usize,
+    // The last error the server sent back, until the next ack
+    error: Option<String>,
+    // Set while the connection is down, saying what the client is doing about it
+    connection: Option<String>,
 }
 
 /**
@@ -66,7 +74,10 @@ impl ChatWindow {
                 window_width - 2,
                 print_slice
             ),
-            dimensions: Dimensions { width: window_width, height: window_height }
+            dimensions: Dimensions { width: window_width, height: window_height },
+            pending: 0,
+            error: None,
+            connection: None,
         }
     }
 
@@ -91,10 +102,117 @@ impl ChatWindow {
      */
 
      pub fn add_chat_line(&mut self, string: String) {
-        let lines = split_long_line(&string, "  ", self.dimensions.clone());
-        lines.iter().for_each(|line| {
-            self.text.push(line.clone());
-        });
+        self.add_lines(string, false);
+     }
+
+     // Adds a log entry from the feed as `[HH:MM] text`
+     pub fn add_entry(&mut self, entry: &LogEntry) {
+        let is_direct = matches!(
+            entry.verb,
+            ChatRequestVerb::DM | ChatRequestVerb::EDM | ChatRequestVerb::KEY | ChatRequestVerb::NOTICE
+        );
+        self.add_lines(format!("[{}] {}", entry.time_of_day(), entry.render()), is_direct);
+     }
+
+     // Adds an encrypted DM from the feed, with the text we decrypted
+     pub fn add_decrypted(&mut self, entry: &LogEntry, text: String) {
+        let target = entry.to_request().target().unwrap_or_default();
+        self.add_lines(format!("[{}] {} -> {} (encrypted): {}", entry.time_of_day(), entry.subject, target, text), true);
+     }
+
+     // Adds a line about encryption keys, shown like a direct message
+     pub fn add_key_line(&mut self, string: String) {
+        self.add_lines(string, true);
+     }
+
+     /**
+      * Status Actions
+      */
+
+     pub fn request_sent(&mut self) {
+        self.pending += 1;
+        self.print_status();
+     }
+
+     pub fn request_acked(&mut self) {
+        self.pending = self.pending.saturating_sub(1);
+        self.error = None;
+        self.print_status();
+     }
+
+     pub fn request_failed(&mut self, message: String) {
+        self.pending = self.pending.saturating_sub(1);
+        self.error = Some(message);
+        self.print_status();
+     }
+
+     // A request never made it to the server
+     pub fn not_sent(&mut self, message: String) {
+        self.error = Some(message);
+        self.print_status();
+     }
+
+     /**
+      * Connection Actions
+      */
+
+     pub fn disconnected(&mut self) {
+        // Whatever was in flight won't be acked by the next connection
+        self.pending = 0;
+        self.connection = Some(String::from("Disconnected!"));
+        self.print_status();
+     }
+
+     pub fn reconnecting(&mut self, attempt: u32, delay: Duration) {
+        self.connection = Some(format!("Reconnecting in {}s (attempt {})...", delay.as_secs_f32().round(), attempt));
+        self.print_status();
+     }
+
+     // The server turned our reconnect down, so we stop trying
+     pub fn rejected(&mut self, reason: String) {
+        self.add_chat_line(format!("Server rejected the connection: {}", reason));
+        self.connection = Some(String::from("Disconnected! Ctrl+C to quit."));
+        self.print_status();
+     }
+
+     pub fn reconnected(&mut self) {
+        self.connection = None;
+        self.error = None;
+        self.print_status();
+     }
+
+     fn status(&self) -> String {
+        let mut status = format!(">> You are {}!", self.name);
+        if let Some(connection) = self.connection.as_ref() {
+            status = format!("{} {}", status, connection);
+        }
+        if self.pending > 0 {
+            status = format!("{} Sending ({})...", status, self.pending);
+        }
+        if let Some(error) = self.error.as_ref() {
+            status = format!("{} Not sent: {}", status, error);
+        }
+        status
+     }
+
+     fn print_status(&self) {
+        print_header(&mut stdout(), self.status(), self.error.is_some() || self.connection.is_some());
+     }
+
+     fn add_lines(&mut self, string: String, is_direct: bool) {
+        if is_direct {
+            // Direct messages get a marker on every wrapped line so they can be told apart from room traffic
+            let dimensions = Dimensions { width: self.dimensions.width - 1, height: self.dimensions.height };
+            let lines = split_long_line(&string, "  ", dimensions);
+            lines.iter().for_each(|line| {
+                self.text.push(format!("{}{}", DM_MARKER, line));
+            });
+        } else {
+            let lines = split_long_line(&string, "  ", self.dimensions.clone());
+            lines.iter().for_each(|line| {
+                self.text.push(line.clone());
+            });
+        }
         let max_height = self.dimensions.height - 2;
         if self.text.len() < self.dimensions.height {
             self.current_slice.change(&self.text, 0, max_height, self.dimensions.clone());
@@ -106,7 +224,7 @@ impl ChatWindow {
     pub fn print (&self) {
         let mut stdout = stdout();
         reset_screen(&mut stdout);
-        println(&mut stdout, format!(">> You are {}!", self.name.clone()));
+        println(&mut stdout, self.status());
         top_line(&mut stdout, self.dimensions.clone());
         for _ in  0..self.dimensions.clone().height - 2 {
             empty_line(&mut stdout, self.dimensions.clone());
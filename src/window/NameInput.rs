@@ -23,30 +23,71 @@ use crossterm::{
         KeyCode,
         read,
         Event,
-    }
+    },
+    style::{
+        Print,
+        SetForegroundColor,
+        ResetColor,
+    },
 };
 use crate::window::{
     constants::*,
-    helpers::*
+    helpers::*,
+    theme::theme,
 };
 
 pub struct BasicInputPanel {
-    input_text: String
+    input_text: String,
+    question: String,
+    // Password mode: the input is shown as `*`s
+    masked: bool,
+    max_char_count: usize,
+    // Why the server turned down the last name, shown under the panel
+    error: Option<String>,
 }
 
+// Width of the input field in the panel
+const INPUT_WIDTH: usize = 10;
+
 impl BasicInputPanel {
     pub fn new() -> BasicInputPanel {
-        BasicInputPanel { input_text: String::from("") }
+        BasicInputPanel {
+            input_text: String::from(""),
+            question: String::from("What is your name?"),
+            masked: false,
+            max_char_count: INPUT_WIDTH,
+            error: None,
+        }
+    }
+
+    // Asks for a password instead of a name
+    pub fn password() -> BasicInputPanel {
+        BasicInputPanel {
+            input_text: String::from(""),
+            question: String::from("Password?"),
+            masked: true,
+            max_char_count: 64,
+            error: None,
+        }
+    }
+
+    // Show why a name was rejected, so the user can pick another one
+    pub fn show_error(&mut self, error: String) {
+        self.error = Some(error);
+        self.print();
     }
 
     pub fn print(&mut self) {
         let width: usize = 25;
         let mut stdout = stdout();
-        let q = "What is your name?";
-        let right_trim = vec!['_'; 10 - UnicodeWidthStr::width(self.input_text.as_str())];
-        let a = self.input_text.clone();
+        let q = self.question.as_str();
+        let a = match self.masked {
+            true => "*".repeat(self.input_text.chars().count().min(INPUT_WIDTH)),
+            false => self.input_text.clone(),
+        };
+        let right_trim = vec!['_'; INPUT_WIDTH - UnicodeWidthStr::width(a.as_str())];
         let start_q_at = (width - q.len()) / 2;
-        let start_a_at = (width - 10) / 2;
+        let start_a_at = (width - INPUT_WIDTH) / 2;
         queue!(
             stdout,
             MoveTo(0, 0),
@@ -71,7 +112,7 @@ impl BasicInputPanel {
             vec![DOBLE_VERT_EDGE],
             vec![' '; start_q_at],
             String::from(q).chars().collect::<Vec<char>>(),
-            vec![' '; start_q_at + 1],
+            vec![' '; width - start_q_at - q.len()],
             vec![DOBLE_VERT_EDGE],
         ].concat()));
         println(&mut stdout, vec_char_to_string([
@@ -102,6 +143,14 @@ impl BasicInputPanel {
             vec![DOBLE_HORI_EDGE; width],
             vec![BR_CORNER],
         ].concat()));
+        if let Some(error) = self.error.as_ref() {
+            queue!(
+                stdout,
+                SetForegroundColor(theme().error),
+                Print(error),
+                ResetColor,
+            ).unwrap();
+        }
         stdout.flush().expect("fail");
     }
 
@@ -114,20 +163,19 @@ impl BasicInputPanel {
     }
 
     pub fn capture_input(&mut self) -> String {
-        let max_char_count: usize = 10;
+        let max_char_count = self.max_char_count;
         while let Ok(ev) = read() {
             match ev {
                 Event::Key(ev) => {
                     match ev.code {
                         KeyCode::Char(character) => {
-                            if self.input_text.len() < max_char_count {
+                            if self.input_text.chars().count() < max_char_count {
                                 self.input_text = format!("{}{}", self.input_text, character);
                             }
                             self.print();
                         },
                         KeyCode::Backspace => {
-                            if self.input_text.len() > 0 {
-                                self.input_text = self.input_text[0..self.input_text.len() - 1].to_string();
+                            if self.input_text.pop().is_some() {
                                 self.print();
                             }
                         },
@@ -13,6 +13,13 @@ pub const DOBLE_VERT_EDGE: char = '║';
 pub const LVDIV_EDGE: char = '├';
 pub const RVDIV_EDGE: char = '┤';
 
+/**
+ * LINE MARKERS
+ */
+
+// Marks chat-feed lines that belong to a direct message
+pub const DM_MARKER: char = '»';
+
 /**
  * SIZES (Replace these or use as default sizes)
  */
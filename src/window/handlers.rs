@@ -1,22 +1,14 @@
 use std::{
-    process,
-    sync::mpsc::Sender,
-    net::TcpStream,
+    sync::{mpsc::Sender, atomic::Ordering},
     io::{
         stdout,
         Write
     }
 };
 use crossterm::{
-    execute,
     event::{
         KeyModifiers, KeyCode
     },
-    terminal::{
-        Clear,
-        ClearType,
-        disable_raw_mode
-    },
 };
 use crate::window::{
     ChatInput::{
@@ -24,25 +16,207 @@ use crate::window::{
     },
     helpers::*,
 };
-use crate::request::request::{ChatRequest, ChatRequestStatus, ChatRequestVerb};
+use crate::peer::{
+    auth::encode_secret,
+    reconnect::SharedConnection,
+    e2e::{SharedKeyring, lock_keyring, sealed_length},
+};
+use crate::request::{
+    request::{ChatRequest, ChatRequestStatus, ChatRequestVerb, Backfill, escape},
+    response::{PROTOCOL_VERSION, CAPABILITIES},
+};
+
+// The INIT request that opens a session. A screenful of history per room is enough to get going;
+// a client that reconnects asks for everything since the last message it saw, in the log `epoch`
+// (see `peer::reconnect::Resume`).
+pub fn init_request(name: String, password: Option<String>, backfill: Backfill, epoch: Option<u64>) -> ChatRequest {
+    let mut object = format!(
+        "version={} caps={} backfill={}",
+        PROTOCOL_VERSION,
+        CAPABILITIES.join(","),
+        backfill
+    );
+    if let Some(epoch) = epoch {
+        object = format!("{} epoch={}", object, epoch);
+    }
+    if let Some(password) = password {
+        object = format!("{} password={}", object, encode_secret(&password));
+    }
+    ChatRequest {
+        subject: Some(name),
+        verb: ChatRequestVerb::INIT,
+        object: Some(object),
+        status: ChatRequestStatus::Valid
+    }
+}
+
+// Lets the server pick a free name like `Dan2` if the INIT's is taken (see `peer::session`). Only
+// for the first INIT: a reconnect wants the name it was given back.
+pub fn allow_rename(mut init: ChatRequest) -> ChatRequest {
+    init.object = init.object.map(|object| format!("{} rename=auto", object));
+    init
+}
+
+// Publishes our key for encrypted DMs
+pub fn key_request(name: String, keyring: &SharedKeyring) -> ChatRequest {
+    ChatRequest {
+        subject: Some(name),
+        verb: ChatRequestVerb::KEY,
+        object: Some(lock_keyring(keyring).identity.public_hex()),
+        status: ChatRequestStatus::Valid
+    }
+}
+
+// Keeps the session alive on servers that reap quiet connections
+pub fn ping_request(name: String) -> ChatRequest {
+    ChatRequest {
+        subject: Some(name),
+        verb: ChatRequestVerb::PING,
+        object: Some(String::new()),
+        status: ChatRequestStatus::Valid
+    }
+}
+
+// Turn typed input into a request. Supports `/join <room>`, `/part <room>`, `/room <room> <message>`,
+// `/dm <name> <message>` and `/key <name>` (shows their key's fingerprint).
+pub fn request_from_input(name: String, text: String) -> ChatRequest {
+    let (verb, object) = match text.split_once(' ') {
+        Some(("/key", subject)) => (ChatRequestVerb::GETKEY, subject.trim().to_string()),
+        Some(("/join", room)) => (ChatRequestVerb::JOIN, room.to_string()),
+        Some(("/part", room)) => (ChatRequestVerb::PART, room.to_string()),
+        Some(("/room", rest)) => (ChatRequestVerb::ROOMTX, rest.to_string()),
+        Some(("/dm", rest)) => (ChatRequestVerb::DM, rest.to_string()),
+        _ => (ChatRequestVerb::TX, text),
+    };
+    ChatRequest {
+        subject: Some(name),
+        verb,
+        object: Some(object),
+        status: ChatRequestStatus::Valid
+    }
+}
+
+// The length of a request's line, without the line ending, which is what a server's max frame counts
+fn frame_length(request: &ChatRequest) -> usize {
+    request.to_string_opt().map(|line| line.len() - 2).unwrap_or(0)
+}
 
-pub fn handle_modified_keys(cw: &mut ChatInput, modifiers: KeyModifiers, code: KeyCode, stream: &mut TcpStream, start_at_row: u16, start_at_column: u16, dimensions: Dimensions) {
+// Split a message the server wouldn't take in one line of `max_frame` bytes into several it will.
+// Only messages (TX, ROOMTX and DM) are split; anything else that doesn't fit is refused. With
+// `sealed`, DMs are measured as the EDMs they'll go out as (see `seal_direct`).
+pub fn split_request(request: ChatRequest, max_frame: usize, sealed: bool) -> Result<Vec<ChatRequest>, String> {
+    let too_long = format!("too long to send (the server takes {} bytes)", max_frame);
+    let object = request.object.clone().unwrap_or_default();
+    let (prefix, body) = match (request.verb, object.split_once(' ')) {
+        (ChatRequestVerb::TX, _) => (String::new(), object),
+        (ChatRequestVerb::ROOMTX | ChatRequestVerb::DM, Some((target, body))) => (format!("{} ", target), body.to_string()),
+        _ => {
+            return match frame_length(&request) <= max_frame {
+                true => Ok(vec![request]),
+                false => Err(too_long),
+            };
+        },
+    };
+    let sealed = sealed && request.verb == ChatRequestVerb::DM;
+    let with_body = |body: String| ChatRequest {
+        subject: request.subject.clone(),
+        verb: request.verb,
+        object: Some(format!("{}{}", prefix, body)),
+        status: ChatRequestStatus::Valid
+    };
+    // What every part takes before its body, and what each character of the body adds
+    let envelope = frame_length(&with_body(String::new())) + match sealed {
+        true => sealed_length(0),
+        false => 0,
+    };
+    let cost = |character: char| match sealed {
+        true => 2 * character.len_utf8(),
+        false => escape(&character.to_string()).len(),
+    };
+    if envelope + body.chars().map(cost).sum::<usize>() <= max_frame {
+        return Ok(vec![request]);
+    }
+    let mut parts: Vec<String> = vec![];
+    let mut part = String::new();
+    let mut length = envelope;
+    for character in body.chars() {
+        if length + cost(character) > max_frame {
+            if part.is_empty() {
+                return Err(too_long);
+            }
+            parts.push(std::mem::take(&mut part));
+            length = envelope;
+        }
+        length += cost(character);
+        part.push(character);
+    }
+    parts.push(part);
+    Ok(parts.into_iter().map(with_body).collect())
+}
+
+// What to leave in the input after sending only some of a split message's parts: the `unsent`
+// ones, joined back together behind the command they were typed with, so Enter sends just those.
+pub fn unsent_input(unsent: &[ChatRequest]) -> String {
+    let command = match unsent.first().map(|request| request.verb) {
+        Some(ChatRequestVerb::ROOMTX) => "/room ",
+        Some(ChatRequestVerb::DM) => "/dm ",
+        _ => "",
+    };
+    let mut objects = unsent.iter().map(|request| request.object.clone().unwrap_or_default());
+    let mut input = format!("{}{}", command, objects.next().unwrap_or_default());
+    for object in objects {
+        // Every part repeats the room or name
+        let body = match (command.is_empty(), object.split_once(' ')) {
+            (false, Some((_, body))) => body.to_string(),
+            _ => object,
+        };
+        input.push_str(&body);
+    }
+    input
+}
+
+// With E2E on, DMs go out sealed as EDMs. If we don't have the target's key yet, the message waits
+// in the keyring and we ask for the key instead; the feed sends it on once the key arrives.
+pub fn seal_direct(keyring: &SharedKeyring, request: ChatRequest) -> ChatRequest {
+    let (target, body) = match (request.verb, request.target(), request.body()) {
+        (ChatRequestVerb::DM, Some(target), Some(body)) => (target, body),
+        _ => { return request; },
+    };
+    let mut keyring = lock_keyring(keyring);
+    let (verb, object) = match keyring.seal_for(&target, &body) {
+        Some(sealed) => (ChatRequestVerb::EDM, sealed),
+        None => {
+            keyring.wait_for_key(&target, body);
+            (ChatRequestVerb::GETKEY, target)
+        },
+    };
+    ChatRequest {
+        subject: request.subject,
+        verb,
+        object: Some(object),
+        status: ChatRequestStatus::Valid
+    }
+}
+
+pub fn handle_modified_keys(cw: &mut ChatInput, modifiers: KeyModifiers, code: KeyCode, stream: &mut SharedConnection, start_at_row: u16, start_at_column: u16, dimensions: Dimensions) {
     match modifiers {
         KeyModifiers::CONTROL => {
             match code {
                 KeyCode::Char(char) => {
                     match char {
+                        // Quit: say goodbye, then stop every thread (see `main`, which restores the terminal)
                         'c' => {
-                            execute!(stdout(), Clear(ClearType::All)).unwrap();
-                            disable_raw_mode().expect("error with disable raw mode");
                             let request = ChatRequest {
                                 subject: Some(cw.name.clone()),
                                 verb: ChatRequestVerb::END,
                                 object: Some(String::new()),
                                 status: ChatRequestStatus::Valid
                             };
-                            stream.write(request.to_string_opt().unwrap().as_bytes()).expect("problem writing to stream");
-                            process::exit(0x0100);
+                            // The server may be gone already
+                            stream.write_all(request.to_string_opt().unwrap().as_bytes()).unwrap_or(());
+                            // Trigger first, so a feed thread that reconnects in between sees it
+                            cw.quit.trigger();
+                            stream.disconnect();
                         },
                         _ => {}
                     }
@@ -54,7 +228,7 @@ pub fn handle_modified_keys(cw: &mut ChatInput, modifiers: KeyModifiers, code: K
     }
 }
 
-pub fn handle_key_codes(cw: &mut ChatInput, modifiers: KeyModifiers, code: KeyCode, stream: &mut TcpStream,  tx: Sender<WindowActions>, start_at_row: u16, start_at_column: u16, dimensions: Dimensions) {
+pub fn handle_key_codes(cw: &mut ChatInput, modifiers: KeyModifiers, code: KeyCode, stream: &mut SharedConnection,  tx: Sender<WindowActions>, start_at_row: u16, start_at_column: u16, dimensions: Dimensions) {
     match code {
         KeyCode::Char(char) => {
             cw.text = format!("{}{}", cw.text, char);
@@ -110,15 +284,42 @@ pub fn handle_key_codes(cw: &mut ChatInput, modifiers: KeyModifiers, code: KeyCo
             });
         },
         KeyCode::Enter => {
-            let request = ChatRequest {
-                subject: Some(cw.name.clone()),
-                verb: ChatRequestVerb::TX,
-                object: Some(cw.text.clone()),
-                status: ChatRequestStatus::Valid
+            let request = request_from_input(cw.name.clone(), cw.text.clone());
+            // Long messages go out in parts the server takes (see `split_request`)
+            let max_frame = cw.max_frame.load(Ordering::SeqCst);
+            let requests = match split_request(request, max_frame, cw.keyring.is_some()) {
+                Ok(requests) => requests,
+                Err(message) => {
+                    tx.send(WindowActions::NotSent(message)).unwrap_or(());
+                    vec![]
+                },
             };
-            let target_string = request.to_string_opt().unwrap();
-            stream.write(target_string.as_bytes()).expect("write failed");
-            cw.text = "".to_string();
+            let mut sent = 0;
+            for request in requests.iter() {
+                let mut request = request.clone();
+                if let Some(keyring) = cw.keyring.as_ref() {
+                    request = seal_direct(keyring, request);
+                }
+                let target_string = request.to_string_opt().unwrap();
+                match stream.write_all(target_string.as_bytes()) {
+                    Ok(()) => {
+                        cw.rooms.sent(&request);
+                        tx.send(WindowActions::Sent).unwrap_or(());
+                        sent += 1;
+                    },
+                    Err(e) => {
+                        tx.send(WindowActions::NotSent(e.to_string())).unwrap_or(());
+                        break;
+                    },
+                }
+            }
+            // Keep what didn't go out, so it can be sent again once we're reconnected. Parts that
+            // went out already are dropped from it, so they aren't sent twice.
+            match (sent, requests.len()) {
+                (0, _) => {},
+                (sent, all) if sent == all => { cw.text = "".to_string(); },
+                (sent, _) => { cw.text = unsent_input(&requests[sent..]); },
+            }
 
             println_starting_at(
                 &mut stdout(),
@@ -14,17 +14,25 @@ use crossterm::{
     terminal::{
         Clear,
         ClearType,
+        disable_raw_mode,
     },
     cursor::{
+        Show,
         MoveTo,
         MoveToNextLine,
+        SavePosition,
+        RestorePosition,
     },
     style::{
         Print,
+        Color,
+        SetForegroundColor,
+        ResetColor,
     },
 };
 
 use crate::window::constants::*;
+use crate::window::theme::theme;
 use crate::window::ChatWindow::ChatWindow;
 
 pub type SharedChatWindow = Arc<Mutex<ChatWindow>>;
@@ -50,6 +58,13 @@ pub fn reset_screen(stdout: &mut Stdout) {
     ).unwrap();
 }
 
+// Leave the terminal the way we found it: cleared, cursor shown, out of raw mode
+pub fn restore_terminal(stdout: &mut Stdout) {
+    reset_screen(stdout);
+    execute!(stdout, Show).unwrap_or(());
+    disable_raw_mode().unwrap_or(());
+}
+
 // Print in place and move down a line
 pub fn println(stdout: &mut Stdout, string: String) {
     queue!(
@@ -76,6 +91,25 @@ pub fn println_starting_at(stdout: &mut Stdout, string: String, start_at: u16, s
     ).unwrap();
 }
 
+// Print the header line at the top of the screen, leaving the cursor where it was.
+// Errors are shown in the theme's error color.
+pub fn print_header(stdout: &mut Stdout, string: String, is_error: bool) {
+    let color = match is_error {
+        true => theme().error,
+        false => Color::Reset,
+    };
+    execute!(
+        stdout,
+        SavePosition,
+        MoveTo(0, 0),
+        Clear(ClearType::CurrentLine),
+        SetForegroundColor(color),
+        Print(string),
+        ResetColor,
+        RestorePosition,
+    ).unwrap();
+}
+
 // Print multiple lines (from within a chat-feed)
 pub fn printlns(stdout: &mut Stdout, strings: Vec<String>, start_printidx: &mut u16, dimensions: Dimensions) {
     strings.iter().for_each(|string| {
@@ -84,6 +118,11 @@ pub fn printlns(stdout: &mut Stdout, strings: Vec<String>, start_printidx: &mut
             false => dimensions_sans_padding - UnicodeWidthStr::width(string.as_str()),
             true => dimensions_sans_padding
         };
+        // Direct messages are highlighted
+        let color = match string.starts_with(DM_MARKER) {
+            true => theme().direct,
+            false => theme().text,
+        };
         queue!(
             stdout,
             MoveTo(0, *start_printidx),
@@ -91,7 +130,13 @@ pub fn printlns(stdout: &mut Stdout, strings: Vec<String>, start_printidx: &mut
                 [
                     vec![VERT_EDGE],
                     vec![' '; H_PADDING as usize],
-                    string.chars().collect::<Vec<char>>(),
+                ].concat()
+            )),
+            SetForegroundColor(color),
+            Print(string),
+            ResetColor,
+            Print(vec_char_to_string(
+                [
                     vec![' '; max_length],
                     vec![VERT_EDGE],
                 ].concat()
@@ -229,5 +274,11 @@ pub enum WindowActions {
     CursorLeft,
     CursorRight,
     Resize(usize, usize),
+    // A request was written to the server and is waiting for its ack
+    Sent,
+    // A request could not be written (e.g. while reconnecting)
+    NotSent(String),
+    // The user quit (Ctrl+C): stop handling actions
+    Quit,
 }
 
@@ -4,3 +4,4 @@ pub mod NameInput;
 pub mod handlers;
 pub mod helpers;
 pub mod constants;
+pub mod theme;
@@ -0,0 +1,52 @@
+use std::sync::OnceLock;
+use crossterm::style::Color;
+
+/**
+ * Colors of the CLI client. There is one terminal per client, so the theme is set once at
+ * startup (see `set_theme`) and everything drawn afterwards uses it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    // The header, while a request failed or the connection is down
+    pub error: Color,
+    // Direct messages, notices and key lines
+    pub direct: Color,
+    // The rest of the feed
+    pub text: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme { error: Color::Red, direct: Color::Magenta, text: Color::Reset }
+    }
+}
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+impl Theme {
+    // `default`, or `mono` for no colors at all
+    pub fn named(name: &str) -> Option<Theme> {
+        match name {
+            "default" => Some(Theme::default()),
+            "mono" => Some(Theme { error: Color::Reset, direct: Color::Reset, text: Color::Reset }),
+            _ => None,
+        }
+    }
+
+    // A color by name (`red`, `dark_cyan`, ...), or `reset` for the terminal's own
+    pub fn color(name: &str) -> Option<Color> {
+        match name {
+            "reset" => Some(Color::Reset),
+            _ => Color::try_from(name).ok(),
+        }
+    }
+}
+
+// Use `theme` from now on. Only the first call counts.
+pub fn set_theme(theme: Theme) {
+    THEME.set(theme).unwrap_or(());
+}
+
+pub fn theme() -> Theme {
+    THEME.get().copied().unwrap_or_default()
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use chat_service::request::{
+    request::ChatRequestVerb,
+    response::{ChatResponse, ErrorCode},
+};
+use common::Client;
+
+#[test]
+fn every_request_gets_an_ack_or_an_error_in_order() {
+    let (sessions, chat) = common::start_session_server();
+    let mut dan = Client::welcomed(sessions, "Dan", "version=2 caps=acks backfill=live");
+    for line in [
+        "[1:Dan][2:tx][3:one]",
+        "not a request",
+        "[1:Dan][2:roomtx][3:dev not in it]",
+        "[1:Dan][2:notice][3:only the server sends these]",
+        "[1:Dan][2:dm][3:Zed hi]",
+        "[1:Dan][2:join][3:dev]",
+        "[1:Dan][2:roomtx][3:dev in it now]",
+    ] {
+        dan.send(&format!("{}\r\n", line));
+    }
+    let mut responses: Vec<ChatResponse> = vec![];
+    while responses.len() < 7 {
+        responses.push(dan.response());
+    }
+    let codes: Vec<Option<ErrorCode>> = responses
+        .iter()
+        .map(|response| match response {
+            ChatResponse::Error { code, .. } => Some(*code),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(codes, vec![
+        None,
+        Some(ErrorCode::Malformed),
+        Some(ErrorCode::NotAMember),
+        Some(ErrorCode::UnknownVerb),
+        Some(ErrorCode::NotConnected),
+        None,
+        None,
+    ]);
+    let acked: Vec<u64> = responses
+        .iter()
+        .filter_map(|response| match response {
+            ChatResponse::Ack { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+    assert!(acked.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // Ack ids are the ids of the entries the requests were logged as
+    let logs = chat.log.text.lock().unwrap();
+    let last = logs["dev"].last().unwrap();
+    assert_eq!((last.id, last.verb, last.object.as_str()), (acked[2], ChatRequestVerb::ROOMTX, "dev in it now"));
+}
+
+#[test]
+fn sessions_without_acks_are_dropped_on_bad_requests() {
+    let (sessions, _chat) = common::start_session_server();
+    let mut dan = Client::init(sessions, "Dan", "backfill=live");
+    dan.send("not a request\r\n");
+    // EOF well before the read timeout, and no error frame
+    assert!(dan.lines.all(|line| line.is_ok_and(|line| ChatResponse::from_json(&line).is_none())));
+}
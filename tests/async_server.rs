@@ -0,0 +1,83 @@
+#![cfg(feature = "async")]
+
+mod common;
+
+use chat_service::{
+    peer::{
+        async_server::AsyncServer,
+        chatlog::{InMemoryChatBuffer, Submission},
+        server::Server,
+    },
+    request::{entry::LogEntry, request::ChatRequestVerb, response::ChatResponse},
+};
+use common::{Chat, Client};
+use std::{net::SocketAddr, thread};
+
+// An async session server and feed on a shared chatlog. Returns their addresses.
+fn start() -> (SocketAddr, SocketAddr, Chat) {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let (session_server, feed_server) = (AsyncServer::new("127.0.0.1:0"), AsyncServer::new("127.0.0.1:0"));
+    let (sessions, feeds) = (session_server.listen().unwrap(), feed_server.listen().unwrap());
+    let addresses = (sessions.local_addr().unwrap(), feeds.local_addr().unwrap());
+    let (tx, log, feed_log) = (chat.tx.clone(), chat.log.clone(), chat.log.clone());
+    thread::spawn(move || session_server.start_session_on(sessions, 2, tx, log));
+    thread::spawn(move || feed_server.start_feed_on(feeds, 2, feed_log));
+    (addresses.0, addresses.1, chat)
+}
+
+#[test]
+fn sessions_ack_and_see_each_other() {
+    let (sessions, _, _chat) = start();
+    let ann = Client::welcomed(sessions, "Ann", "version=2 caps=acks backfill=live");
+    let mut dan = Client::welcomed(sessions, "Dan", "version=2 caps=acks backfill=live");
+
+    // A taken name is turned down, like on the threaded server
+    let mut other = Client::init(sessions, "dan", "version=2");
+    assert!(matches!(other.response(), ChatResponse::Rejected { .. }));
+
+    dan.send("[1:Dan][2:tx][3:hello]\r\n");
+    let ack = match dan.response() {
+        ChatResponse::Ack { id } => id,
+        response => panic!("unexpected response {:?}", response),
+    };
+    // Dan goes away without an END
+    drop(dan);
+
+    let mut ann_entries = ann.entries();
+    let hello = ann_entries.find(|entry| entry.verb == ChatRequestVerb::TX).unwrap();
+    assert_eq!((hello.id, hello.subject.as_str(), hello.object.as_str()), (ack, "Dan", "hello"));
+    assert!(ann_entries.any(|entry| entry.verb == ChatRequestVerb::END && entry.subject == "Dan"));
+}
+
+#[test]
+fn threaded_and_async_sessions_share_the_chatlog_names() {
+    let (sessions, _, chat) = start();
+    let (threaded, _) = common::start_sessions(Server::new("127.0.0.1:0"), &chat);
+    let _dan = Client::welcomed(threaded, "Dan", "version=2");
+    let _ann = Client::welcomed(sessions, "Ann", "version=2");
+
+    let mut other = Client::init(sessions, "dan", "version=2");
+    assert!(matches!(other.response(), ChatResponse::Rejected { .. }));
+    let mut other = Client::init(threaded, "ANN", "version=2");
+    assert!(matches!(other.response(), ChatResponse::Rejected { .. }));
+}
+
+#[test]
+fn feeds_backfill_and_close_with_the_notice() {
+    let (sessions, feeds, chat) = start();
+    let mut dan = Client::init(sessions, "Dan", "");
+    dan.send("[1:Dan][2:tx][3:before]\r\n");
+    common::wait_until(|| chat.log.text.lock().unwrap().values().flatten().any(|entry| entry.object == "before"));
+
+    // Ann has no session, so the feed port serves her feed
+    common::submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    let ann_feed = Client::open(feeds, "Ann backfill=all\n");
+    // Dan's session, and Ann's feed
+    common::wait_until(|| chat.log.subscribers.stats().len() == 2);
+    chat.tx.send(Submission::Shutdown { notice: String::from("going down") }).unwrap();
+
+    let received: Vec<LogEntry> = ann_feed.entries().collect();
+    assert!(received.iter().any(|entry| entry.object == "before"));
+    let last = received.last().unwrap();
+    assert_eq!((last.verb, last.object.as_str()), (ChatRequestVerb::NOTICE, "going down"));
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use chat_service::{
+    peer::{
+        auth::{Credentials, encode_secret, hash_secret},
+        chatlog::InMemoryChatBuffer,
+        server::Server,
+    },
+    request::{request::ChatRequestVerb, response::ChatResponse},
+};
+use common::{Chat, Client, submit};
+use std::{fs, net::SocketAddr, time::Instant};
+
+// Dan's password is "hunter2"
+fn credentials(test: &str) -> Credentials {
+    let path = common::temp_dir(test).join("credentials");
+    fs::write(&path, format!("Dan {}\n", hash_secret("hunter2").unwrap())).unwrap();
+    Credentials::load(&path).unwrap()
+}
+
+// A chatlog whose feeds want Dan's password, with a DM from Ann waiting for Dan
+fn start(test: &str) -> Chat {
+    let chat = common::start_chat(InMemoryChatBuffer::new().with_credentials(credentials(test)));
+    submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:dm][3:Dan psst]").unwrap();
+    chat
+}
+
+// Handshakes without Dan's password get nothing, and leave Dan's DMs for the one with it
+fn only_dan_gets_the_feed(feed: SocketAddr) {
+    for handshake in ["Dan backfill=all\n", &format!("Dan backfill=all password={}\n", encode_secret("hunter3"))] {
+        let mut feed = Client::open(feed, handshake);
+        assert!(feed.lines.next().is_none());
+    }
+    let feed = Client::open(feed, &format!("Dan backfill=all password={}\n", encode_secret("hunter2")));
+    let dm = feed.entries().find(|entry| entry.verb == ChatRequestVerb::DM).unwrap();
+    assert_eq!((dm.subject.as_str(), dm.object.as_str()), ("Ann", "Dan psst"));
+}
+
+#[test]
+fn feeds_need_the_password() {
+    let chat = start("feeds_need_the_password");
+    only_dan_gets_the_feed(chat.feed);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_feeds_need_the_password() {
+    use chat_service::peer::async_server::AsyncServer;
+    use std::thread;
+
+    let chat = start("async_feeds_need_the_password");
+    let server = AsyncServer::new("127.0.0.1:0").with_credentials(credentials("async_feeds_need_the_password"));
+    let listener = server.listen().unwrap();
+    let feed = listener.local_addr().unwrap();
+    let log = chat.log.clone();
+    thread::spawn(move || server.start_feed_on(listener, 2, log));
+    only_dan_gets_the_feed(feed);
+}
+
+#[test]
+fn sessions_need_the_password() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let server = Server::new("127.0.0.1:0").with_credentials(credentials("sessions_need_the_password"));
+    let (sessions, _) = common::start_sessions(server, &chat);
+
+    let mut wrong = Client::init(sessions, "Dan", &format!("version=2 password={}", encode_secret("hunter3")));
+    assert!(matches!(wrong.response(), ChatResponse::Rejected { .. }));
+    let mut stranger = Client::init(sessions, "Zed", &format!("version=2 password={}", encode_secret("hunter2")));
+    assert!(matches!(stranger.response(), ChatResponse::Rejected { .. }));
+
+    let mut dan = Client::welcomed(sessions, "Dan", &format!("version=2 backfill=all password={}", encode_secret("hunter2")));
+    // The password is never logged
+    let init = dan.entry(ChatRequestVerb::INIT);
+    assert_eq!(init.subject, "Dan");
+    assert!(!init.object.contains("password"));
+}
+
+// Dan reconnects while the server still holds the old session, e.g. after the network dropped
+fn dan_replaces_the_stale_session(sessions: SocketAddr, chat: &Chat) {
+    let options = format!("version=2 caps=acks backfill=live password={}", encode_secret("hunter2"));
+    let stale = Client::welcomed(sessions, "Dan", &options);
+    let mut dan = Client::welcomed(sessions, "Dan", &options);
+
+    // The old connection is hung up on rather than left to time out
+    let started = Instant::now();
+    assert_eq!(stale.responses().count(), 0);
+    assert!(started.elapsed() < common::TIMEOUT);
+
+    // Dan never left, so there is no END for the old session
+    dan.send("[1:Dan][2:tx][3:still here]\r\n");
+    assert!(matches!(dan.response(), ChatResponse::Ack { .. }));
+    assert!(common::is_member(&chat.log, "Dan"));
+    assert!(!chat.log.text.lock().unwrap().values().flatten().any(|entry| entry.verb == ChatRequestVerb::END));
+}
+
+#[test]
+fn authenticated_sessions_replace_stale_ones() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let server = Server::new("127.0.0.1:0").with_credentials(credentials("authenticated_sessions_replace_stale_ones"));
+    let (sessions, _) = common::start_sessions(server, &chat);
+    dan_replaces_the_stale_session(sessions, &chat);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn authenticated_async_sessions_replace_stale_ones() {
+    use chat_service::peer::async_server::AsyncServer;
+    use std::thread;
+
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let server = AsyncServer::new("127.0.0.1:0").with_credentials(credentials("authenticated_async_sessions_replace_stale_ones"));
+    let listener = server.listen().unwrap();
+    let sessions = listener.local_addr().unwrap();
+    let (tx, log) = (chat.tx.clone(), chat.log.clone());
+    thread::spawn(move || server.start_session_on(listener, 2, tx, log));
+    dan_replaces_the_stale_session(sessions, &chat);
+}
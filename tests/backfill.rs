@@ -0,0 +1,81 @@
+mod common;
+
+use chat_service::{
+    peer::chatlog::{FeedCursor, InMemoryChatBuffer},
+    request::{entry::LogEntry, request::{Backfill, ChatRequestVerb}},
+};
+use common::{Chat, Client, submit};
+
+// Dan in the lobby and #dev, with a couple of messages in each
+fn chat_with_history() -> (Chat, Vec<u64>) {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let ids = [
+        "[1:Dan][2:init][3:]",
+        "[1:Dan][2:tx][3:one]",
+        "[1:Dan][2:join][3:dev]",
+        "[1:Dan][2:roomtx][3:dev two]",
+        "[1:Dan][2:tx][3:three]",
+    ].iter().map(|line| submit(&chat.tx, line).unwrap()).collect();
+    (chat, ids)
+}
+
+fn ids(entries: Vec<LogEntry>) -> Vec<u64> {
+    entries.into_iter().map(|entry| entry.id).collect()
+}
+
+// What a feed for Ann gets once she has joined both rooms, with the backfill `pick` makes of the
+// history's ids
+fn backfilled(pick: impl FnOnce(&[u64]) -> Backfill) -> (Vec<u64>, Vec<u64>) {
+    let (chat, history) = chat_with_history();
+    let mut ann = FeedCursor::new("Ann", pick(&history), &chat.log);
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:join][3:dev]").unwrap();
+    (history, ids(common::next_batch(&mut ann, &chat.log)))
+}
+
+#[test]
+fn all_sends_every_room_in_full() {
+    let (history, batch) = backfilled(|_| Backfill::All);
+    assert_eq!(&batch[..5], &history[..]);
+    assert_eq!(batch.len(), 7);
+}
+
+#[test]
+fn since_sends_what_came_after_an_id() {
+    let (history, batch) = backfilled(|history| Backfill::Since(history[1]));
+    assert_eq!(&batch[..3], &history[2..]);
+    assert_eq!(batch.len(), 5);
+}
+
+#[test]
+fn last_sends_the_newest_of_each_room() {
+    let (history, batch) = backfilled(|_| Backfill::Last(1));
+    // Ann's INIT and JOIN are the newest in their rooms
+    assert!(batch.iter().all(|id| !history.contains(id)));
+    assert_eq!(batch.len(), 2);
+}
+
+#[test]
+fn live_starts_at_the_feed_even_in_rooms_it_sees_later() {
+    let (chat, history) = chat_with_history();
+    // Most likely created in the same millisecond as the last of the history
+    let mut ann = FeedCursor::new("Ann", Backfill::Live, &chat.log);
+    let init = submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    let later = submit(&chat.tx, "[1:Dan][2:roomtx][3:dev four]").unwrap();
+    assert_eq!(ids(common::next_batch(&mut ann, &chat.log)), vec![init]);
+
+    // #dev is new to the feed: what was logged there before the feed started stays out
+    let join = submit(&chat.tx, "[1:Ann][2:join][3:dev]").unwrap();
+    let batch = ids(common::next_batch(&mut ann, &chat.log));
+    assert_eq!(batch, vec![later, join]);
+    assert!(batch.iter().all(|id| !history.contains(id)));
+}
+
+#[test]
+fn live_sessions_see_their_own_init() {
+    let (sessions, chat) = common::start_session_server();
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    let dan = Client::init(sessions, "Dan", "backfill=live");
+    let first = dan.entries().next().unwrap();
+    assert_eq!((first.subject.as_str(), first.verb), ("Dan", ChatRequestVerb::INIT));
+}
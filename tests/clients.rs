@@ -0,0 +1,77 @@
+mod common;
+
+use chat_service::{
+    peer::{
+        chatlog::InMemoryChatBuffer,
+        server::Server,
+    },
+    request::response::{ChatResponse, ErrorCode},
+};
+use common::{Chat, Client};
+use std::net::SocketAddr;
+
+// A chatlog with a session server and a legacy client server. Returns their addresses.
+fn start() -> (SocketAddr, SocketAddr, Chat) {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let (sessions, _) = common::start_sessions(Server::new("127.0.0.1:0"), &chat);
+    let (clients, _) = common::start_clients(Server::new("127.0.0.1:0"), &chat);
+    (sessions, clients, chat)
+}
+
+fn error_code(client: &mut Client) -> ErrorCode {
+    match client.response() {
+        ChatResponse::Error { code, .. } => code,
+        response => panic!("unexpected response {:?}", response),
+    }
+}
+
+fn logged(chat: &Chat, object: &str) -> bool {
+    chat.log.text.lock().unwrap().values().flatten().any(|entry| entry.object == object)
+}
+
+#[test]
+fn clients_cannot_speak_as_someone_in_a_session() {
+    let (sessions, clients, chat) = start();
+    let mut dan = Client::welcomed(sessions, "Dan", "version=2 caps=acks backfill=live");
+
+    let mut impostor = Client::open(clients, "[1:dan][2:tx][3:not me]\r\n");
+    assert_eq!(error_code(&mut impostor), ErrorCode::NameTaken);
+    assert!(impostor.lines.next().is_none());
+
+    // Dan is still there, and nothing was logged for the impostor
+    dan.send("[1:Dan][2:tx][3:still me]\r\n");
+    assert!(matches!(dan.response(), ChatResponse::Ack { .. }));
+    assert!(logged(&chat, "still me"));
+    assert!(!logged(&chat, "not me"));
+}
+
+#[test]
+fn clients_are_pinned_to_their_first_subject() {
+    let (sessions, clients, chat) = start();
+    let mut bob = Client::open(clients, "[1:Bob][2:init][3:]\r\n");
+    common::wait_until(|| common::is_member(&chat.log, "Bob"));
+
+    // Nobody else can have the name meanwhile
+    let mut session = Client::init(sessions, "bob", "version=2");
+    assert!(matches!(session.response(), ChatResponse::Rejected { .. }));
+
+    bob.send("[1:Dan][2:tx][3:as Dan]\r\n");
+    assert_eq!(error_code(&mut bob), ErrorCode::Unauthorized);
+    assert!(bob.lines.next().is_none());
+    assert!(!logged(&chat, "as Dan"));
+
+    // Bob is ENDed for the connection, and the name is free again
+    common::wait_until(|| !common::is_member(&chat.log, "Bob") && !chat.log.names.is_active("Bob"));
+    Client::welcomed(sessions, "Bob", "version=2");
+}
+
+#[test]
+fn an_end_frees_the_name_for_the_next_subject() {
+    let (_, clients, chat) = start();
+    let mut client = Client::open(clients, "[1:Bob][2:init][3:]\r\n");
+    client.send("[1:Bob][2:end][3:]\r\n");
+    client.send("[1:Ann][2:init][3:]\r\n");
+    common::wait_until(|| common::is_member(&chat.log, "Ann"));
+    assert!(chat.log.names.is_active("Ann"));
+    assert!(!chat.log.names.is_active("Bob"));
+}
@@ -0,0 +1,187 @@
+// Setup shared by the integration tests. Servers listen on ports the OS picks, and are ready as
+// soon as they're started: the listener is bound before the accept loop's thread starts, so
+// connections queue up until it runs.
+#![allow(dead_code)]
+
+use chat_service::{
+    peer::{
+        chatlog::{ChatLogHandle, FeedBatch, FeedCursor, InMemoryChatBuffer, Outcome, Submission, create_listening_threads_on},
+        logstore::ChatStore,
+        server::Server,
+    },
+    request::{entry::LogEntry, request::{ChatRequest, ChatRequestVerb}, response::ChatResponse},
+};
+use rustls::ServerConfig;
+use std::{
+    io::{BufRead, BufReader, Error, Lines, Write},
+    fs,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{Arc, mpsc::{self, Sender}},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How long a test waits for anything: a line, an outcome, or a condition to come true
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+// A running chatlog and its feed listener
+pub struct Chat<S: ChatStore = Vec<LogEntry>> {
+    pub log: ChatLogHandle<S>,
+    pub tx: Sender<Submission>,
+    // Where the feed listens
+    pub feed: SocketAddr,
+    pub chatlog: JoinHandle<Result<(), Error>>,
+    pub feeds: JoinHandle<Result<(), Error>>,
+}
+
+pub fn local() -> TcpListener {
+    TcpListener::bind("127.0.0.1:0").unwrap()
+}
+
+pub fn start_chat<S: ChatStore>(chat_buffer: InMemoryChatBuffer<S>) -> Chat<S> {
+    start_chat_with_tls(chat_buffer, None)
+}
+
+pub fn start_chat_with_tls<S: ChatStore>(chat_buffer: InMemoryChatBuffer<S>, tls: Option<Arc<ServerConfig>>) -> Chat<S> {
+    let listener = local();
+    let feed = listener.local_addr().unwrap();
+    let log = chat_buffer.create_handle();
+    let (chatlog, feeds, tx) = create_listening_threads_on(chat_buffer, listener, tls);
+    Chat { log, tx, feed, chatlog, feeds }
+}
+
+// A server made with `Server::new("127.0.0.1:0")`, accepting sessions for `chat`
+pub fn start_sessions<S: ChatStore>(server: Server, chat: &Chat<S>) -> (SocketAddr, JoinHandle<Result<(), Error>>) {
+    let listener = server.listen().unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, log) = (chat.tx.clone(), chat.log.clone());
+    (address, thread::spawn(move || server.start_session_on(listener, 4, tx, log)))
+}
+
+// A server made with `Server::new("127.0.0.1:0")`, accepting legacy clients for `chat`
+pub fn start_clients<S: ChatStore>(server: Server, chat: &Chat<S>) -> (SocketAddr, JoinHandle<Result<(), Error>>) {
+    let listener = server.listen().unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, log) = (chat.tx.clone(), chat.log.clone());
+    (address, thread::spawn(move || server.start_on(listener, 4, tx, log)))
+}
+
+// A chatlog with a plain session server
+pub fn start_session_server() -> (SocketAddr, Chat) {
+    let chat = start_chat(InMemoryChatBuffer::new());
+    let (address, _) = start_sessions(Server::new("127.0.0.1:0"), &chat);
+    (address, chat)
+}
+
+// An empty directory of its own for the test called `name`
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("chat-service-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&dir).unwrap_or(());
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Submit a request line straight to the chatlog and wait until it has been applied
+pub fn submit(tx: &Sender<Submission>, line: &str) -> Outcome {
+    let (reply, outcome) = mpsc::channel();
+    let request = ChatRequest::from(line.to_string());
+    tx.send(Submission::Request { request, reply: Some(reply) }).unwrap();
+    outcome.recv_timeout(TIMEOUT).unwrap()
+}
+
+// Poll until `check` comes up with something, failing the test after TIMEOUT
+pub fn wait_for<T>(mut check: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(found) = check() {
+            return found;
+        }
+        assert!(Instant::now() < deadline, "timed out waiting");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+pub fn wait_until(mut check: impl FnMut() -> bool) {
+    wait_for(|| check().then_some(()))
+}
+
+// What a feed would write next. The feed must not have ended.
+pub fn next_batch<S: ChatStore>(cursor: &mut FeedCursor, log: &ChatLogHandle<S>) -> Vec<LogEntry> {
+    match cursor.next_batch(log) {
+        FeedBatch::Entries(entries) | FeedBatch::Closing(entries) => entries,
+        FeedBatch::Ended => panic!("the feed ended"),
+    }
+}
+
+// Whether the chatlog has `subject` in any room
+pub fn is_member<S: ChatStore>(log: &ChatLogHandle<S>, subject: &str) -> bool {
+    log.members.lock().unwrap().contains_key(subject)
+}
+
+// One connection to a server, read line by line
+pub struct Client {
+    pub stream: TcpStream,
+    pub lines: Lines<BufReader<TcpStream>>,
+}
+
+impl Client {
+    // Connect and send `first_line` as it is
+    pub fn open(address: SocketAddr, first_line: &str) -> Client {
+        let stream = TcpStream::connect(address).unwrap();
+        let reader = stream.try_clone().unwrap();
+        reader.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut client = Client { stream, lines: BufReader::new(reader).lines() };
+        client.send(first_line);
+        client
+    }
+
+    // A session that INITs as `name` with `options`
+    pub fn init(address: SocketAddr, name: &str, options: &str) -> Client {
+        Client::open(address, &format!("[1:{}][2:init][3:{}]\r\n", name, options))
+    }
+
+    // A session that INITs with a handshake in `options` and is welcomed
+    pub fn welcomed(address: SocketAddr, name: &str, options: &str) -> Client {
+        let mut client = Client::init(address, name, options);
+        assert!(matches!(client.response(), ChatResponse::Welcome { .. }));
+        client
+    }
+
+    pub fn send(&mut self, line: &str) {
+        self.stream.write_all(line.as_bytes()).unwrap();
+    }
+
+    pub fn line(&mut self) -> String {
+        self.lines.next().unwrap().unwrap()
+    }
+
+    // The next response frame, skipping feed lines
+    pub fn response(&mut self) -> ChatResponse {
+        loop {
+            if let Some(response) = ChatResponse::from_json(&self.line()) {
+                return response;
+            }
+        }
+    }
+
+    // The next entry with `verb`, skipping everything else
+    pub fn entry(&mut self, verb: ChatRequestVerb) -> LogEntry {
+        loop {
+            match LogEntry::from_json(&self.line()) {
+                Some(entry) if entry.verb == verb => { return entry; },
+                _ => {},
+            }
+        }
+    }
+
+    // Every response frame until the connection ends or goes quiet
+    pub fn responses(self) -> impl Iterator<Item = ChatResponse> {
+        self.lines.map_while(|line| line.ok()).filter_map(|line| ChatResponse::from_json(&line))
+    }
+
+    // Every feed entry until the connection ends or goes quiet
+    pub fn entries(self) -> impl Iterator<Item = LogEntry> {
+        self.lines.map_while(|line| line.ok()).filter_map(|line| LogEntry::from_json(&line))
+    }
+}
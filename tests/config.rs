@@ -0,0 +1,144 @@
+mod common;
+
+use chat_service::{
+    config::{
+        client::{CLIENT_FLAGS, ClientProfile},
+        flags::Flags,
+        server::{SERVER_FLAGS, ServerSettings, Storage},
+    },
+    peer::{chatlog::InMemoryChatBuffer, subscribers::Laggards},
+    request::{entry::LogEntry, request::ChatRequestVerb},
+    window::theme::Theme,
+};
+use crossterm::style::Color;
+use common::Client;
+use std::path::{Path, PathBuf};
+
+fn flags(spec: &[chat_service::config::flags::Flag], args: &[&str]) -> Result<Flags, String> {
+    Flags::parse(spec, args.iter().map(|arg| arg.to_string()))
+}
+
+#[test]
+fn shipped_configs_are_the_defaults() {
+    let settings = ServerSettings::load(Path::new("config/server.toml")).unwrap();
+    let defaults = ServerSettings::default();
+    assert_eq!(settings.session_socket, defaults.session_socket);
+    assert_eq!(settings.legacy, defaults.legacy);
+    assert_eq!(settings.storage, Storage::Memory);
+    assert_eq!(settings.idle_timeout, defaults.idle_timeout);
+    assert_eq!(settings.rate_limit, defaults.rate_limit);
+    assert_eq!(settings.feed_limits, defaults.feed_limits);
+    assert_eq!(settings.feed_stats, defaults.feed_stats);
+    assert_eq!(settings.feed_executors, defaults.feed_executors);
+
+    let profile = ClientProfile::load(Path::new("config/client.toml")).unwrap();
+    assert_eq!(profile.server, "0.0.0.0:7000");
+    assert_eq!(profile.rename, ClientProfile::default().rename);
+    assert_eq!(profile.theme, Theme::default());
+}
+
+#[test]
+fn server_files_are_read_and_checked() {
+    let settings = ServerSettings::parse(r#"
+        motd = "hi"
+        feed_stats_secs = 0
+        [listen]
+        session = "127.0.0.1:7777"
+        [storage]
+        store = "ring:50"
+        [limits]
+        idle_timeout_secs = 0
+        rate_burst = 0
+        laggards = "drop"
+    "#).unwrap();
+    assert_eq!(settings.motd.as_deref(), Some("hi"));
+    assert_eq!(settings.feed_stats, None);
+    assert_eq!(settings.session_socket, "127.0.0.1:7777");
+    assert_eq!(settings.feed_socket, "0.0.0.0:8000");
+    assert_eq!(settings.storage, Storage::Ring(50));
+    assert_eq!(settings.idle_timeout, None);
+    assert!(settings.rate_limit.is_none());
+    assert_eq!(settings.feed_limits.laggards, Laggards::Drop);
+
+    // Typos, wrong types and half a TLS setup are all turned down
+    assert_eq!(ServerSettings::parse("[listen]\nsesion = \"x\"").unwrap_err(), "unknown key listen.sesion");
+    assert_eq!(ServerSettings::parse("[executors]\nclient = \"20\"").unwrap_err(), "executors.client must be a whole number");
+    assert_eq!(ServerSettings::parse("[limits]\nlaggards = \"ignore\"").unwrap_err(), "limits.laggards must be `disconnect` or `drop`");
+    assert!(ServerSettings::parse("[tls]\ncert = \"cert.pem\"").is_err());
+    assert_eq!(ServerSettings::parse("[executors]\nfeed = 0").unwrap_err(), "every server needs at least one executor");
+    assert_eq!(ServerSettings::parse("[executors]\nfeed = 5").unwrap().feed_executors, 5);
+    // So are limits the servers can't work with
+    for limits in ["max_frame = 0", "rate_per_second = 0.0", "rate_per_second = -2.0", "rate_per_second = nan"] {
+        assert!(ServerSettings::parse(&format!("[limits]\n{}", limits)).is_err(), "{}", limits);
+    }
+    // Unless rate limits are off
+    assert!(ServerSettings::parse("[limits]\nrate_burst = 0\nrate_per_second = 0.0").is_ok());
+    assert!(ServerSettings::parse("[listen\n").is_err());
+}
+
+#[test]
+fn flags_override_the_file() {
+    let mut settings = ServerSettings::parse("[listen]\nclient = \"0.0.0.0:1\"\nfeed = \"0.0.0.0:2\"").unwrap();
+    let given = flags(SERVER_FLAGS, &["--client", "0.0.0.0:3", "--store=test/logs", "--executors", "4"]).unwrap();
+    settings.apply_flags(&given).unwrap();
+    assert_eq!(settings.client_socket, "0.0.0.0:3");
+    assert_eq!(settings.feed_socket, "0.0.0.0:2");
+    assert_eq!(settings.storage, Storage::Directory(PathBuf::from("test/logs")));
+    assert_eq!((settings.client_executors, settings.session_executors), (4, 4));
+
+    assert!(flags(SERVER_FLAGS, &["--help"]).unwrap().help);
+    assert_eq!(flags(SERVER_FLAGS, &["--bogus"]).unwrap_err(), "unknown flag --bogus (see --help)");
+    assert_eq!(flags(SERVER_FLAGS, &["--client"]).unwrap_err(), "--client needs a value");
+    assert!(flags(SERVER_FLAGS, &["0.0.0.0:9000"]).is_err());
+    let bad_count = flags(SERVER_FLAGS, &["--executors", "many"]).unwrap();
+    assert!(ServerSettings::default().apply_flags(&bad_count).is_err());
+}
+
+#[test]
+fn credentials_turn_the_legacy_listeners_off_unless_asked_for() {
+    assert!(ServerSettings::default().legacy_listeners());
+    let locked = ServerSettings::parse("credentials = \"creds.txt\"").unwrap();
+    assert!(!locked.legacy_listeners());
+    let mut asked = ServerSettings::parse("credentials = \"creds.txt\"\n[listen]\nlegacy = true").unwrap();
+    assert!(asked.legacy_listeners());
+
+    asked.legacy = None;
+    asked.apply_flags(&flags(SERVER_FLAGS, &["--legacy"]).unwrap()).unwrap();
+    assert!(asked.legacy_listeners());
+    assert!(!ServerSettings::parse("[listen]\nlegacy = false").unwrap().legacy_listeners());
+}
+
+#[test]
+fn client_profiles_pick_a_theme_and_take_flags() {
+    let mut profile = ClientProfile::parse(r#"
+        server = "chat.example:7000"
+        nickname = "Dan"
+        [window]
+        width = 80
+        [theme]
+        base = "mono"
+        direct = "dark_cyan"
+    "#).unwrap();
+    assert_eq!(profile.nickname.as_deref(), Some("Dan"));
+    assert_eq!((profile.width, profile.height), (Some(80), None));
+    assert_eq!(profile.theme, Theme { error: Color::Reset, direct: Color::DarkCyan, text: Color::Reset });
+
+    assert!(!profile.rename);
+    profile.apply_flags(&flags(CLIENT_FLAGS, &["--password", "--rename", "--nickname", "Ann", "--theme", "default"]).unwrap()).unwrap();
+    assert!(profile.password && profile.rename);
+    assert_eq!(profile.nickname.as_deref(), Some("Ann"));
+    assert_eq!(profile.theme, Theme::default());
+    assert_eq!(flags(CLIENT_FLAGS, &["--password=yes"]).unwrap_err(), "--password doesn't take a value");
+    assert!(ClientProfile::parse("[theme]\nerror = \"blurple\"").is_err());
+}
+
+#[test]
+fn feeds_end_their_first_batch_with_the_motd() {
+    let chat = common::start_chat(InMemoryChatBuffer::new().with_motd(String::from("be nice")));
+    common::submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+
+    let feed = Client::open(chat.feed, "Dan backfill=all\n");
+    let entries: Vec<LogEntry> = feed.entries().take(2).collect();
+    assert_eq!(entries[0].verb, ChatRequestVerb::INIT);
+    assert_eq!((entries[1].id, entries[1].verb, entries[1].object.as_str()), (0, ChatRequestVerb::NOTICE, "be nice"));
+}
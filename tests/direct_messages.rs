@@ -0,0 +1,116 @@
+mod common;
+
+use chat_service::{
+    peer::chatlog::{FeedBatch, FeedCursor, InMemoryChatBuffer},
+    request::{request::{Backfill, ChatRequestVerb}, response::ErrorCode},
+};
+use common::{Client, submit};
+
+#[test]
+fn dms_reach_the_target_and_echo_to_the_sender_only() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    for name in ["Ann", "Bob", "Dan"] {
+        submit(&chat.tx, &format!("[1:{}][2:init][3:]", name)).unwrap();
+    }
+    let mut cursors: Vec<FeedCursor> = ["Ann", "Bob", "Dan"].iter().map(|name| FeedCursor::new(name, Backfill::Live, &chat.log)).collect();
+    for cursor in cursors.iter_mut() {
+        common::next_batch(cursor, &chat.log);
+    }
+
+    let id = submit(&chat.tx, "[1:Dan][2:dm][3:Ann psst]").unwrap();
+    let batches: Vec<_> = cursors.iter_mut().map(|cursor| common::next_batch(cursor, &chat.log)).collect();
+    for reader in [0, 2] {
+        let dm = &batches[reader];
+        assert_eq!(dm.len(), 1);
+        assert_eq!((dm[0].id, dm[0].verb, dm[0].subject.as_str(), dm[0].object.as_str()), (id, ChatRequestVerb::DM, "Dan", "Ann psst"));
+        assert_eq!(dm[0].room, None);
+    }
+    assert!(batches[1].is_empty());
+    // DMs are never logged to a room
+    assert!(chat.log.text.lock().unwrap().values().flatten().all(|entry| entry.verb != ChatRequestVerb::DM));
+}
+
+#[test]
+fn dms_to_someone_not_connected_get_a_notice() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    let mut dan = FeedCursor::new("Dan", Backfill::Live, &chat.log);
+    common::next_batch(&mut dan, &chat.log);
+
+    assert_eq!(submit(&chat.tx, "[1:Dan][2:dm][3:Zed are you there?]"), Err(ErrorCode::NotConnected));
+    // A DM needs a target and a body
+    assert_eq!(submit(&chat.tx, "[1:Dan][2:dm][3:Zed]"), Err(ErrorCode::Malformed));
+
+    let notices = common::next_batch(&mut dan, &chat.log);
+    assert_eq!(notices.len(), 1);
+    assert_eq!((notices[0].verb, notices[0].object.as_str()), (ChatRequestVerb::NOTICE, "Zed is not connected"));
+}
+
+#[test]
+fn undelivered_dms_are_dropped_when_the_target_leaves() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:dm][3:Ann you there?]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:end][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+
+    // The next session under the name doesn't get the last one's messages
+    let mut ann = FeedCursor::new("Ann", Backfill::Live, &chat.log);
+    assert!(common::next_batch(&mut ann, &chat.log).iter().all(|entry| entry.verb != ChatRequestVerb::DM));
+}
+
+#[test]
+fn every_feed_for_a_subject_gets_every_dm() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    let mut feeds = [FeedCursor::new("Dan", Backfill::Live, &chat.log), FeedCursor::new("Dan", Backfill::Live, &chat.log)];
+
+    submit(&chat.tx, "[1:Ann][2:dm][3:Dan one]").unwrap();
+    let first = common::next_batch(&mut feeds[0], &chat.log);
+    submit(&chat.tx, "[1:Ann][2:dm][3:Dan two]").unwrap();
+    for feed in feeds.iter_mut() {
+        let dms: Vec<String> = common::next_batch(feed, &chat.log).into_iter().chain(first.clone()).map(|entry| entry.object).collect();
+        assert!(dms.contains(&String::from("Dan one")) && dms.contains(&String::from("Dan two")));
+    }
+}
+
+#[test]
+fn dms_for_a_session_only_reach_its_feed() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    let claim = chat.log.names.claim("Dan").unwrap();
+    let mut session = FeedCursor::new("Dan", Backfill::Live, &chat.log).with_claim(claim.id());
+    submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:dm][3:Dan for the first session]").unwrap();
+
+    // Nobody else can read them under the name
+    let mut stranger = FeedCursor::new("Dan", Backfill::Live, &chat.log);
+    assert!(matches!(stranger.next_batch(&chat.log), FeedBatch::Ended));
+
+    // The session ends without its feed having caught up, and the name goes to the next one
+    drop(claim);
+    let claim = chat.log.names.claim("Dan").unwrap();
+    let mut next = FeedCursor::new("Dan", Backfill::Live, &chat.log).with_claim(claim.id());
+    submit(&chat.tx, "[1:Ann][2:dm][3:Dan for the next session]").unwrap();
+    assert!(matches!(session.next_batch(&chat.log), FeedBatch::Ended));
+    let dms: Vec<String> = common::next_batch(&mut next, &chat.log).into_iter()
+        .filter(|entry| entry.verb == ChatRequestVerb::DM)
+        .map(|entry| entry.object)
+        .collect();
+    assert_eq!(dms, ["Dan for the next session"]);
+}
+
+#[test]
+fn feed_handshakes_are_refused_for_a_name_in_a_session() {
+    let (sessions, chat) = common::start_session_server();
+    let mut dan = Client::welcomed(sessions, "Dan", "version=2 backfill=live");
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    common::wait_until(|| common::is_member(&chat.log, "Dan"));
+
+    let mut feed = Client::open(chat.feed, "Dan backfill=all\n");
+    assert!(feed.lines.next().is_none());
+    submit(&chat.tx, "[1:Ann][2:dm][3:Dan psst]").unwrap();
+    assert_eq!(dan.entry(ChatRequestVerb::DM).object, "Dan psst");
+}
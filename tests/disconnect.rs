@@ -0,0 +1,17 @@
+mod common;
+
+use chat_service::request::request::ChatRequestVerb;
+use common::Client;
+
+#[test]
+fn a_session_that_vanishes_still_ends() {
+    let (sessions, chat) = common::start_session_server();
+    let ann = Client::init(sessions, "Ann", "");
+    let dan = Client::init(sessions, "Dan", "");
+    common::wait_until(|| common::is_member(&chat.log, "Ann") && common::is_member(&chat.log, "Dan"));
+    // No END: the client just goes away
+    drop(dan);
+
+    let ended = ann.entries().any(|entry| entry.verb == ChatRequestVerb::END && entry.subject == "Dan");
+    assert!(ended);
+}
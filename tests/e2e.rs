@@ -0,0 +1,110 @@
+mod common;
+
+use chat_service::{
+    peer::e2e::{Identity, Keyring, KeyChange, Sealed, parse_public_key},
+    request::{request::ChatRequestVerb, response::{ChatResponse, ErrorCode}},
+};
+use common::Client;
+use std::net::SocketAddr;
+
+fn connect(sessions: SocketAddr, name: &str) -> Client {
+    Client::welcomed(sessions, name, "version=2 caps=acks,e2e backfill=live")
+}
+
+fn publish(client: &mut Client, name: &str, keyring: &Keyring) {
+    client.send(&format!("[1:{}][2:key][3:{}]\r\n", name, keyring.identity.public_hex()));
+    assert!(matches!(client.response(), ChatResponse::Ack { .. }));
+}
+
+// Ask the server for `name`'s key and pin it
+fn fetch(client: &mut Client, me: &str, name: &str, keyring: &mut Keyring) {
+    client.send(&format!("[1:{}][2:getkey][3:{}]\r\n", me, name));
+    let key = client.entry(ChatRequestVerb::KEY);
+    assert_eq!(key.subject, name);
+    assert_eq!(keyring.learn(name, parse_public_key(&key.object).unwrap()), KeyChange::New);
+}
+
+#[test]
+fn encrypted_dm_is_relayed_but_not_readable_by_the_server() {
+    let (sessions, chat) = common::start_session_server();
+    let mut ann = connect(sessions, "Ann");
+    let mut bob = connect(sessions, "Bob");
+    let mut ann_keys = Keyring::new(Identity::generate());
+    let mut bob_keys = Keyring::new(Identity::generate());
+    publish(&mut ann, "Ann", &ann_keys);
+    publish(&mut bob, "Bob", &bob_keys);
+    fetch(&mut ann, "Ann", "Bob", &mut ann_keys);
+    fetch(&mut bob, "Bob", "Ann", &mut bob_keys);
+
+    let plaintext = "the password is hunter2";
+    ann.send(&format!("[1:Ann][2:edm][3:{}]\r\n", ann_keys.seal_for("Bob", plaintext).unwrap()));
+    let received = bob.entry(ChatRequestVerb::EDM);
+    assert!(!received.object.contains(plaintext));
+    assert_eq!(bob_keys.open_entry(&received, "Bob").unwrap(), plaintext);
+    // The sender can read its own echo
+    let echo = ann.entry(ChatRequestVerb::EDM);
+    assert_eq!(ann_keys.open_entry(&echo, "Ann").unwrap(), plaintext);
+
+    let logged = chat.log.text.lock().unwrap();
+    assert!(logged.values().flatten().all(|entry| !entry.object.contains("hunter2")));
+}
+
+#[test]
+fn edms_are_only_opened_with_the_senders_pinned_key() {
+    let (sessions, _chat) = common::start_session_server();
+    let mut ann = connect(sessions, "Ann");
+    let mut bob = connect(sessions, "Bob");
+    let ann_keys = Keyring::new(Identity::generate());
+    let mut bob_keys = Keyring::new(Identity::generate());
+    publish(&mut ann, "Ann", &ann_keys);
+    publish(&mut bob, "Bob", &bob_keys);
+    let bob_public = bob_keys.identity.public_key();
+
+    // Whoever relays an EDM can put any key in it, e.g. a key of their own
+    let forger = Identity::generate();
+    let forge = |sealed: Sealed| format!("[1:Ann][2:edm][3:Bob {}]\r\n", sealed.to_hex());
+    ann.send(&forge(forger.seal(&bob_public, "from Ann, honest").unwrap()));
+    let unpinned = bob.entry(ChatRequestVerb::EDM);
+    // Nothing is opened before Ann's key is pinned
+    assert_eq!(bob_keys.open_entry(&unpinned, "Bob"), None);
+
+    fetch(&mut bob, "Bob", "Ann", &mut bob_keys);
+    assert_eq!(bob_keys.open_entry(&unpinned, "Bob"), None);
+    // ... or names Ann's key without being sealed with it
+    let mut claimed = forger.seal(&bob_public, "from Ann, really").unwrap();
+    claimed.sender_key = ann_keys.identity.public_key();
+    ann.send(&forge(claimed));
+    assert_eq!(bob_keys.open_entry(&bob.entry(ChatRequestVerb::EDM), "Bob"), None);
+
+    ann.send(&forge(ann_keys.identity.seal(&bob_public, "from Ann").unwrap()));
+    assert_eq!(bob_keys.open_entry(&bob.entry(ChatRequestVerb::EDM), "Bob").unwrap(), "from Ann");
+}
+
+#[test]
+fn unsealed_edm_and_unknown_keys_are_refused() {
+    let (sessions, _chat) = common::start_session_server();
+    let mut ann = connect(sessions, "Ann");
+    let _bob = connect(sessions, "Bob");
+
+    ann.send("[1:Ann][2:edm][3:Bob not encrypted at all]\r\n");
+    assert!(matches!(ann.response(), ChatResponse::Error { code: ErrorCode::Malformed, .. }));
+    ann.send("[1:Ann][2:key][3:not a key]\r\n");
+    assert!(matches!(ann.response(), ChatResponse::Error { code: ErrorCode::Malformed, .. }));
+    ann.send("[1:Ann][2:getkey][3:Bob]\r\n");
+    assert!(matches!(ann.response(), ChatResponse::Error { code: ErrorCode::NoKey, .. }));
+}
+
+#[test]
+fn pinned_keys_ignore_the_case_of_the_name() {
+    let ann = Identity::generate();
+    let mut keyring = Keyring::new(Identity::generate());
+    assert_eq!(keyring.learn("Ann", ann.public_key()), KeyChange::New);
+    assert_eq!(keyring.learn("ann", ann.public_key()), KeyChange::Known);
+    // Nobody gets a second pin by changing the case of a pinned name
+    assert!(matches!(keyring.learn("ANN", Identity::generate().public_key()), KeyChange::Changed(_)));
+    assert_eq!(keyring.key_of("aNN"), Some(&ann.public_key()));
+    assert!(keyring.seal_for("ann", "hi").unwrap().starts_with("ann "));
+
+    keyring.wait_for_key("Bob", String::from("later"));
+    assert_eq!(keyring.take_waiting("BOB"), ["later"]);
+}
@@ -0,0 +1,109 @@
+mod common;
+
+use chat_service::{
+    peer::{
+        chatlog::InMemoryChatBuffer,
+        e2e::Identity,
+        framing::{Frame, FrameReader},
+        server::Server,
+    },
+    request::{
+        request::{ChatRequest, ChatRequestStatus, ChatRequestVerb},
+        response::{ChatResponse, ErrorCode},
+    },
+    window::handlers::{request_from_input, split_request, unsent_input},
+};
+use common::Client;
+use std::io::{BufReader, Cursor};
+
+fn request(verb: ChatRequestVerb, object: &str) -> ChatRequest {
+    ChatRequest {
+        subject: Some(String::from("Dan")),
+        verb,
+        object: Some(object.to_string()),
+        status: ChatRequestStatus::Valid
+    }
+}
+
+fn frame_length(request: &ChatRequest) -> usize {
+    request.to_string_opt().unwrap().len() - 2
+}
+
+#[test]
+fn long_lines_are_skipped_without_losing_the_next_one() {
+    let input = format!("hello\r\n{}\nhello!\nabc", "x".repeat(100_000));
+    // A small buffer, so the long line arrives in many pieces
+    let reader = FrameReader::new(BufReader::with_capacity(16, Cursor::new(input)), 5);
+    let frames: Vec<Frame> = reader.map(|frame| frame.unwrap()).collect();
+    assert_eq!(frames, vec![
+        Frame::Line(String::from("hello")),
+        Frame::TooLong,
+        Frame::TooLong,
+        Frame::Line(String::from("abc")),
+    ]);
+}
+
+#[test]
+fn sessions_learn_the_limit_and_get_an_error_for_longer_lines() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let (sessions, _) = common::start_sessions(Server::new("127.0.0.1:0").with_max_frame(100), &chat);
+
+    let mut dan = Client::init(sessions, "Dan", "version=2 caps=acks backfill=live");
+    dan.send(&format!("[1:Dan][2:tx][3:{}]\r\n", "x".repeat(1_000_000)));
+    dan.send("[1:Dan][2:tx][3:short]\r\n");
+    let responses: Vec<ChatResponse> = dan.responses().take(3).collect();
+
+    assert!(matches!(responses[0], ChatResponse::Welcome { max_frame: 100, .. }));
+    assert!(matches!(responses[1], ChatResponse::Error { code: ErrorCode::TooLong, .. }));
+    assert!(matches!(responses[2], ChatResponse::Ack { .. }));
+}
+
+#[test]
+fn long_messages_are_split_to_fit() {
+    let body = "[brackets] and text ".repeat(20);
+    let parts = split_request(request(ChatRequestVerb::ROOMTX, &format!("dev {}", body)), 100, false).unwrap();
+    assert!(parts.len() > 1);
+    assert!(parts.iter().all(|part| frame_length(part) <= 100));
+    let rejoined: String = parts
+        .iter()
+        .map(|part| part.object.clone().unwrap().strip_prefix("dev ").unwrap().to_string())
+        .collect();
+    assert_eq!(rejoined, body);
+
+    // Short ones go out as they are
+    let short = split_request(request(ChatRequestVerb::TX, "hi"), 100, false).unwrap();
+    assert_eq!(short.len(), 1);
+    assert_eq!(short[0].object.as_deref(), Some("hi"));
+    // Anything else that doesn't fit is refused
+    assert!(split_request(request(ChatRequestVerb::JOIN, &"x".repeat(200)), 100, false).is_err());
+}
+
+#[test]
+fn sealed_messages_are_split_to_fit_once_sealed() {
+    let (dan, ann) = (Identity::generate(), Identity::generate());
+    let parts = split_request(request(ChatRequestVerb::DM, &format!("Ann {}", "é".repeat(300))), 400, true).unwrap();
+    assert!(parts.len() > 1);
+    for part in parts {
+        let sealed = dan.seal(&ann.public_key(), &part.body().unwrap()).unwrap();
+        let edm = request(ChatRequestVerb::EDM, &format!("Ann {}", sealed.to_hex()));
+        assert!(frame_length(&edm) <= 400);
+    }
+}
+
+#[test]
+fn unsent_parts_are_typed_back_in_without_the_sent_ones() {
+    let body = "part of a long message ".repeat(20);
+    // What the message is typed with, and what every part's object starts with
+    for (command, target) in [("", ""), ("/room dev ", "dev "), ("/dm Ann ", "Ann ")] {
+        let request = request_from_input(String::from("Dan"), format!("{}{}", command, body));
+        let parts = split_request(request, 100, false).unwrap();
+        assert!(parts.len() > 2);
+        let bodies: Vec<String> = parts
+            .iter()
+            .map(|part| part.object.clone().unwrap().strip_prefix(target).unwrap().to_string())
+            .collect();
+        assert_eq!(unsent_input(&parts[2..]), format!("{}{}", command, bodies[2..].concat()));
+        // All of it, if nothing went out
+        assert_eq!(unsent_input(&parts), format!("{}{}", command, body));
+    }
+}
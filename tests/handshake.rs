@@ -0,0 +1,71 @@
+mod common;
+
+use chat_service::request::{
+    entry::LogEntry,
+    request::{ChatRequest, ChatRequestVerb, MAX_REQUEST_LENGTH},
+    response::{ChatResponse, PROTOCOL_VERSION, negotiate},
+};
+use common::Client;
+
+fn init(options: &str) -> ChatRequest {
+    ChatRequest::from(format!("[1:Dan][2:init][3:{}]", options))
+}
+
+#[test]
+fn the_server_grants_the_capabilities_it_knows() {
+    let welcome = negotiate(&init("version=2 caps=acks,teleport,rooms")).unwrap();
+    assert_eq!(welcome, ChatResponse::Welcome {
+        version: 2,
+        caps: vec![String::from("rooms"), String::from("acks")],
+        subject: String::from("Dan"),
+        max_frame: MAX_REQUEST_LENGTH,
+        epoch: None,
+    });
+    assert!(matches!(negotiate(&init("version=1")), Some(ChatResponse::Welcome { version: 1, ref caps, .. }) if caps.is_empty()));
+}
+
+#[test]
+fn newer_clients_step_down_and_unknown_versions_are_rejected() {
+    assert!(matches!(negotiate(&init("version=9")), Some(ChatResponse::Welcome { version: PROTOCOL_VERSION, .. })));
+    for version in ["0", "two", ""] {
+        let rejected = negotiate(&init(&format!("version={}", version)));
+        assert!(matches!(rejected, Some(ChatResponse::Rejected { version: PROTOCOL_VERSION, .. })), "version={}", version);
+    }
+    // Clients from before the handshake get no answer
+    assert_eq!(negotiate(&init("backfill=all")), None);
+}
+
+#[test]
+fn responses_round_trip_and_old_welcomes_get_the_default_frame() {
+    let welcome = ChatResponse::Welcome {
+        version: 2,
+        caps: vec![String::from("dm")],
+        subject: String::from("Dan2"),
+        max_frame: 100,
+        epoch: Some(7),
+    };
+    assert_eq!(ChatResponse::from_json(&welcome.to_json()), Some(welcome));
+    let old = r#"{"response":"welcome","version":2,"caps":[],"subject":"Dan"}"#;
+    assert!(matches!(ChatResponse::from_json(old), Some(ChatResponse::Welcome { max_frame: MAX_REQUEST_LENGTH, epoch: None, .. })));
+    assert_eq!(ChatResponse::from_json(r#"{"response":"teleport"}"#), None);
+}
+
+#[test]
+fn sessions_are_welcomed_rejected_or_left_alone() {
+    let (sessions, _chat) = common::start_session_server();
+    let mut dan = Client::init(sessions, "Dan", "version=2 caps=rooms,dm backfill=live");
+    // The welcome comes before anything else
+    let welcome = ChatResponse::from_json(&dan.line());
+    assert!(matches!(welcome, Some(ChatResponse::Welcome { ref caps, .. }) if caps.len() == 2));
+
+    // Turned down, then hung up on
+    let mut old = Client::init(sessions, "Ann", "version=0");
+    assert!(matches!(old.response(), ChatResponse::Rejected { .. }));
+    assert!(old.lines.next().is_none());
+
+    // Clients from before the handshake only ever see their feed
+    let mut legacy = Client::init(sessions, "Bob", "backfill=all");
+    let first = legacy.line();
+    assert_eq!(ChatResponse::from_json(&first), None);
+    assert_eq!(LogEntry::from_json(&first).unwrap().verb, ChatRequestVerb::INIT);
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use chat_service::{
+    peer::{chatlog::InMemoryChatBuffer, server::Server},
+    request::{request::ChatRequestVerb, response::ChatResponse},
+};
+use common::Client;
+use std::{net::SocketAddr, time::Duration};
+
+// A session server that hangs up on quiet `ping` sessions after `idle_timeout`
+fn start(idle_timeout: Duration) -> SocketAddr {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    common::start_sessions(Server::new("127.0.0.1:0").with_idle_timeout(idle_timeout), &chat).0
+}
+
+#[test]
+fn pings_get_a_pong_and_no_ack() {
+    let sessions = start(Duration::from_secs(60));
+    let mut dan = Client::init(sessions, "Dan", "version=2 caps=acks,ping backfill=live");
+    let welcome = dan.response();
+    assert!(matches!(welcome, ChatResponse::Welcome { ref caps, .. } if caps.contains(&String::from("ping"))));
+
+    dan.send("[1:Dan][2:ping][3:]\r\n");
+    dan.send("[1:Dan][2:tx][3:hi]\r\n");
+    let responses: Vec<ChatResponse> = dan.responses().take(2).collect();
+    assert_eq!(responses[0], ChatResponse::Pong);
+    assert!(matches!(responses[1], ChatResponse::Ack { .. }));
+}
+
+#[test]
+fn quiet_ping_sessions_are_dropped_and_ended() {
+    let sessions = start(Duration::from_millis(300));
+    // Clients from before PING may stay quiet as long as they like
+    let ann = Client::init(sessions, "Ann", "backfill=live");
+    let mut dan = Client::welcomed(sessions, "Dan", "version=2 caps=ping backfill=live");
+
+    // Dan never PINGs, so the server hangs up on it: EOF well before the read timeout
+    let dan_closed = dan.lines.all(|line| line.is_ok());
+    assert!(dan_closed);
+    let ended = ann.entries().any(|entry| entry.verb == ChatRequestVerb::END && entry.subject == "Dan");
+    assert!(ended);
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use chat_service::{
+    peer::chatlog::InMemoryChatBuffer,
+    request::{
+        entry::LogEntry,
+        request::{ChatRequest, ChatRequestVerb},
+        response::ChatResponse,
+    },
+};
+use common::submit;
+use proptest::prelude::*;
+
+fn any_entry() -> impl Strategy<Value = LogEntry> {
+    let verb = prop_oneof![
+        Just(ChatRequestVerb::INIT),
+        Just(ChatRequestVerb::TX),
+        Just(ChatRequestVerb::ROOMTX),
+        Just(ChatRequestVerb::DM),
+        Just(ChatRequestVerb::END),
+        Just(ChatRequestVerb::NOTICE),
+    ];
+    (any::<u64>(), any::<u64>(), any::<String>(), verb, any::<String>(), proptest::option::of(any::<String>()))
+        .prop_map(|(id, timestamp, subject, verb, object, room)| LogEntry { id, timestamp, subject, verb, object, room })
+}
+
+proptest! {
+    #[test]
+    fn entries_round_trip_through_json(entry in any_entry()) {
+        let json = entry.to_json();
+        // One entry per feed line
+        prop_assert!(!json.contains('\n'));
+        prop_assert_eq!(LogEntry::from_json(&json), Some(entry));
+    }
+}
+
+#[test]
+fn direct_messages_and_notices_have_no_room() {
+    let dm = LogEntry::from_request(7, None, &ChatRequest::from(String::from("[1:Dan][2:dm][3:Ann hi]")));
+    assert!(!dm.to_json().contains("\"room\""));
+    assert_eq!(LogEntry::from_json(&dm.to_json()).unwrap().room, None);
+    assert_eq!((dm.id, dm.subject.as_str(), dm.object.as_str()), (7, "Dan", "Ann hi"));
+    assert!(dm.timestamp > 0);
+}
+
+#[test]
+fn other_lines_are_not_entries() {
+    assert_eq!(LogEntry::from_json("Dan: hi"), None);
+    assert_eq!(LogEntry::from_json(r#"{"id":1,"subject":"Dan","verb":"tx","object":"hi"}"#), None);
+    assert_eq!(LogEntry::from_json(&ChatResponse::Ack { id: 1 }.to_json()), None);
+}
+
+#[test]
+fn entries_render_for_display() {
+    let entry = |line: &str, room: Option<&str>| {
+        LogEntry::from_request(1, room.map(String::from), &ChatRequest::from(line.to_string())).render()
+    };
+    assert_eq!(entry("[1:Dan][2:init][3:]", Some("lobby")), "Dan is connected!");
+    assert_eq!(entry("[1:Dan][2:tx][3:hi]", Some("lobby")), "Dan: hi");
+    assert_eq!(entry("[1:Dan][2:join][3:#dev]", Some("dev")), "Dan joined #dev");
+    assert_eq!(entry("[1:Dan][2:roomtx][3:dev hi devs]", Some("dev")), "#dev Dan: hi devs");
+    assert_eq!(entry("[1:Dan][2:dm][3:Ann psst]", None), "Dan -> Ann: psst");
+    assert_eq!(entry("[1:Dan][2:end][3:]", Some("lobby")), "Dan disconnected!");
+    assert_eq!(LogEntry::notice(0, String::from("be nice")).render(), "be nice");
+
+    let mut noon = LogEntry::notice(0, String::new());
+    noon.timestamp = (12 * 60 + 5) * 60 * 1000 + 59_999;
+    assert_eq!(noon.time_of_day(), "12:05");
+}
+
+#[test]
+fn the_chatlog_numbers_entries_across_rooms() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let ids = [
+        submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap(),
+        submit(&chat.tx, "[1:Dan][2:join][3:dev]").unwrap(),
+        submit(&chat.tx, "[1:Dan][2:tx][3:lobby]").unwrap(),
+        submit(&chat.tx, "[1:Dan][2:roomtx][3:dev dev]").unwrap(),
+    ];
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let logs = chat.log.text.lock().unwrap();
+    let logged = |room: &str| -> Vec<(u64, String)> {
+        logs[room].iter().map(|entry| (entry.id, entry.object.clone())).collect()
+    };
+    assert_eq!(logged("lobby"), vec![(ids[0], String::new()), (ids[2], String::from("lobby"))]);
+    assert_eq!(logged("dev"), vec![(ids[1], String::from("dev")), (ids[3], String::from("dev dev"))]);
+}
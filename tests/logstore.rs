@@ -0,0 +1,101 @@
+mod common;
+
+use chat_service::{
+    peer::{
+        chatlog::{InMemoryChatBuffer, Submission},
+        logstore::{ChatStore, FileLog, RingBufferStore, SyncPolicy},
+    },
+    request::entry::LogEntry,
+};
+use common::submit;
+use std::{fs::{self, OpenOptions}, io::Write};
+
+// Entries with ids 10, 20, 30, ... so ids and positions can't be mixed up
+fn filled<S: ChatStore>(mut store: S, count: u64) -> S {
+    for n in 1..=count {
+        store.append(LogEntry::notice(n * 10, format!("entry {}", n))).unwrap();
+    }
+    store
+}
+
+fn ids(entries: Vec<LogEntry>) -> Vec<u64> {
+    entries.into_iter().map(|entry| entry.id).collect()
+}
+
+#[test]
+fn ring_buffers_keep_positions_after_wrapping_around() {
+    let store = filled(RingBufferStore::new(3), 5);
+    // Positions 0 and 1 are gone, but still count
+    assert_eq!(store.len(), 5);
+    assert_eq!(ids(store.range(0, 5)), vec![30, 40, 50]);
+    assert_eq!(ids(store.range(0, 2)), Vec::<u64>::new());
+    assert_eq!(ids(store.range(1, 4)), vec![30, 40]);
+    assert_eq!(ids(store.range(3, 100)), vec![40, 50]);
+    assert_eq!(ids(store.range(4, 2)), Vec::<u64>::new());
+    assert_eq!(store.last().map(|entry| entry.id), Some(50));
+}
+
+#[test]
+fn ring_buffers_find_ids_and_follow_like_any_store() {
+    let mut ring = filled(RingBufferStore::new(3), 5);
+    let vec = filled(Vec::<LogEntry>::new(), 5);
+    // Anything forgotten counts as older than what's left
+    for (id, position) in [(0, 2), (10, 2), (25, 2), (30, 3), (45, 4), (50, 5), (60, 5)] {
+        assert_eq!(ring.position_after(id), position, "after id {}", id);
+    }
+    for id in [30, 45, 50, 60] {
+        assert_eq!(ring.position_after(id), vec.position_after(id));
+    }
+
+    let mut cursor = 1;
+    assert_eq!(ids(ring.follow(&mut cursor)), vec![30, 40, 50]);
+    assert_eq!(cursor, 5);
+    ring.append(LogEntry::notice(60, String::from("entry 6"))).unwrap();
+    assert_eq!(ids(ring.follow(&mut cursor)), vec![60]);
+    assert!(ring.follow(&mut cursor).is_empty());
+}
+
+#[test]
+fn ring_buffers_hold_at_least_one_entry() {
+    let store = filled(RingBufferStore::new(0), 2);
+    assert_eq!(ids(store.range(0, 2)), vec![20]);
+    assert!(RingBufferStore::new(4).is_empty());
+}
+
+#[test]
+fn file_logs_cut_off_a_torn_record_before_appending() {
+    let path = common::temp_dir("torn").join("lobby.log");
+    let mut log = filled(FileLog::open(&path, SyncPolicy::Always).unwrap(), 2);
+    drop(log);
+    // The server died halfway through the third append
+    let torn = LogEntry::notice(30, String::from("entry 3")).to_json();
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&torn.as_bytes()[..torn.len() / 2]).unwrap();
+
+    log = FileLog::open(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(ids(log.range(0, 10)), vec![10, 20]);
+    log.append(LogEntry::notice(40, String::from("entry 4"))).unwrap();
+    drop(log);
+
+    let reloaded = FileLog::open(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(ids(reloaded.range(0, 10)), vec![10, 20, 40]);
+    assert!(fs::read_to_string(&path).unwrap().lines().all(|line| LogEntry::from_json(line).is_some()));
+}
+
+#[test]
+fn ids_of_unlogged_entries_are_not_reused_after_a_restart() {
+    let dir = common::temp_dir("ids");
+    let chat = common::start_chat(InMemoryChatBuffer::open(&dir, SyncPolicy::Always).unwrap());
+    submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    // Neither the DM nor the closing notice after it is stored in a room
+    let dm = submit(&chat.tx, "[1:Dan][2:dm][3:Dan note to self]").unwrap();
+    let epoch = chat.log.epoch();
+    chat.tx.send(Submission::Shutdown { notice: String::from("restarting") }).unwrap();
+    chat.chatlog.join().unwrap().unwrap();
+
+    let chat = common::start_chat(InMemoryChatBuffer::open(&dir, SyncPolicy::Always).unwrap());
+    assert_eq!(chat.log.text.lock().unwrap()["lobby"].len(), 1);
+    let after_restart = submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    assert!(after_restart > dm + 1);
+    // Clients resuming from an id can keep counting
+    assert_eq!(chat.log.epoch(), epoch);
+}
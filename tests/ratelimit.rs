@@ -0,0 +1,79 @@
+mod common;
+
+use chat_service::{
+    peer::{
+        chatlog::InMemoryChatBuffer,
+        ratelimit::{RateLimit, RateLimiter, TokenBucket, Verdict},
+        server::Server,
+    },
+    request::{request::ChatRequestVerb, response::{ChatResponse, ErrorCode}},
+};
+use common::Client;
+use std::time::{Duration, Instant};
+
+#[test]
+fn buckets_throttle_refill_and_mute() {
+    let start = Instant::now();
+    let at = |millis: u64| start + Duration::from_millis(millis);
+    let limit = RateLimit::new(2, 1.0).with_mute(3, Duration::from_secs(10));
+    let mut bucket = TokenBucket::new(limit, start);
+
+    assert_eq!(bucket.check(at(0)), Verdict::Allowed);
+    assert_eq!(bucket.check(at(0)), Verdict::Allowed);
+    assert_eq!(bucket.check(at(0)), Verdict::Throttled);
+    // One token back after a second
+    assert_eq!(bucket.check(at(1000)), Verdict::Allowed);
+    assert_eq!(bucket.check(at(1000)), Verdict::Throttled);
+    // The third strike since the bucket was last full
+    assert_eq!(bucket.check(at(1000)), Verdict::Muted(Duration::from_secs(10)));
+    assert_eq!(bucket.check(at(5000)), Verdict::Muted(Duration::from_secs(6)));
+    // Back with a full bucket and a clean slate
+    assert_eq!(bucket.check(at(11000)), Verdict::Allowed);
+    assert_eq!(bucket.check(at(11000)), Verdict::Allowed);
+    assert_eq!(bucket.check(at(11000)), Verdict::Throttled);
+}
+
+#[test]
+fn sessions_are_told_when_they_are_throttled() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let rate_limiter = RateLimiter::new(RateLimit::new(2, 0.01).with_mute(2, Duration::from_secs(60)));
+    let (sessions, _) = common::start_sessions(Server::new("127.0.0.1:0").with_rate_limit(rate_limiter), &chat);
+
+    let mut dan = Client::init(sessions, "Dan", "version=2 caps=acks backfill=live");
+    for text in ["one", "two", "three", "four", "five"] {
+        dan.send(&format!("[1:Dan][2:tx][3:{}]\r\n", text));
+    }
+    // ENDs always go through
+    dan.send("[1:Dan][2:end][3:]\r\n");
+    let responses: Vec<ChatResponse> = dan.responses().take(7).collect();
+
+    assert!(matches!(responses[0], ChatResponse::Welcome { .. }));
+    assert!(matches!(responses[1], ChatResponse::Ack { .. }));
+    assert!(matches!(responses[2], ChatResponse::Ack { .. }));
+    assert_eq!(responses[3], ChatResponse::Error { code: ErrorCode::RateLimited, message: String::from("slow down") });
+    assert_eq!(responses[4], ChatResponse::Error { code: ErrorCode::RateLimited, message: String::from("you are muted for 60s") });
+    assert!(matches!(responses[5], ChatResponse::Error { code: ErrorCode::RateLimited, ref message } if message.starts_with("you are muted")));
+    assert!(matches!(responses[6], ChatResponse::Ack { .. }));
+}
+
+#[test]
+fn clients_keep_their_first_bucket_whoever_they_speak_as() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let rate_limiter = RateLimiter::new(RateLimit::new(3, 0.01));
+    let (clients, _) = common::start_clients(Server::new("127.0.0.1:0").with_rate_limit(rate_limiter), &chat);
+
+    let mut client = Client::open(clients, "[1:Bob][2:init][3:]\r\n");
+    client.send("[1:Bob][2:tx][3:one]\r\n");
+    client.send("[1:Bob][2:end][3:]\r\n");
+    // Ann starts with what is left of Bob's bucket
+    client.send("[1:Ann][2:init][3:]\r\n");
+    client.send("[1:Ann][2:tx][3:two]\r\n");
+    client.send("[1:Ann][2:end][3:]\r\n");
+
+    let logged = |subject: &str, verb: ChatRequestVerb| {
+        chat.log.text.lock().unwrap().values().flatten().any(|entry| entry.subject == subject && entry.verb == verb)
+    };
+    common::wait_until(|| logged("Ann", ChatRequestVerb::END));
+    assert!(logged("Bob", ChatRequestVerb::TX));
+    assert!(!logged("Ann", ChatRequestVerb::TX));
+}
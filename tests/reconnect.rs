@@ -0,0 +1,261 @@
+mod common;
+
+use chat_service::{
+    peer::{
+        auth::{Credentials, hash_secret},
+        chatlog::InMemoryChatBuffer,
+        logstore::ChatStore,
+        reconnect::{Backoff, Granted, Handshake, Reconnect, Reconnected, Resume, Rooms, SharedConnection, handshake},
+        server::Server,
+        shutdown::ShutdownSignal,
+        transport::Stream,
+    },
+    request::{entry::LogEntry, request::{ChatRequest, Backfill}},
+    window::handlers::{allow_rename, init_request},
+};
+use common::{Chat, submit};
+use std::{
+    fs,
+    io::{BufRead, BufReader, ErrorKind, Lines, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+#[test]
+fn backoff_doubles_up_to_the_max_and_resets() {
+    let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(3));
+    let delays: Vec<Duration> = (0..5).map(|_| backoff.next_delay()).collect();
+    assert_eq!(delays, [500, 1000, 2000, 3000, 3000].map(Duration::from_millis));
+    assert_eq!(backoff.attempt, 5);
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+    assert_eq!(backoff.attempt, 1);
+}
+
+#[test]
+fn writes_fail_while_disconnected_and_go_to_the_replacement() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let connection = SharedConnection::new(Stream::Plain(TcpStream::connect(address).unwrap()));
+    let (_first, _) = listener.accept().unwrap();
+
+    connection.disconnect();
+    let error = connection.clone().write_all(b"lost\n").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotConnected);
+
+    connection.replace(Stream::Plain(TcpStream::connect(address).unwrap()));
+    let (mut second, _) = listener.accept().unwrap();
+    connection.clone().write_all(b"resent\n").unwrap();
+    connection.disconnect();
+    let mut received = String::new();
+    second.read_to_string(&mut received).unwrap();
+    assert_eq!(received, "resent\n");
+}
+
+#[test]
+fn resume_remembers_the_newest_entry_of_each_room() {
+    let entry = |id, room: Option<&str>| LogEntry { room: room.map(String::from), ..LogEntry::notice(id, format!("entry {}", id)) };
+    let mut resume = Resume::default();
+    assert!(resume.saw(&entry(10, Some("lobby"))));
+    assert!(resume.saw(&entry(12, None)));
+    // A room joined later backfills older entries
+    assert!(resume.saw(&entry(4, Some("dev"))));
+    assert!(resume.saw(&entry(0, None)));
+    for repeat in [entry(10, Some("lobby")), entry(9, Some("lobby")), entry(4, Some("dev")), entry(12, None), entry(0, None)] {
+        assert!(!resume.saw(&repeat), "{:?}", repeat);
+    }
+    assert!(matches!(resume.backfill(10), Backfill::Since(12)));
+}
+
+// Dan's session, read the way the CLI client reads it
+struct Feed {
+    stream: Stream,
+    lines: Lines<BufReader<Stream>>,
+    resume: Resume,
+}
+
+impl Feed {
+    fn new(stream: Stream, granted: &Granted, mut resume: Resume) -> Feed {
+        stream.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+        let lines = BufReader::new(stream.try_clone().unwrap()).lines();
+        resume.welcomed(granted);
+        Feed { stream, lines, resume }
+    }
+
+    fn send(&mut self, request: &ChatRequest) {
+        self.stream.write_all(request.to_string_opt().unwrap().as_bytes()).unwrap();
+    }
+
+    // The objects of the entries new to the feed, up to the one with `object`
+    fn until(&mut self, object: &str) -> Vec<String> {
+        let mut shown = vec![];
+        while !shown.iter().any(|shown| shown == object) {
+            let line = self.lines.next().unwrap().unwrap();
+            match LogEntry::from_json(&line) {
+                Some(entry) if self.resume.saw(&entry) => shown.push(entry.object),
+                _ => {},
+            }
+        }
+        shown
+    }
+
+    // Drop the connection, and wait until the server has noticed
+    fn drop_connection<S: ChatStore>(self, chat: &Chat<S>) -> Resume {
+        self.stream.shutdown(Shutdown::Both).unwrap();
+        common::wait_until(|| !chat.log.names.is_active("Dan"));
+        self.resume
+    }
+}
+
+fn dan(sessions: SocketAddr) -> Feed {
+    let init = init_request(String::from("Dan"), None, Backfill::Last(10), None);
+    match handshake(&sessions.to_string(), &None, &init).unwrap() {
+        Handshake::Welcome(stream, _, granted) => Feed::new(stream, &granted, Resume::default()),
+        _ => panic!("Dan was not welcomed"),
+    }
+}
+
+fn reconnect(address: SocketAddr, init: &ChatRequest) -> (Reconnected, u32) {
+    let mut attempts = 0;
+    let reconnect = Reconnect::new(address.to_string(), None).with_backoff(Duration::from_millis(1), Duration::from_millis(1));
+    let reconnected = reconnect.run(init, &ShutdownSignal::new(), |attempt, _| { attempts = attempt; });
+    (reconnected, attempts)
+}
+
+fn resume(address: SocketAddr, resume: Resume, password: Option<String>) -> Feed {
+    let init = init_request(String::from("Dan"), password, resume.backfill(10), resume.epoch());
+    match reconnect(address, &init).0 {
+        Reconnected::Resumed(stream, None, granted) => Feed::new(stream, &granted, resume),
+        _ => panic!("Dan could not reconnect"),
+    }
+}
+
+fn request(line: &str) -> ChatRequest {
+    ChatRequest::from(line.to_string())
+}
+
+#[test]
+fn dropped_sessions_resume_the_feed_and_rejoin_their_rooms() {
+    let (sessions, chat) = common::start_session_server();
+    let mut feed = dan(sessions);
+    let rooms = Rooms::default();
+    for line in ["[1:Dan][2:join][3:dev]", "[1:Dan][2:join][3:ops]", "[1:Dan][2:part][3:ops]"] {
+        let sent = request(line);
+        feed.send(&sent);
+        rooms.sent(&sent);
+    }
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:join][3:dev]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:roomtx][3:dev before]").unwrap();
+    feed.until("dev before");
+
+    let resumed = feed.drop_connection(&chat);
+    submit(&chat.tx, "[1:Ann][2:roomtx][3:dev while away]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:tx][3:lobby while away]").unwrap();
+    let mut feed = resume(sessions, resumed, None);
+    let rejoin = rooms.rejoin("Dan");
+    assert_eq!(rejoin.iter().map(|join| join.object.clone().unwrap()).collect::<Vec<String>>(), ["dev"]);
+    for join in &rejoin {
+        feed.send(join);
+    }
+    common::wait_until(|| chat.log.members.lock().unwrap().get("Dan").is_some_and(|rooms| rooms.contains("dev")));
+    submit(&chat.tx, "[1:Ann][2:roomtx][3:dev after]").unwrap();
+
+    // Everything Dan missed, in both rooms, and nothing twice
+    let shown = feed.until("dev after");
+    assert!(shown.iter().any(|object| object == "dev while away"));
+    assert!(shown.iter().any(|object| object == "lobby while away"));
+    assert!(!shown.iter().any(|object| object == "dev before"));
+}
+
+#[test]
+fn resuming_in_a_restarted_log_starts_over() {
+    let (sessions, chat) = common::start_session_server();
+    let mut feed = dan(sessions);
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:tx][3:before the restart]").unwrap();
+    feed.until("before the restart");
+    let resumed = feed.drop_connection(&chat);
+
+    // A server without an ids file numbers its entries from the start again
+    let (restarted, chat) = common::start_session_server();
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    let after = submit(&chat.tx, "[1:Ann][2:tx][3:after the restart]").unwrap();
+    assert!(matches!(resumed.backfill(10), Backfill::Since(last) if last >= after));
+    let mut feed = resume(restarted, resumed, None);
+    assert!(feed.until("after the restart").iter().any(|object| object == "after the restart"));
+}
+
+#[test]
+fn rejected_reconnects_are_not_retried() {
+    let path = common::temp_dir("rejected_reconnects_are_not_retried").join("credentials");
+    fs::write(&path, format!("Dan {}\n", hash_secret("hunter2").unwrap())).unwrap();
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let server = Server::new("127.0.0.1:0").with_credentials(Credentials::load(&path).unwrap());
+    let (sessions, _) = common::start_sessions(server, &chat);
+
+    // The password changed while Dan was away
+    let init = init_request(String::from("Dan"), Some(String::from("hunter3")), Backfill::Since(5), None);
+    match reconnect(sessions, &init) {
+        (Reconnected::Rejected(reason), 1) => assert_eq!(reason, "wrong name or password"),
+        _ => panic!("the rejection was not reported"),
+    }
+}
+
+#[test]
+fn taken_names_are_retried_until_the_old_session_goes() {
+    let (sessions, chat) = common::start_session_server();
+    let stale = dan(sessions);
+
+    // The connection dropped on Dan's side, but the server still holds the session
+    let (tx, attempts) = mpsc::channel();
+    let reconnecting = thread::spawn(move || {
+        let init = init_request(String::from("Dan"), None, Backfill::Last(10), None);
+        let reconnect = Reconnect::new(sessions.to_string(), None).with_backoff(Duration::from_millis(1), Duration::from_millis(1));
+        reconnect.run(&init, &ShutdownSignal::new(), |attempt, _| tx.send(attempt).unwrap_or(()))
+    });
+    while attempts.recv().unwrap() < 3 {}
+    assert!(chat.log.names.is_active("Dan"));
+
+    // The reconnect gets in as soon as the server lets go of the name
+    stale.stream.shutdown(Shutdown::Both).unwrap();
+    assert!(matches!(reconnecting.join().unwrap(), Reconnected::Resumed(_, None, _)));
+}
+
+#[test]
+fn authenticated_reconnects_replace_the_stale_session() {
+    let path = common::temp_dir("authenticated_reconnects_replace_the_stale_session").join("credentials");
+    fs::write(&path, format!("Dan {}\n", hash_secret("hunter2").unwrap())).unwrap();
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let server = Server::new("127.0.0.1:0").with_credentials(Credentials::load(&path).unwrap());
+    let (sessions, _) = common::start_sessions(server, &chat);
+    let init = init_request(String::from("Dan"), Some(String::from("hunter2")), Backfill::Last(10), None);
+    let mut stale = match handshake(&sessions.to_string(), &None, &init).unwrap() {
+        Handshake::Welcome(stream, _, granted) => Feed::new(stream, &granted, Resume::default()),
+        _ => panic!("Dan was not welcomed"),
+    };
+
+    // Back in on the first attempt, and the server hangs up on the old session
+    let mut feed = match reconnect(sessions, &init) {
+        (Reconnected::Resumed(stream, None, granted), 1) => Feed::new(stream, &granted, Resume::default()),
+        _ => panic!("Dan could not reconnect"),
+    };
+    assert!(stale.lines.all(|line| line.is_ok()));
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:tx][3:welcome back]").unwrap();
+    feed.until("welcome back");
+}
+
+#[test]
+fn first_inits_can_take_a_suffix_for_a_taken_name() {
+    let (sessions, _chat) = common::start_session_server();
+    let _dan = dan(sessions);
+    let init = init_request(String::from("Dan"), None, Backfill::Last(10), None);
+    assert!(matches!(handshake(&sessions.to_string(), &None, &init).unwrap(), Handshake::NameTaken(_)));
+    match handshake(&sessions.to_string(), &None, &allow_rename(init)).unwrap() {
+        Handshake::Welcome(_, subject, _) => assert_eq!(subject, "Dan2"),
+        _ => panic!("the name was not picked for us"),
+    }
+}
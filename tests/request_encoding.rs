@@ -0,0 +1,67 @@
+use chat_service::request::request::{ChatRequest, ChatRequestStatus, ChatRequestVerb, escape, unescape};
+use proptest::prelude::*;
+
+fn any_verb() -> impl Strategy<Value = ChatRequestVerb> {
+    prop_oneof![
+        Just(ChatRequestVerb::INIT),
+        Just(ChatRequestVerb::TX),
+        Just(ChatRequestVerb::JOIN),
+        Just(ChatRequestVerb::PART),
+        Just(ChatRequestVerb::ROOMTX),
+        Just(ChatRequestVerb::DM),
+        Just(ChatRequestVerb::END),
+        Just(ChatRequestVerb::NOTICE),
+    ]
+}
+
+// Fields that are likely to break naive framing
+fn nasty_field() -> impl Strategy<Value = String> {
+    prop_oneof![
+        any::<String>(),
+        "[\\[\\]\\\\\r\n:123a-z]*",
+        Just(String::from("hi][2:END][3:")),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn escape_round_trips(field in any::<String>()) {
+        prop_assert_eq!(unescape(&escape(&field)), Some(field));
+    }
+
+    #[test]
+    fn request_round_trips(subject in nasty_field(), verb in any_verb(), object in nasty_field()) {
+        let request = ChatRequest {
+            subject: Some(subject),
+            verb,
+            object: Some(object),
+            status: ChatRequestStatus::Valid,
+        };
+        let encoded = request.to_string_opt().unwrap();
+        let line = encoded.strip_suffix("\r\n").unwrap();
+        // A request must be exactly one line on the wire
+        prop_assert!(!line.contains('\n') && !line.contains('\r'));
+        let decoded = ChatRequest::from(line.to_string());
+        prop_assert_eq!(decoded.status, ChatRequestStatus::Valid);
+        prop_assert_eq!(decoded.subject, request.subject);
+        prop_assert_eq!(decoded.verb, request.verb);
+        prop_assert_eq!(decoded.object, request.object);
+    }
+
+    #[test]
+    fn decoding_never_panics(line in nasty_field()) {
+        ChatRequest::from(line);
+    }
+}
+
+#[test]
+fn unescaped_brackets_are_invalid() {
+    let request = ChatRequest::from(String::from("[1:Dan][2:TX][3:hi][2:END][3:]"));
+    assert_eq!(request.status, ChatRequestStatus::Invalid);
+}
+
+#[test]
+fn unknown_escapes_are_invalid() {
+    let request = ChatRequest::from(String::from("[1:Dan][2:TX][3:\\q]"));
+    assert_eq!(request.status, ChatRequestStatus::Invalid);
+}
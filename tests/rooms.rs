@@ -0,0 +1,91 @@
+mod common;
+
+use chat_service::{
+    peer::chatlog::{FeedCursor, InMemoryChatBuffer},
+    request::{
+        entry::LogEntry,
+        request::{Backfill, ChatRequestVerb},
+        response::ErrorCode,
+    },
+};
+use common::submit;
+
+fn texts(entries: &[LogEntry]) -> Vec<(&str, Option<&str>, &str)> {
+    entries.iter().map(|entry| (entry.subject.as_str(), entry.room.as_deref(), entry.object.as_str())).collect()
+}
+
+#[test]
+fn room_messages_only_reach_members() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    let mut ann = FeedCursor::new("Ann", Backfill::Live, &chat.log);
+    let mut dan = FeedCursor::new("Dan", Backfill::Live, &chat.log);
+    common::next_batch(&mut ann, &chat.log);
+    common::next_batch(&mut dan, &chat.log);
+
+    // `#dev` and `dev` are the same room
+    submit(&chat.tx, "[1:Dan][2:join][3:#dev]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:roomtx][3:dev just us]").unwrap();
+    assert_eq!(submit(&chat.tx, "[1:Ann][2:roomtx][3:dev let me in]"), Err(ErrorCode::NotAMember));
+    submit(&chat.tx, "[1:Ann][2:tx][3:hi all]").unwrap();
+
+    assert_eq!(texts(&common::next_batch(&mut ann, &chat.log)), vec![("Ann", Some("lobby"), "hi all")]);
+    assert_eq!(texts(&common::next_batch(&mut dan, &chat.log)), vec![
+        ("Dan", Some("dev"), "#dev"),
+        ("Dan", Some("dev"), "dev just us"),
+        ("Ann", Some("lobby"), "hi all"),
+    ]);
+}
+
+#[test]
+fn members_see_joins_and_parts_and_leavers_are_refused() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:join][3:dev]").unwrap();
+    let mut ann = FeedCursor::new("Ann", Backfill::Live, &chat.log);
+    common::next_batch(&mut ann, &chat.log);
+
+    submit(&chat.tx, "[1:Dan][2:join][3:dev]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:roomtx][3:dev hello]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:part][3:dev]").unwrap();
+    assert_eq!(submit(&chat.tx, "[1:Dan][2:roomtx][3:dev still here?]"), Err(ErrorCode::NotAMember));
+    assert_eq!(submit(&chat.tx, "[1:Dan][2:part][3:dev]"), Err(ErrorCode::NotAMember));
+    // A room name is a single word
+    assert_eq!(submit(&chat.tx, "[1:Dan][2:join][3:two words]"), Err(ErrorCode::Malformed));
+    assert_eq!(submit(&chat.tx, "[1:Dan][2:join][3:]"), Err(ErrorCode::Malformed));
+
+    let verbs: Vec<(ChatRequestVerb, String)> = common::next_batch(&mut ann, &chat.log)
+        .into_iter()
+        .map(|entry| (entry.verb, entry.subject))
+        .collect();
+    assert_eq!(verbs, vec![
+        (ChatRequestVerb::JOIN, String::from("Dan")),
+        (ChatRequestVerb::ROOMTX, String::from("Dan")),
+        (ChatRequestVerb::PART, String::from("Dan")),
+    ]);
+    assert!(chat.log.members.lock().unwrap()["Dan"].iter().eq(["lobby"].iter()));
+}
+
+#[test]
+fn an_end_is_logged_once_to_every_room_the_subject_was_in() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    submit(&chat.tx, "[1:Ann][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Ann][2:join][3:dev]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:init][3:]").unwrap();
+    submit(&chat.tx, "[1:Dan][2:join][3:dev]").unwrap();
+    let mut ann = FeedCursor::new("Ann", Backfill::Live, &chat.log);
+    common::next_batch(&mut ann, &chat.log);
+
+    let end = submit(&chat.tx, "[1:Dan][2:end][3:]").unwrap();
+    let logs = chat.log.text.lock().unwrap();
+    for room in ["lobby", "dev"] {
+        assert_eq!(logs[room].last().map(|entry| (entry.id, entry.verb)), Some((end, ChatRequestVerb::END)));
+    }
+    drop(logs);
+    // Ann shares both rooms, but sees Dan leave once
+    let ends = common::next_batch(&mut ann, &chat.log);
+    assert_eq!(ends.len(), 1);
+    assert!(!common::is_member(&chat.log, "Dan"));
+}
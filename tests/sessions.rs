@@ -0,0 +1,53 @@
+mod common;
+
+use chat_service::request::request::ChatRequestVerb;
+use common::Client;
+
+#[test]
+fn one_connection_sends_requests_and_reads_its_feed() {
+    let (sessions, _chat) = common::start_session_server();
+    let mut dan = Client::init(sessions, "Dan", "backfill=all");
+    assert_eq!(dan.entry(ChatRequestVerb::INIT).subject, "Dan");
+
+    dan.send("[1:Dan][2:tx][3:hi]\r\n");
+    // The session owns its subject; it can't speak for anyone else
+    dan.send("[1:Ann][2:tx][3:it was me]\r\n");
+    let first = dan.entry(ChatRequestVerb::TX);
+    let second = dan.entry(ChatRequestVerb::TX);
+    assert_eq!((first.subject.as_str(), first.object.as_str()), ("Dan", "hi"));
+    assert_eq!((second.subject.as_str(), second.object.as_str()), ("Dan", "it was me"));
+}
+
+#[test]
+fn sessions_see_each_other_and_end_with_end() {
+    let (sessions, chat) = common::start_session_server();
+    let ann = Client::init(sessions, "Ann", "backfill=live");
+    // Ann's feed is open before Dan arrives
+    common::wait_until(|| chat.log.subscribers.stats().len() == 1);
+    let mut dan = Client::init(sessions, "Dan", "backfill=all");
+    dan.send("[1:Dan][2:tx][3:hello Ann]\r\n");
+    assert_eq!(dan.entry(ChatRequestVerb::TX).object, "hello Ann");
+    dan.send("[1:Dan][2:end][3:]\r\n");
+
+    // Dan's connection closes after the END: EOF well before the read timeout
+    let dan_closed = dan.lines.all(|line| line.is_ok());
+    assert!(dan_closed);
+    let ann_entries: Vec<(ChatRequestVerb, String)> = ann.entries()
+        .filter(|entry| entry.subject == "Dan")
+        .take(3)
+        .map(|entry| (entry.verb, entry.object))
+        .collect();
+    assert_eq!(ann_entries, vec![
+        (ChatRequestVerb::INIT, String::from("backfill=all")),
+        (ChatRequestVerb::TX, String::from("hello Ann")),
+        (ChatRequestVerb::END, String::new()),
+    ]);
+}
+
+#[test]
+fn sessions_must_start_with_init() {
+    let (sessions, chat) = common::start_session_server();
+    let dan = Client::open(sessions, "[1:Dan][2:tx][3:hi]\r\n");
+    assert_eq!(dan.entries().count(), 0);
+    assert!(chat.log.text.lock().unwrap().is_empty());
+}
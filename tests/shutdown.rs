@@ -0,0 +1,41 @@
+mod common;
+
+use chat_service::{
+    peer::{
+        chatlog::{InMemoryChatBuffer, Submission},
+        server::Server,
+    },
+    request::request::ChatRequestVerb,
+};
+use common::Client;
+use std::net::TcpStream;
+
+#[test]
+fn shutdown_notifies_feeds_and_joins_every_thread() {
+    let chat = common::start_chat(InMemoryChatBuffer::new());
+    let shutdown = chat.log.shutdown.clone();
+    let (session_address, sessions) = common::start_sessions(Server::new("127.0.0.1:0").with_shutdown(shutdown.clone()), &chat);
+    let (client_address, clients) = common::start_clients(Server::new("127.0.0.1:0").with_shutdown(shutdown.clone()), &chat);
+
+    let session = Client::init(session_address, "Dan", "");
+    let feed = Client::open(chat.feed, "Ann backfill=live\n");
+    // Connections that never say anything must not hold up the shutdown either
+    let _silent_session = TcpStream::connect(session_address).unwrap();
+    let _silent_client = TcpStream::connect(client_address).unwrap();
+    // Dan's session feed and Ann's feed
+    common::wait_until(|| chat.log.subscribers.stats().len() == 2);
+
+    shutdown.trigger();
+    chat.tx.send(Submission::Shutdown { notice: String::from("going down") }).unwrap();
+    chat.chatlog.join().unwrap().unwrap();
+    shutdown.close_connections();
+    chat.feeds.join().unwrap().unwrap();
+    sessions.join().unwrap().unwrap();
+    clients.join().unwrap().unwrap();
+
+    for client in [session, feed] {
+        let last = client.entries().last().unwrap();
+        assert_eq!(last.verb, ChatRequestVerb::NOTICE);
+        assert_eq!(last.object, "going down");
+    }
+}
@@ -0,0 +1,106 @@
+mod common;
+
+use chat_service::{
+    peer::{
+        chatlog::InMemoryChatBuffer,
+        server::Server,
+        subscribers::{Admitted, FeedLimits, FeedStats, FeedTotals, Laggards, Subscribers},
+    },
+    request::{entry::LogEntry, request::ChatRequestVerb},
+};
+use common::Client;
+use std::{io::Write, thread, time::Duration};
+
+// Entries logged to the lobby
+fn entries(ids: std::ops::Range<u64>) -> Vec<LogEntry> {
+    ids.map(|id| LogEntry { room: Some(String::from("lobby")), ..LogEntry::notice(id, format!("entry {}", id)) }).collect()
+}
+
+fn written(admitted: Admitted) -> Vec<u64> {
+    match admitted {
+        Admitted::Write(entries) => entries.iter().map(|entry| entry.id).collect(),
+        Admitted::HangUp(_) => panic!("the feed was disconnected"),
+    }
+}
+
+#[test]
+fn laggards_drop_the_oldest_entries_and_count_them() {
+    let subscribers = Subscribers::new(FeedLimits { high_water: 3, write_timeout: None, laggards: Laggards::Drop });
+    let mut subscription = subscribers.subscribe("Dan");
+    // The backfill goes out whole
+    assert_eq!(written(subscription.admit(entries(0..10))).len(), 10);
+    subscription.wrote(10);
+    let kept = written(subscription.admit(entries(10..15)));
+    assert_eq!(kept, vec![12, 13, 14]);
+    subscription.wrote(kept.len());
+    assert_eq!(subscribers.stats(), vec![FeedStats { subject: String::from("Dan"), written: 13, dropped: 2 }]);
+    assert!(subscribers.summary().ends_with("open feeds that dropped entries: Dan (2)"));
+
+    // The totals outlive the feed
+    drop(subscription);
+    assert!(subscribers.stats().is_empty());
+    assert_eq!(subscribers.totals(), FeedTotals { feeds: 1, written: 13, dropped: 2, disconnected: 0 });
+    assert_eq!(subscribers.summary(), "feeds: 0 open, 1 since start; 13 entries written, 2 dropped, 0 laggards disconnected");
+}
+
+#[test]
+fn laggards_can_be_disconnected_instead() {
+    let subscribers = Subscribers::new(FeedLimits { high_water: 3, write_timeout: None, laggards: Laggards::Disconnect });
+    let mut subscription = subscribers.subscribe("Dan");
+    written(subscription.admit(entries(0..10)));
+    written(subscription.admit(entries(10..13)));
+    assert_eq!(subscription.admit(entries(13..17)), Admitted::HangUp(vec![]));
+    assert_eq!(subscribers.totals().disconnected, 1);
+}
+
+#[test]
+fn laggards_keep_their_direct_messages() {
+    let dm = |id| LogEntry::notice(id, String::from("psst"));
+    for laggards in [Laggards::Drop, Laggards::Disconnect] {
+        let subscribers = Subscribers::new(FeedLimits { high_water: 2, write_timeout: None, laggards });
+        let mut subscription = subscribers.subscribe("Dan");
+        written(subscription.admit(vec![]));
+
+        // They don't count towards the high-water mark either
+        let mut pending: Vec<LogEntry> = (0..5).map(dm).collect();
+        pending.extend(entries(5..7));
+        assert_eq!(written(subscription.admit(pending)), vec![0, 1, 2, 3, 4, 5, 6]);
+
+        let late = dm(10);
+        let mut pending = entries(7..10);
+        pending.insert(1, late.clone());
+        let admitted = subscription.admit(pending);
+        match laggards {
+            Laggards::Drop => assert_eq!(written(admitted), vec![10, 8, 9]),
+            Laggards::Disconnect => assert_eq!(admitted, Admitted::HangUp(vec![late])),
+        }
+    }
+}
+
+#[test]
+fn sessions_that_stop_reading_are_disconnected_and_ended() {
+    let limits = FeedLimits { high_water: usize::MAX, write_timeout: Some(Duration::from_millis(300)), laggards: Laggards::Disconnect };
+    let chat = common::start_chat(InMemoryChatBuffer::new().with_feed_limits(limits));
+    let (sessions, _) = common::start_sessions(Server::new("127.0.0.1:0"), &chat);
+    let log = chat.log.clone();
+
+    // Dan never reads a thing
+    let _dan = Client::init(sessions, "Dan", "backfill=live");
+    let ann = Client::init(sessions, "Ann", "backfill=live");
+    let mut ann_writer = ann.stream.try_clone().unwrap();
+    common::wait_until(|| log.subscribers.stats().len() == 2);
+
+    // Far more than the socket buffers hold
+    thread::spawn(move || {
+        let message = format!("[1:Ann][2:tx][3:{}]\r\n", "x".repeat(60_000));
+        for _ in 0..400 {
+            if ann_writer.write_all(message.as_bytes()).is_err() {
+                break;
+            }
+        }
+    });
+    let dan_ended = ann.entries().any(|entry| entry.verb == ChatRequestVerb::END && entry.subject == "Dan");
+    assert!(dan_ended);
+    let subjects: Vec<String> = log.subscribers.stats().into_iter().map(|stats| stats.subject).collect();
+    assert_eq!(subjects, vec![String::from("Ann")]);
+}
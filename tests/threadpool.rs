@@ -0,0 +1,53 @@
+mod common;
+
+use chat_service::threadpool::threadpool::{ExecuteError, Overflow, Threadpool};
+use std::{
+    sync::{Arc, atomic::{AtomicUsize, Ordering}, mpsc},
+    time::Duration,
+};
+
+// A job that holds its worker until the returned sender is dropped
+fn parked(pool: &mut Threadpool) -> Result<mpsc::Sender<()>, ExecuteError> {
+    let (release, wait) = mpsc::channel::<()>();
+    pool.execute(move || { wait.recv().unwrap_or(()); })?;
+    Ok(release)
+}
+
+#[test]
+fn runs_every_job_and_joins_on_shutdown() {
+    let done = Arc::new(AtomicUsize::new(0));
+    let mut pool = Threadpool::new(4);
+    for _ in 0..100 {
+        let done = done.clone();
+        pool.execute(move || { done.fetch_add(1, Ordering::SeqCst); }).unwrap();
+    }
+    pool.shutdown();
+    assert_eq!(done.load(Ordering::SeqCst), 100);
+    assert_eq!(pool.execute(|| {}), Err(ExecuteError::ShutDown));
+}
+
+#[test]
+fn rejects_when_full() {
+    let mut pool = Threadpool::with_config(1, 0, Overflow::Reject);
+    // Rejected until the worker is waiting for jobs
+    let release = common::wait_for(|| parked(&mut pool).ok());
+    assert_eq!(pool.execute(|| {}).unwrap_err(), ExecuteError::Rejected);
+    drop(release);
+}
+
+#[test]
+fn grows_up_to_the_limit() {
+    let mut pool = Threadpool::with_config(1, 0, Overflow::Grow(3));
+    let releases: Vec<mpsc::Sender<()>> = (0..3).map(|_| parked(&mut pool).unwrap()).collect();
+    assert_eq!(pool.worker_count(), 3);
+    drop(releases);
+}
+
+#[test]
+fn survives_a_panicking_job() {
+    let (done, finished) = mpsc::channel();
+    let mut pool = Threadpool::new(1);
+    pool.execute(|| panic!("job failed")).unwrap();
+    pool.execute(move || { done.send(()).unwrap(); }).unwrap();
+    finished.recv_timeout(Duration::from_secs(5)).unwrap();
+}
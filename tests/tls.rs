@@ -0,0 +1,109 @@
+mod common;
+
+use chat_service::{
+    peer::{
+        chatlog::InMemoryChatBuffer,
+        server::Server,
+        transport::{Stream, connect, client_config_from_der, server_config_from_der},
+    },
+    request::{entry::LogEntry, response::ChatResponse},
+};
+use rustls::{
+    ClientConfig, ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+};
+use std::{
+    io::{BufRead, BufReader, Lines, Write},
+    sync::Arc,
+};
+
+// A self-signed certificate for localhost, and configs for a server using it and a client trusting it
+fn self_signed() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+    let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let cert: CertificateDer<'static> = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+    (
+        server_config_from_der(vec![cert.clone()], key).unwrap(),
+        client_config_from_der(vec![cert]).unwrap(),
+    )
+}
+
+struct Ports {
+    client: u16,
+    feed: u16,
+    session: u16,
+}
+
+// Starts the chatlog, its feed, and the legacy and session servers, all over TLS
+fn start_servers(tls: Arc<ServerConfig>) -> Ports {
+    let chat = common::start_chat_with_tls(InMemoryChatBuffer::new(), Some(tls.clone()));
+    let (client, _) = common::start_clients(Server::new("127.0.0.1:0").with_tls(tls.clone()), &chat);
+    let (session, _) = common::start_sessions(Server::new("127.0.0.1:0").with_tls(tls), &chat);
+    Ports { client: client.port(), feed: chat.feed.port(), session: session.port() }
+}
+
+fn lines(stream: &Stream) -> Lines<BufReader<Stream>> {
+    let reader = stream.try_clone().unwrap();
+    reader.set_read_timeout(Some(common::TIMEOUT)).unwrap();
+    BufReader::new(reader).lines()
+}
+
+fn next_entry(lines: &mut Lines<BufReader<Stream>>) -> LogEntry {
+    loop {
+        let line = lines.next().unwrap().unwrap();
+        if let Some(entry) = LogEntry::from_json(&line) {
+            return entry;
+        }
+    }
+}
+
+#[test]
+fn session_over_tls() {
+    let (server_tls, client_tls) = self_signed();
+    let ports = start_servers(server_tls);
+    let mut stream = connect(&format!("localhost:{}", ports.session), &Some(client_tls)).unwrap();
+    let mut lines = lines(&stream);
+    stream.write_all(b"[1:Dan][2:init][3:version=2 caps=acks backfill=all]\r\n").unwrap();
+    let welcome = ChatResponse::from_json(&lines.next().unwrap().unwrap()).unwrap();
+    assert!(matches!(welcome, ChatResponse::Welcome { .. }));
+    assert_eq!(next_entry(&mut lines).subject, "Dan");
+
+    stream.write_all(b"[1:Dan][2:tx][3:over tls]\r\n").unwrap();
+    let mut acked = false;
+    let mut echoed = false;
+    while !(acked && echoed) {
+        let line = lines.next().unwrap().unwrap();
+        match (LogEntry::from_json(&line), ChatResponse::from_json(&line)) {
+            (Some(entry), _) => {
+                assert_eq!(entry.object, "over tls");
+                echoed = true;
+            },
+            (_, Some(ChatResponse::Ack { .. })) => { acked = true; },
+            _ => panic!("unexpected line {}", line),
+        }
+    }
+}
+
+#[test]
+fn feed_and_legacy_client_over_tls() {
+    let (server_tls, client_tls) = self_signed();
+    let ports = start_servers(server_tls);
+    let client_tls = Some(client_tls);
+
+    let mut feed = connect(&format!("localhost:{}", ports.feed), &client_tls).unwrap();
+    let mut feed_lines = lines(&feed);
+    feed.write_all(b"Dan backfill=all\n").unwrap();
+
+    let mut client = connect(&format!("localhost:{}", ports.client), &client_tls).unwrap();
+    client.write_all(b"[1:Dan][2:init][3:]\r\n[1:Dan][2:tx][3:hi]\r\n").unwrap();
+    assert_eq!(next_entry(&mut feed_lines).verb.to_string(), "init");
+    assert_eq!(next_entry(&mut feed_lines).object, "hi");
+}
+
+#[test]
+fn untrusted_certificate_is_refused() {
+    let (server_tls, _) = self_signed();
+    let (_, other_client_tls) = self_signed();
+    let ports = start_servers(server_tls);
+    assert!(connect(&format!("localhost:{}", ports.session), &Some(other_client_tls)).is_err());
+}